@@ -0,0 +1,141 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use error::*;
+
+/// A block of IP addresses written as `address/prefix`, such as
+/// `10.0.0.0/8` or `fc00::/7`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+/// The loopback, private and link-local blocks of IPv4 and IPv6.
+pub const PRIVATE_RANGES: &[&str] = &["10.0.0.0/8",
+                                      "172.16.0.0/12",
+                                      "192.168.0.0/16",
+                                      "127.0.0.0/8",
+                                      "169.254.0.0/16",
+                                      "::1/128",
+                                      "fc00::/7",
+                                      "fe80::/10"];
+
+impl Cidr {
+    /// Parses `address/prefix`. A bare address stands for itself alone, and
+    /// bits set past the prefix are ignored, so `10.1.2.3/8` is `10.0.0.0/8`.
+    pub fn new(s: &str) -> Result<Cidr, Error> {
+        let invalid = || Error::InvalidArgument(format!("invalid CIDR block: {}", s));
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let network = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => {
+                match p.parse::<u8>() {
+                    Ok(n) if n <= bits && !p.starts_with('+') => n,
+                    _ => return Err(invalid()),
+                }
+            }
+            None => bits,
+        };
+        let network = match network {
+            IpAddr::V4(a) => IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask32(prefix))),
+            IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask128(prefix))),
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns whether `addr` is in the block. IPv4 addresses and their
+    /// IPv4-mapped IPv6 forms (`::ffff:a.b.c.d`) are treated as the same.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                u32::from(a) & mask32(self.prefix) == u32::from(net)
+            }
+            (IpAddr::V4(_), IpAddr::V6(a)) => {
+                a.to_ipv4_mapped().is_some_and(|a| self.contains(IpAddr::V4(a)))
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                u128::from(a) & mask128(self.prefix) == u128::from(net)
+            }
+            (IpAddr::V6(_), IpAddr::V4(a)) => self.contains(IpAddr::V6(a.to_ipv6_mapped())),
+        }
+    }
+}
+
+/// Parses an address as logged, allowing IPv6 in brackets as in `[::1]`.
+pub fn parse_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let s = if s.starts_with('[') && s.ends_with(']') { &s[1..s.len() - 1] } else { s };
+    s.parse().ok()
+}
+
+fn mask32(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask128(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains_v4() {
+        let block = Cidr::new("192.168.1.0/24").unwrap();
+        assert!(block.contains(addr("192.168.1.0")));
+        assert!(block.contains(addr("192.168.1.255")));
+        assert!(!block.contains(addr("192.168.0.255")));
+        assert!(!block.contains(addr("192.168.2.0")));
+        assert!(block.contains(addr("::ffff:192.168.1.7")));
+        assert!(!block.contains(addr("::192.168.1.7")));
+
+        assert_eq!(Cidr::new("10.1.2.3/8").unwrap(), Cidr::new("10.0.0.0/8").unwrap());
+        assert!(Cidr::new("0.0.0.0/0").unwrap().contains(addr("255.255.255.255")));
+        let host = Cidr::new("10.0.0.1").unwrap();
+        assert_eq!(host.prefix(), 32);
+        assert!(host.contains(addr("10.0.0.1")) && !host.contains(addr("10.0.0.2")));
+    }
+
+    #[test]
+    fn test_contains_v6() {
+        let block = Cidr::new("2001:db8::/32").unwrap();
+        assert!(block.contains(addr("2001:db8::")));
+        assert!(block.contains(addr("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!block.contains(addr("2001:db9::")));
+        assert!(!block.contains(addr("10.0.0.1")));
+
+        let mapped = Cidr::new("::ffff:10.0.0.0/104").unwrap();
+        assert!(mapped.contains(addr("10.20.30.40")));
+        assert!(!mapped.contains(addr("11.0.0.0")));
+        assert_eq!(Cidr::new("::/0").unwrap().network(), addr("::"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for s in &["", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/", "10.0.0.0/+8",
+                   "10.0.0.0/-1", "example.com/8", "10.0.0.0/8/8"] {
+            assert!(Cidr::new(s).is_err(), "{}", s);
+        }
+        assert_eq!(parse_addr("[::1]"), Some(addr("::1")));
+        assert_eq!(parse_addr(" 10.0.0.1"), Some(addr("10.0.0.1")));
+        assert_eq!(parse_addr("10.0.0.1:80"), None);
+        assert_eq!(parse_addr("-"), None);
+    }
+}
@@ -0,0 +1,179 @@
+use error::*;
+use ltsv::Record;
+
+/// Parses an access log line in the combined format,
+/// `%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i"`, into a record
+/// labelled `host`, `ident`, `user`, `time`, `method`, `uri`, `protocol`,
+/// `status`, `size`, `referer` and `ua`.
+///
+/// Fields logged as `-` are left out, as is the protocol of a request line
+/// without one. The referer and user agent may be missing altogether, which
+/// makes lines in the common format parse too. `\"` and `\\` in quoted fields
+/// are unescaped.
+pub fn parse_line(line: &str) -> Result<Record, Error> {
+    let mut p = Fields { s: line, pos: 0 };
+    let mut record = Record::default();
+    for label in &["host", "ident", "user"] {
+        let value = p.word().ok_or_else(|| error(line, label))?;
+        insert(&mut record, label, value);
+    }
+    let time = p.bracketed().ok_or_else(|| error(line, "time"))?;
+    insert(&mut record, "time", time);
+    let request = p.quoted().ok_or_else(|| error(line, "request"))?;
+    if request != "-" {
+        let mut parts = request.splitn(3, ' ');
+        for label in &["method", "uri", "protocol"] {
+            if let Some(part) = parts.next() {
+                insert(&mut record, label, part.to_string());
+            }
+        }
+    }
+    for label in &["status", "size"] {
+        let value = p.word().ok_or_else(|| error(line, label))?;
+        insert(&mut record, label, value);
+    }
+    for label in &["referer", "ua"] {
+        if p.at_end() {
+            break;
+        }
+        let value = p.quoted().ok_or_else(|| error(line, label))?;
+        insert(&mut record, label, value);
+    }
+    if !p.at_end() {
+        return Err(error(line, "end of line"));
+    }
+    Ok(record)
+}
+
+fn insert(record: &mut Record, label: &str, value: String) {
+    if value != "-" && !value.is_empty() {
+        record.insert(label.to_string(), value);
+    }
+}
+
+fn error(line: &str, field: &str) -> Error {
+    let msg = format!("invalid combined log line, expected {}: {}", field, line);
+    Error::Parse(ParseError::new(msg).with_field(field))
+}
+
+struct Fields<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches(' ').len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_spaces();
+        self.rest().trim_end_matches(['\r', '\n']).is_empty()
+    }
+
+    /// Reads a space-delimited field.
+    fn word(&mut self) -> Option<String> {
+        if self.at_end() {
+            return None;
+        }
+        let rest = self.rest();
+        let len = rest.find(' ').unwrap_or(rest.len());
+        self.pos += len;
+        Some(rest[..len].to_string())
+    }
+
+    /// Reads a `[...]` field without its brackets.
+    fn bracketed(&mut self) -> Option<String> {
+        self.skip_spaces();
+        let rest = self.rest();
+        if !rest.starts_with('[') {
+            return None;
+        }
+        let end = rest.find(']')?;
+        self.pos += end + 1;
+        Some(rest[1..end].to_string())
+    }
+
+    /// Reads a double-quoted field, unescaping `\"` and `\\`.
+    fn quoted(&mut self) -> Option<String> {
+        self.skip_spaces();
+        let rest = self.rest();
+        if !rest.starts_with('"') {
+            return None;
+        }
+        let mut value = String::new();
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Some(value);
+                }
+                '\\' => {
+                    match chars.next() {
+                        Some((_, c @ '"')) | Some((_, c @ '\\')) => value.push(c),
+                        Some((_, c)) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => return None,
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let line = "127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] \"GET /a?b=c HTTP/1.0\" 200 \
+                    2326 \"http://example.com/\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\"\n";
+        let record = parse_line(line).unwrap();
+        assert_eq!(record["host"], "127.0.0.1");
+        assert!(!record.contains_key("ident"));
+        assert_eq!(record["user"], "frank");
+        assert_eq!(record["time"], "10/Oct/2000:13:55:36 -0700");
+        assert_eq!(record["method"], "GET");
+        assert_eq!(record["uri"], "/a?b=c");
+        assert_eq!(record["protocol"], "HTTP/1.0");
+        assert_eq!(record["status"], "200");
+        assert_eq!(record["size"], "2326");
+        assert_eq!(record["referer"], "http://example.com/");
+        assert_eq!(record["ua"], "Mozilla/4.08 [en] (Win98; I ;Nav)");
+    }
+
+    #[test]
+    fn test_parse_line_partial() {
+        let line = "::1 - - [10/Oct/2000:13:55:36 +0000] \"GET /\" 304 - \"-\" \
+                    \"a \\\"b\\\" \\\\c\"";
+        let record = parse_line(line).unwrap();
+        assert_eq!(record["uri"], "/");
+        assert!(!record.contains_key("protocol"));
+        assert!(!record.contains_key("size"));
+        assert!(!record.contains_key("referer"));
+        assert_eq!(record["ua"], "a \"b\" \\c");
+
+        let common = "::1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 0";
+        let record = parse_line(common).unwrap();
+        assert_eq!(record.len(), 4);
+        assert_eq!(record["size"], "0");
+    }
+
+    #[test]
+    fn test_parse_line_invalid() {
+        assert!(parse_line("host:a\tstatus:200").is_err());
+        assert!(parse_line("::1 - - [10/Oct/2000:13:55:36 +0000] \"GET / 200 0").is_err());
+        assert!(parse_line("::1 - - [10/Oct/2000:13:55:36 +0000] \"GET /\" 200 0 x").is_err());
+    }
+}
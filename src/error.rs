@@ -2,9 +2,12 @@ use std::io;
 use std::fmt;
 use std::error;
 
+use ltsv::FieldGroupCount;
+
+#[allow(unused_macros)]
 macro_rules! stderr {
     ( $( $msg:tt )* ) => {{
-        writeln!(io::stderr(), $($msg)*).unwrap();
+        eprintln!($($msg)*);
     }}
 }
 
@@ -34,7 +37,7 @@ impl error::Error for CliError {
         }
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             CliError::NotEnoughArgs => None,
             CliError::TooManyArgs => None,
@@ -43,15 +46,60 @@ impl error::Error for CliError {
     }
 }
 
+/// Malformed input. `msg` describes the problem in full, line number
+/// included; the accessors give the parts of it that are known separately.
 #[derive(Debug)]
 pub struct ParseError {
     pub msg: String,
+    line: Option<u64>,
+    file: Option<String>,
+    field: Option<String>,
+}
+
+impl ParseError {
+    pub fn new<S: Into<String>>(msg: S) -> ParseError {
+        ParseError {
+            msg: msg.into(),
+            line: None,
+            file: None,
+            field: None,
+        }
+    }
+
+    pub fn with_line(mut self, line: u64) -> ParseError {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn with_file<S: Into<String>>(mut self, file: S) -> ParseError {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_field<S: Into<String>>(mut self, field: S) -> ParseError {
+        self.field = Some(field.into());
+        self
+    }
+
+    /// The number of the offending line, counting from 1.
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    /// The name of the file read, if the input was opened by name.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The offending item, or the label of the offending value.
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let &ParseError { ref msg } = self;
-        write!(f, "Parse error: {}", msg)
+        write!(f, "Parse error: {}", self.msg)
     }
 }
 
@@ -60,15 +108,28 @@ impl error::Error for ParseError {
         "parse error"
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     Io(io::Error),
     Parse(ParseError),
+    InvalidArgument(String),
+    /// The operation was cancelled through `ParseOptions::cancel`. `group_by`
+    /// and its variants carry the counts made up to that point.
+    Cancelled(Option<FieldGroupCount>),
+    /// Buffering the input took more than `ParseOptions::max_memory_bytes`.
+    /// `bytes` is the approximate size of the `lines_buffered` lines.
+    MemoryLimit { lines_buffered: u64, bytes: u64 },
+    /// Writing failed because the reading end of the output went away, as
+    /// when piping into `head`. Converted from `ErrorKind::BrokenPipe`, it is
+    /// usually best ignored by exiting successfully. Functions writing
+    /// output return it on the first failed write, without reading further.
+    OutputClosed,
 }
 
 impl fmt::Display for Error {
@@ -76,29 +137,59 @@ impl fmt::Display for Error {
         match *self {
             Error::Io(ref e) => write!(f, "{}", e),
             Error::Parse(ref e) => e.fmt(f),
+            Error::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
+            Error::Cancelled(_) => write!(f, "cancelled"),
+            Error::MemoryLimit { lines_buffered, bytes } => {
+                write!(f,
+                       "memory limit exceeded: {} lines buffered in about {} bytes",
+                       lines_buffered,
+                       bytes)
+            }
+            Error::OutputClosed => write!(f, "output closed"),
         }
     }
 }
 
 impl error::Error for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match *self {
             Error::Io(ref e) => e.description(),
             Error::Parse(ref e) => e.description(),
+            Error::InvalidArgument(_) => "invalid argument",
+            Error::Cancelled(_) => "cancelled",
+            Error::MemoryLimit { .. } => "memory limit exceeded",
+            Error::OutputClosed => "output closed",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Parse(ref e) => Some(e),
+            _ => None,
         }
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    #[allow(deprecated)]
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::Io(ref e) => Some(e),
             Error::Parse(ref e) => Some(e),
+            Error::InvalidArgument(_) => None,
+            Error::Cancelled(_) => None,
+            Error::MemoryLimit { .. } => None,
+            Error::OutputClosed => None,
         }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
-        Error::Io(e)
+        match e.kind() {
+            io::ErrorKind::BrokenPipe => Error::OutputClosed,
+            _ => Error::Io(e),
+        }
     }
 }
 
@@ -107,3 +198,37 @@ impl From<ParseError> for Error {
         Error::Parse(e)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_source() {
+        let err: Box<dyn StdError> = Box::new(Error::from(io::Error::other("disk on fire")));
+        let source = err.source().unwrap();
+        assert_eq!(source.downcast_ref::<io::Error>().unwrap().to_string(), "disk on fire");
+        assert!(err.downcast_ref::<Error>().is_some());
+
+        let err = Error::Parse(ParseError::new("bad").with_line(3).with_field("x"));
+        let parse = err.source().unwrap().downcast_ref::<ParseError>().unwrap();
+        assert_eq!((parse.line(), parse.field(), parse.file()), (Some(3), Some("x"), None));
+        assert!(Error::OutputClosed.source().is_none());
+    }
+
+    #[test]
+    fn test_display() {
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe");
+        let cases = [(Error::from(io::Error::other("disk on fire")), "disk on fire"),
+                     (Error::Parse(ParseError::new("line 2: bad")), "Parse error: line 2: bad"),
+                     (Error::InvalidArgument("k".to_string()), "invalid argument: k"),
+                     (Error::Cancelled(None), "cancelled"),
+                     (Error::MemoryLimit { lines_buffered: 2, bytes: 130 },
+                      "memory limit exceeded: 2 lines buffered in about 130 bytes"),
+                     (Error::from(broken), "output closed")];
+        for &(ref err, expected) in &cases {
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The hasher of rustc and Firefox: much faster than the standard SipHash on
+/// short keys, but not keyed, so input crafted to collide can make hash maps
+/// degrade to linear time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FxHasher {
+    hash: u64,
+}
+
+pub type FxBuildHasher = BuildHasherDefault<FxHasher>;
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+        for &b in chunks.remainder() {
+            self.add(b as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::hash::BuildHasher;
+
+    #[test]
+    fn test_hash() {
+        let hash = |s: &str| FxBuildHasher::default().hash_one(s);
+        assert_eq!(hash("status"), hash("status"));
+        assert_ne!(hash("status"), hash("statut"));
+        assert_ne!(hash("a"), hash("a\0"));
+
+        let mut counts: HashMap<String, i32, FxBuildHasher> = HashMap::default();
+        for value in "b a c a b a".split(' ') {
+            *counts.entry(value.to_string()).or_insert(0) += 1;
+        }
+        assert_eq!((counts["a"], counts["b"], counts["c"]), (3, 2, 1));
+    }
+}
@@ -0,0 +1,842 @@
+use std::io::{self, Read, Write};
+
+/// The first two bytes of every gzip stream.
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Size of the DEFLATE window: matches reach at most this far back.
+const WINDOW: usize = 32 * 1024;
+
+// The lengths and distances of DEFLATE's length and distance symbols: the
+// base value of each symbol and the number of extra bits added to it.
+const LEN_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51,
+                             59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4,
+                             4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385,
+                              513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385,
+                              24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10,
+                              10, 11, 11, 12, 12, 13, 13];
+
+/// A reader decompressing a gzip stream. Concatenated members are read one
+/// after the other, as `gzip -d` does, and each member's CRC and length are
+/// checked.
+pub struct GzDecoder<R> {
+    bits: BitReader<R>,
+    state: State,
+    /// Decompressed bytes: those before `pos` have been returned and are kept
+    /// as the window for matches, the rest are pending.
+    buf: Vec<u8>,
+    pos: usize,
+    /// Where the current member's output starts in `buf`, for its CRC.
+    member_start: usize,
+    crc: u32,
+    size: u32,
+}
+
+enum State {
+    Header,
+    Block,
+    Stored(usize, bool),
+    Codes(Box<Huffman>, Box<Huffman>, bool),
+    Trailer,
+    Done,
+}
+
+impl<R: Read> GzDecoder<R> {
+    pub fn new(r: R) -> GzDecoder<R> {
+        GzDecoder {
+            bits: BitReader::new(r),
+            state: State::Header,
+            buf: Vec::new(),
+            pos: 0,
+            member_start: 0,
+            crc: 0,
+            size: 0,
+        }
+    }
+
+    /// Decodes more output, or advances to the next state.
+    fn step(&mut self) -> io::Result<()> {
+        match ::std::mem::replace(&mut self.state, State::Done) {
+            State::Header => {
+                self.header()?;
+                self.crc = 0;
+                self.size = 0;
+                self.member_start = self.buf.len();
+                self.state = State::Block;
+            }
+            State::Block => {
+                let last = self.bits.bits(1)? == 1;
+                self.state = match self.bits.bits(2)? {
+                    0 => {
+                        self.bits.align();
+                        let len = self.bits.bits(16)?;
+                        if self.bits.bits(16)? != !len & 0xffff {
+                            return Err(invalid("stored block length mismatch"));
+                        }
+                        State::Stored(len as usize, last)
+                    }
+                    1 => {
+                        let (lit, dist) = fixed_tables();
+                        State::Codes(Box::new(lit), Box::new(dist), last)
+                    }
+                    2 => {
+                        let (lit, dist) = self.dynamic_tables()?;
+                        State::Codes(Box::new(lit), Box::new(dist), last)
+                    }
+                    _ => return Err(invalid("invalid block type")),
+                };
+            }
+            State::Stored(len, last) => {
+                let n = len.min(WINDOW);
+                for _ in 0..n {
+                    let b = self.bits.bits(8)? as u8;
+                    self.buf.push(b);
+                }
+                self.state = if len > n {
+                    State::Stored(len - n, last)
+                } else {
+                    next_block(last)
+                };
+            }
+            State::Codes(lit, dist, last) => {
+                let done = self.codes(&lit, &dist)?;
+                self.state = if done {
+                    next_block(last)
+                } else {
+                    State::Codes(lit, dist, last)
+                };
+            }
+            State::Trailer => {
+                self.finish_member();
+                self.bits.align();
+                let crc = self.bits.bits(32)?;
+                let size = self.bits.bits(32)?;
+                if crc != self.crc || size != self.size {
+                    return Err(invalid("gzip checksum mismatch"));
+                }
+                self.state = if self.bits.at_eof()? {
+                    State::Done
+                } else {
+                    State::Header
+                };
+            }
+            State::Done => {}
+        }
+        Ok(())
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let magic = [self.bits.bits(8)? as u8, self.bits.bits(8)? as u8];
+        if magic != MAGIC {
+            return Err(invalid("not in gzip format"));
+        }
+        if self.bits.bits(8)? != 8 {
+            return Err(invalid("unknown gzip compression method"));
+        }
+        let flags = self.bits.bits(8)?;
+        if flags & 0xe0 != 0 {
+            return Err(invalid("reserved gzip flags set"));
+        }
+        // Modification time, extra flags and OS.
+        for _ in 0..6 {
+            self.bits.bits(8)?;
+        }
+        if flags & 0x04 != 0 {
+            let len = self.bits.bits(16)?;
+            for _ in 0..len {
+                self.bits.bits(8)?;
+            }
+        }
+        // File name and comment, both zero-terminated.
+        for &flag in &[0x08, 0x10] {
+            if flags & flag != 0 {
+                while self.bits.bits(8)? != 0 {}
+            }
+        }
+        if flags & 0x02 != 0 {
+            self.bits.bits(16)?;
+        }
+        Ok(())
+    }
+
+    fn dynamic_tables(&mut self) -> io::Result<(Huffman, Huffman)> {
+        const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1,
+                                    15];
+        let nlen = self.bits.bits(5)? as usize + 257;
+        let ndist = self.bits.bits(5)? as usize + 1;
+        let ncode = self.bits.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err(invalid("too many length or distance codes"));
+        }
+        let mut lengths = [0u8; 19];
+        for &i in &ORDER[..ncode] {
+            lengths[i] = self.bits.bits(3)? as u8;
+        }
+        let code = Huffman::new(&lengths)?;
+
+        let mut lengths = vec![0u8; nlen + ndist];
+        let mut i = 0;
+        while i < lengths.len() {
+            let (value, repeat) = match self.bits.decode(&code)? {
+                sym @ 0..=15 => (sym as u8, 1),
+                16 if i == 0 => return Err(invalid("repeat with no previous length")),
+                16 => (lengths[i - 1], 3 + self.bits.bits(2)? as usize),
+                17 => (0, 3 + self.bits.bits(3)? as usize),
+                _ => (0, 11 + self.bits.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid("too many code lengths"));
+            }
+            for l in &mut lengths[i..i + repeat] {
+                *l = value;
+            }
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(invalid("no end-of-block code"));
+        }
+        Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+    }
+
+    /// Decodes symbols until the end of the block, returning `true`, or until
+    /// a window's worth of output is pending.
+    fn codes(&mut self, lit: &Huffman, dist: &Huffman) -> io::Result<bool> {
+        while self.buf.len() - self.pos < WINDOW {
+            let sym = self.bits.decode(lit)? as usize;
+            if sym < 256 {
+                self.buf.push(sym as u8);
+                continue;
+            }
+            if sym == 256 {
+                return Ok(true);
+            }
+            let sym = sym - 257;
+            if sym >= 29 {
+                return Err(invalid("invalid length code"));
+            }
+            let len = LEN_BASE[sym] as usize + self.bits.bits(LEN_EXTRA[sym] as u32)? as usize;
+            let sym = self.bits.decode(dist)? as usize;
+            if sym >= 30 {
+                return Err(invalid("invalid distance code"));
+            }
+            let d = DIST_BASE[sym] as usize + self.bits.bits(DIST_EXTRA[sym] as u32)? as usize;
+            if d > self.buf.len() {
+                return Err(invalid("distance too far back"));
+            }
+            let start = self.buf.len() - d;
+            for i in 0..len {
+                let b = self.buf[start + i];
+                self.buf.push(b);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Adds the output of the current member not yet checksummed.
+    fn finish_member(&mut self) {
+        let data = &self.buf[self.member_start..];
+        self.crc = crc32(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        self.member_start = self.buf.len();
+    }
+}
+
+impl<R: Read> Read for GzDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if let State::Done = self.state {
+                return Ok(0);
+            }
+            if self.buf.len() > 2 * WINDOW {
+                // Keep only the window, checksumming what's dropped first.
+                self.finish_member();
+                let drop = self.buf.len() - WINDOW;
+                self.buf.drain(..drop);
+                self.pos -= drop;
+                self.member_start -= drop;
+            }
+            self.step()?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn next_block(last: bool) -> State {
+    if last { State::Trailer } else { State::Block }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads bits least significant first, as DEFLATE packs them.
+struct BitReader<R> {
+    r: R,
+    input: Vec<u8>,
+    start: usize,
+    end: usize,
+    bitbuf: u64,
+    bitcnt: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(r: R) -> BitReader<R> {
+        BitReader {
+            r,
+            input: vec![0; 8192],
+            start: 0,
+            end: 0,
+            bitbuf: 0,
+            bitcnt: 0,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = loop {
+                match self.r.read(&mut self.input) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result?,
+                }
+            };
+        }
+        Ok(self.start < self.end)
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.bitcnt < n {
+            if !self.fill()? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated gzip stream"));
+            }
+            self.bitbuf |= (self.input[self.start] as u64) << self.bitcnt;
+            self.start += 1;
+            self.bitcnt += 8;
+        }
+        let value = (self.bitbuf & ((1u64 << n) - 1)) as u32;
+        self.bitbuf >>= n;
+        self.bitcnt -= n;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        let n = self.bitcnt % 8;
+        self.bitbuf >>= n;
+        self.bitcnt -= n;
+    }
+
+    fn at_eof(&mut self) -> io::Result<bool> {
+        Ok(self.bitcnt == 0 && !self.fill()?)
+    }
+
+    fn decode(&mut self, h: &Huffman) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= self.bits(1)? as i32;
+            let count = h.counts[len] as i32;
+            if code - count < first {
+                return Ok(h.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid Huffman code"))
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the
+/// symbols ordered by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed Huffman code"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = sym as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (sym, l) in lengths.iter_mut().enumerate() {
+        *l = match sym {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+/// Input compressed into one block at a time.
+const BLOCK: usize = 128 * 1024;
+/// Candidates tried for each match, trading speed for size.
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+/// A writer compressing into a gzip stream, with LZ77 matching and the fixed
+/// Huffman codes. The stream is completed by `finish`, or else when the
+/// encoder is dropped, where errors can only be ignored.
+///
+/// Once writing to the underlying writer has failed, the encoder refuses
+/// further writes and never writes the trailer, so that a stream cut short
+/// by a full disk fails to decompress with "truncated gzip stream" rather
+/// than passing for a complete one. Writes to a `BufWriter` may only fail
+/// when it is flushed, which `finish` does.
+pub struct GzEncoder<W: Write> {
+    w: Option<W>,
+    /// The last `WINDOW` bytes compressed, followed by those pending.
+    data: Vec<u8>,
+    pending: usize,
+    bitbuf: u64,
+    bitcnt: u32,
+    out: Vec<u8>,
+    crc: u32,
+    size: u32,
+    started: bool,
+    failed: bool,
+}
+
+impl<W: Write> GzEncoder<W> {
+    pub fn new(w: W) -> GzEncoder<W> {
+        GzEncoder {
+            w: Some(w),
+            data: Vec::new(),
+            pending: 0,
+            bitbuf: 0,
+            bitcnt: 0,
+            out: Vec::new(),
+            crc: 0,
+            size: 0,
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// Compresses what's pending, writes the trailer, flushes and returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()?;
+        Ok(self.w.take().unwrap())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<()> {
+        self.compress()?;
+        // An empty final block, then the trailer on a byte boundary.
+        self.put_bits(0b011, 3);
+        self.put_code(0, 7);
+        self.put_bits(0, (8 - self.bitcnt % 8) % 8);
+        let trailer = [self.crc.to_le_bytes(), self.size.to_le_bytes()].concat();
+        for b in trailer {
+            self.put_bits(b as u32, 8);
+        }
+        self.write_out()?;
+        self.w.as_mut().unwrap().flush()
+    }
+
+    /// Compresses the pending input into a block, unless there is none.
+    fn compress(&mut self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other("an earlier write of the gzip stream failed"));
+        }
+        if !self.started {
+            self.started = true;
+            self.out.extend_from_slice(&[MAGIC[0], MAGIC[1], 8, 0, 0, 0, 0, 0, 0, 0xff]);
+        }
+        if self.pending == 0 {
+            return self.write_out();
+        }
+        self.put_bits(0b010, 3);
+        let data = ::std::mem::take(&mut self.data);
+        let start = data.len() - self.pending;
+        let mut chains = Chains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; data.len()],
+        };
+        for i in 0..start {
+            chains.insert(&data, i);
+        }
+        let mut symbols = Vec::new();
+        let mut i = start;
+        while i < data.len() {
+            let max = (data.len() - i).min(258);
+            let (mut best_len, mut best_dist) = (0, 0);
+            if max >= 3 {
+                let mut candidate = chains.head[hash3(&data, i)];
+                let mut tries = 0;
+                while candidate != usize::MAX && i - candidate <= WINDOW && tries < MAX_CHAIN {
+                    let len = (0..max).take_while(|&k| data[candidate + k] == data[i + k]).count();
+                    if len > best_len {
+                        best_len = len;
+                        best_dist = i - candidate;
+                        if len == max {
+                            break;
+                        }
+                    }
+                    candidate = chains.prev[candidate];
+                    tries += 1;
+                }
+            }
+            if best_len >= 3 {
+                symbols.push((best_len as u16, best_dist as u16));
+                for k in i..i + best_len {
+                    chains.insert(&data, k);
+                }
+                i += best_len;
+            } else {
+                symbols.push((data[i] as u16, 0));
+                chains.insert(&data, i);
+                i += 1;
+            }
+        }
+        for (value, dist) in symbols {
+            if dist == 0 {
+                self.put_literal(value as usize);
+            } else {
+                self.put_match(value as usize, dist as usize);
+            }
+        }
+        self.put_literal(256);
+        self.data = data;
+        let drop = self.data.len() - self.data.len().min(WINDOW);
+        self.data.drain(..drop);
+        self.pending = 0;
+        self.write_out()
+    }
+
+    fn put_literal(&mut self, sym: usize) {
+        match sym {
+            0..=143 => self.put_code(0x30 + sym as u32, 8),
+            144..=255 => self.put_code(0x190 + sym as u32 - 144, 9),
+            256..=279 => self.put_code(sym as u32 - 256, 7),
+            _ => self.put_code(0xc0 + sym as u32 - 280, 8),
+        }
+    }
+
+    fn put_match(&mut self, len: usize, dist: usize) {
+        let sym = LEN_BASE.iter().rposition(|&base| base as usize <= len).unwrap();
+        self.put_literal(257 + sym);
+        self.put_bits((len - LEN_BASE[sym] as usize) as u32, LEN_EXTRA[sym] as u32);
+        let sym = DIST_BASE.iter().rposition(|&base| base as usize <= dist).unwrap();
+        self.put_code(sym as u32, 5);
+        self.put_bits((dist - DIST_BASE[sym] as usize) as u32, DIST_EXTRA[sym] as u32);
+    }
+
+    /// Writes a Huffman code, which DEFLATE packs most significant bit first.
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn put_bits(&mut self, value: u32, n: u32) {
+        self.bitbuf |= (value as u64) << self.bitcnt;
+        self.bitcnt += n;
+        while self.bitcnt >= 8 {
+            self.out.push(self.bitbuf as u8);
+            self.bitbuf >>= 8;
+            self.bitcnt -= 8;
+        }
+    }
+
+    /// Writes out the whole bytes produced so far.
+    fn write_out(&mut self) -> io::Result<()> {
+        let result = self.w.as_mut().unwrap().write_all(&self.out);
+        self.out.clear();
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+/// For each position, the previous one whose next three bytes hash alike.
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + 3 <= data.len() {
+            let h = hash3(data, i);
+            self.prev[i] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+}
+
+fn hash3(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+}
+
+impl<W: Write> Write for GzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("an earlier write of the gzip stream failed"));
+        }
+        self.data.extend_from_slice(buf);
+        self.pending += buf.len();
+        self.crc = crc32(self.crc, buf);
+        self.size = self.size.wrapping_add(buf.len() as u32);
+        if self.pending >= BLOCK {
+            self.compress()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Compresses what's pending and ends it with an empty stored block, so
+    /// that everything written so far can be decompressed.
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress()?;
+        self.put_bits(0, 3);
+        self.put_bits(0, (8 - self.bitcnt % 8) % 8);
+        self.put_bits(0xffff_0000, 32);
+        self.write_out()?;
+        self.w.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for GzEncoder<W> {
+    fn drop(&mut self) {
+        if self.w.is_some() && !self.failed {
+            let _ = self.finish_stream();
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Continues the CRC-32 `crc` of earlier data over `data`.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `printf 'host:a\nhost:b\n' | gzip -9n`, using the fixed Huffman codes.
+    const HOSTS: &[u8] = &[0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb,
+                           0xc8, 0x2f, 0x2e, 0xb1, 0x4a, 0xe4, 0xca, 0x00, 0x51, 0x49, 0x5c,
+                           0x00, 0xbc, 0x9c, 0xac, 0x7a, 0x0e, 0x00, 0x00, 0x00];
+
+    /// 12 lines of `access_log`, compressed with dynamic Huffman codes.
+    const ACCESS: &[u8] = &[0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x6d,
+                            0xd0, 0x4b, 0x0a, 0x84, 0x30, 0x14, 0x44, 0xd1, 0xb1, 0x2e, 0xa6,
+                            0x79, 0xbf, 0x18, 0xcd, 0x6e, 0x7a, 0x20, 0xe8, 0x40, 0x44, 0x13,
+                            0xf7, 0x6f, 0xd3, 0x20, 0x58, 0x50, 0x64, 0x16, 0x0e, 0xef, 0x42,
+                            0x2d, 0x7b, 0x6d, 0x45, 0xe5, 0xf3, 0x7f, 0x5d, 0x6d, 0xdf, 0x76,
+                            0xd5, 0x62, 0x22, 0xdd, 0x39, 0x1f, 0x6d, 0xdd, 0xe6, 0xf2, 0xfb,
+                            0x16, 0xe9, 0x97, 0x17, 0xd3, 0x87, 0x85, 0xc4, 0x9b, 0x79, 0x06,
+                            0x66, 0x0f, 0x4b, 0x78, 0x2d, 0x07, 0x30, 0xe7, 0x51, 0x55, 0x05,
+                            0x16, 0x3c, 0xaa, 0x31, 0x02, 0x4b, 0x3c, 0xaa, 0x63, 0x02, 0x36,
+                            0xf0, 0xa8, 0x99, 0x01, 0x13, 0x1e, 0xb5, 0x34, 0xf1, 0x41, 0x30,
+                            0x6a, 0xd3, 0xc0, 0x07, 0xc1, 0xa8, 0xbb, 0xf3, 0x41, 0x30, 0xea,
+                            0x59, 0xf8, 0x20, 0x18, 0x0d, 0xc9, 0xfd, 0x0d, 0xa8, 0x5e, 0x0b,
+                            0xfc, 0xd4, 0x01, 0x00, 0x00];
+
+    fn access_log() -> String {
+        (0..12)
+            .map(|i| {
+                format!("host:10.0.0.{}\tstatus:{}\treqtime:0.{:03}\n",
+                        i % 7,
+                        [200, 404, 500][i % 3],
+                        i * 37 % 1000)
+            })
+            .collect()
+    }
+
+    /// 200,000 `x`s, mostly as long matches; the output outgrows the buffer.
+    fn repeated() -> Vec<u8> {
+        let mut data = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xed,
+                            0xc1, 0x31, 0x01, 0x00, 0x00, 0x00, 0xc2, 0xa0, 0xda, 0x8b, 0x6f,
+                            0x06, 0x7f, 0xa0];
+        data.extend_from_slice(&[0; 193]);
+        data.extend_from_slice(&[0x80, 0xd7, 0x00, 0x72, 0x02, 0xbf, 0x51, 0x40, 0x0d, 0x03,
+                                 0x00]);
+        data
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(HOSTS).unwrap(), b"host:a\nhost:b\n");
+
+        let mut twice = HOSTS.to_vec();
+        twice.extend_from_slice(HOSTS);
+        assert_eq!(decode(&twice).unwrap(), b"host:a\nhost:b\nhost:a\nhost:b\n");
+
+        assert_eq!(String::from_utf8(decode(ACCESS).unwrap()).unwrap(), access_log());
+        assert_eq!(decode(&repeated()).unwrap(), vec![b'x'; 200000]);
+    }
+
+    #[test]
+    fn test_decode_stored() {
+        // A member holding one stored block with "abc".
+        let mut data = vec![0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 0xff, b'x', 0];
+        data.extend_from_slice(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c']);
+        data.extend_from_slice(&crc32(0, b"abc").to_le_bytes());
+        data.extend_from_slice(&3u32.to_le_bytes());
+        assert_eq!(decode(&data).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"host:a\n").is_err());
+        assert!(decode(&HOSTS[..HOSTS.len() - 4]).is_err());
+        let mut corrupt = HOSTS.to_vec();
+        corrupt[HOSTS.len() - 8] ^= 1;
+        assert!(decode(&corrupt).is_err());
+    }
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::new());
+        for chunk in data.chunks(1000) {
+            e.write_all(chunk).unwrap();
+        }
+        e.finish().unwrap()
+    }
+
+    /// Accepts `n` bytes, then fails like a full disk.
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("no space left on device"));
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let log = access_log().repeat(2_000).into_bytes();
+        let gz = encode(&log);
+        assert!(gz.len() < log.len() / 10, "{}", gz.len());
+        assert_eq!(decode(&gz).unwrap(), log);
+
+        // Noise, which matches little, and long runs past the block size.
+        let mut x = 1u32;
+        let noise = (0..300_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect::<Vec<u8>>();
+        assert_eq!(decode(&encode(&noise)).unwrap(), noise);
+        assert_eq!(decode(&encode(&[b'x'; 300_000])).unwrap(), vec![b'x'; 300_000]);
+        assert_eq!(decode(&encode(b"")).unwrap(), b"");
+
+        let mut twice = encode(b"host:a\n");
+        twice.extend_from_slice(&encode(b"host:b\n"));
+        assert_eq!(decode(&twice).unwrap(), b"host:a\nhost:b\n");
+    }
+
+    #[test]
+    fn test_encode_flush() {
+        let mut e = GzEncoder::new(Vec::new());
+        e.write_all(b"host:a\n").unwrap();
+        e.flush().unwrap();
+        let flushed = e.w.as_ref().unwrap().clone();
+        let mut out = vec![0; 7];
+        GzDecoder::new(&flushed[..]).read_exact(&mut out).unwrap();
+        assert_eq!(out, b"host:a\n");
+
+        e.write_all(b"host:b\n").unwrap();
+        assert_eq!(decode(&e.finish().unwrap()).unwrap(), b"host:a\nhost:b\n");
+    }
+
+    #[test]
+    fn test_encode_failed() {
+        let mut e = GzEncoder::new(Full(20));
+        e.write_all(&access_log().into_bytes()).unwrap();
+        assert!(e.flush().is_err());
+        assert!(e.write_all(b"host:a\n").is_err());
+        assert!(e.finish().is_err());
+
+        // Dropping doesn't complete a stream that failed.
+        let mut out = Vec::new();
+        {
+            let mut e = GzEncoder::new(&mut out);
+            e.write_all(b"host:a\n").unwrap();
+            e.compress().unwrap();
+            e.failed = true;
+        }
+        match decode(&out) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            r => panic!("expected a truncated stream: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use error::*;
+
+/// A HyperLogLog sketch estimating the number of distinct values inserted.
+///
+/// A sketch of precision `p` uses 2^`p` one-byte registers and has a
+/// standard error of about `1.04 / sqrt(2^p)`: 1.6% for the default
+/// precision of 12, which takes 4 KB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+pub const DEFAULT_PRECISION: u8 = 12;
+
+impl HyperLogLog {
+    /// Creates an empty sketch. `precision` must be between 4 and 16.
+    pub fn new(precision: u8) -> Result<HyperLogLog, Error> {
+        if !(4..=16).contains(&precision) {
+            let msg = format!("precision must be between 4 and 16: {}", precision);
+            return Err(Error::InvalidArgument(msg));
+        }
+        Ok(HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        })
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// The standard error of the estimate, relative to the true count.
+    pub fn relative_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let h = hash(value);
+        let p = self.precision as u32;
+        let index = (h >> (64 - p)) as usize;
+        let rank = ((h << p) | (1 << (p - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Adds the values counted by `other`, so that the sketch estimates the
+    /// distinct count of the union. Both must have the same precision.
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), Error> {
+        if other.precision != self.precision {
+            let msg = format!("can't merge sketches of precision {} and {}",
+                              self.precision,
+                              other.precision);
+            return Err(Error::InvalidArgument(msg));
+        }
+        for (r, &o) in self.registers.iter_mut().zip(&other.registers) {
+            if o > *r {
+                *r = o;
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimates the number of distinct values inserted.
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities.
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+/// FNV-1a followed by the splitmix64 finalizer, which spreads FNV's weak low
+/// bits over the whole word.
+fn hash(value: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in value.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sketch(values: ::std::ops::Range<u32>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new(DEFAULT_PRECISION).unwrap();
+        for i in values {
+            hll.insert(&format!("10.{}.{}.{}", i >> 16, (i >> 8) & 0xff, i & 0xff));
+        }
+        hll
+    }
+
+    #[test]
+    fn test_estimate() {
+        assert_eq!(HyperLogLog::new(8).unwrap().estimate(), 0);
+        for &n in &[10, 1_000, 100_000] {
+            let hll = sketch(0..n);
+            let error = (hll.estimate() as f64 - n as f64).abs() / n as f64;
+            assert!(error < 3.0 * hll.relative_error(), "{}: {}", n, hll.estimate());
+        }
+
+        let mut hll = sketch(0..1_000);
+        hll.insert("10.0.0.1");
+        assert_eq!(hll, sketch(0..1_000));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = sketch(0..6_000);
+        a.merge(&sketch(4_000..10_000)).unwrap();
+        assert_eq!(a, sketch(0..10_000));
+
+        assert!(a.merge(&HyperLogLog::new(10).unwrap()).is_err());
+        assert!(HyperLogLog::new(3).is_err());
+        assert!(HyperLogLog::new(17).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use error::*;
+use ltsv::Record;
+
+/// Options controlling how JSON objects are turned into records.
+#[derive(Clone, Debug)]
+pub struct JsonOptions {
+    /// Flatten nested objects into `.`-joined labels (`{"a":{"b":1}}` becomes
+    /// `a.b:1`). When unset, nested objects are an error.
+    pub flatten: bool,
+}
+
+impl Default for JsonOptions {
+    fn default() -> JsonOptions {
+        JsonOptions { flatten: true }
+    }
+}
+
+/// Parses one JSON object into a record.
+///
+/// Strings are unescaped, numbers keep their textual form (`1.0` stays
+/// `1.0`), booleans become `true`/`false` and arrays are kept as their JSON
+/// text. Members whose value is `null` are left out. On duplicate labels the
+/// last member wins.
+pub fn parse_object(line: &str, opts: &JsonOptions) -> Result<Record, Error> {
+    let mut p = Parser { s: line, pos: 0, opts, depth: 0 };
+    let mut record = Record::default();
+    p.space();
+    p.object("", &mut record)?;
+    p.space();
+    if p.pos != line.len() {
+        return Err(p.error("trailing characters"));
+    }
+    Ok(record)
+}
+
+/// How deeply objects and arrays may nest, bounding the parser's recursion.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    opts: &'a JsonOptions,
+    /// The objects and arrays entered and not yet left.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> Error {
+        let msg = format!("invalid json: {} at byte {}", what, self.pos);
+        Error::Parse(ParseError::new(msg))
+    }
+
+    /// Runs `f` on a nested object or array, one level deeper.
+    fn nested<T, F>(&mut self, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Parser<'a>) -> Result<T, Error>
+    {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).cloned()
+    }
+
+    fn space(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), Error> {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", b as char)))
+        }
+    }
+
+    /// Parses an object whose members are stored under `prefix`.
+    fn object(&mut self, prefix: &str, record: &mut Record) -> Result<(), Error> {
+        self.expect(b'{')?;
+        self.space();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            self.space();
+            let key = format!("{}{}", prefix, self.string()?);
+            self.space();
+            self.expect(b':')?;
+            self.space();
+            match self.peek() {
+                Some(b'{') if self.opts.flatten => {
+                    let prefix = format!("{}.", key);
+                    self.nested(|p| p.object(&prefix, record))?
+                }
+                Some(b'{') => return Err(self.error("nested object")),
+                Some(b'n') => {
+                    self.keyword("null")?;
+                    record.remove(&key);
+                }
+                _ => {
+                    let value = self.scalar()?;
+                    record.insert(key, value);
+                }
+            }
+            self.space();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    /// Parses a non-object, non-null value into its record form.
+    fn scalar(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(b'"') => self.string(),
+            Some(b't') => self.keyword("true").map(|_| "true".to_string()),
+            Some(b'f') => self.keyword("false").map(|_| "false".to_string()),
+            Some(b'[') => {
+                let start = self.pos;
+                self.skip_value()?;
+                Ok(self.s[start..self.pos].to_string())
+            }
+            _ => {
+                let start = self.pos;
+                self.number()?;
+                Ok(self.s[start..self.pos].to_string())
+            }
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> Result<(), Error> {
+        if self.s[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(())
+        } else {
+            Err(self.error("unexpected token"))
+        }
+    }
+
+    fn number(&mut self) -> Result<(), Error> {
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.digits(),
+            _ => return Err(self.error("unexpected token")),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            self.required_digits()?;
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            self.required_digits()?;
+        }
+        Ok(())
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+
+    fn required_digits(&mut self) -> Result<(), Error> {
+        let start = self.pos;
+        self.digits();
+        if self.pos == start {
+            return Err(self.error("expected digit"));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            let c = match self.s[self.pos..].chars().next() {
+                Some(c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => out.push(self.escape()?),
+                c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, Error> {
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                self.pos += 1;
+                let high = self.hex4()?;
+                let code = if (0xd800..0xdc00).contains(&high) {
+                    if !self.s[self.pos..].starts_with("\\u") {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    self.pos += 2;
+                    let low = self.hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                } else {
+                    high
+                };
+                return ::std::char::from_u32(code).ok_or_else(|| self.error("invalid escape"));
+            }
+            _ => return Err(self.error("invalid escape")),
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = match self.s.get(self.pos..self.pos + 4) {
+            Some(digits) if digits.bytes().all(|b| b.is_ascii_hexdigit()) => digits,
+            _ => return Err(self.error("invalid escape")),
+        };
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    /// Skips over any value, validating it.
+    fn skip_value(&mut self) -> Result<(), Error> {
+        match self.peek() {
+            Some(b'[') => self.nested(|p| p.skip_array()),
+            Some(b'{') => {
+                self.nested(|p| {
+                    let opts = JsonOptions { flatten: true };
+                    let mut nested = Parser { s: p.s, pos: p.pos, opts: &opts, depth: p.depth };
+                    nested.object("", &mut Record::default())?;
+                    p.pos = nested.pos;
+                    Ok(())
+                })
+            }
+            Some(b'n') => self.keyword("null"),
+            _ => self.scalar().map(|_| ()),
+        }
+    }
+
+    fn skip_array(&mut self) -> Result<(), Error> {
+        self.expect(b'[')?;
+        self.space();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            self.space();
+            self.skip_value()?;
+            self.space();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(line: &str) -> Record {
+        parse_object(line, &JsonOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let record = parse(r#"{"host":"a\tbé😀","status":200,"ok":true,"t":[1, {"x":2}]}"#);
+        assert_eq!(record["host"], "a\tbé\u{1f600}");
+        assert_eq!(record["status"], "200");
+        assert_eq!(record["ok"], "true");
+        assert_eq!(record["t"], r#"[1, {"x":2}]"#);
+        assert_eq!(parse(" {} ").len(), 0);
+    }
+
+    #[test]
+    fn test_numbers_keep_text() {
+        let record = parse(r#"{"a":1.0,"b":-0.50,"c":1e3,"d":12345678901234567890}"#);
+        assert_eq!(record["a"], "1.0");
+        assert_eq!(record["b"], "-0.50");
+        assert_eq!(record["c"], "1e3");
+        assert_eq!(record["d"], "12345678901234567890");
+    }
+
+    #[test]
+    fn test_null() {
+        let record = parse(r#"{"a":null,"b":"null","c":1,"c":null}"#);
+        assert!(!record.contains_key("a"));
+        assert_eq!(record["b"], "null");
+        assert!(!record.contains_key("c"));
+    }
+
+    #[test]
+    fn test_nested() {
+        let line = r#"{"req":{"method":"GET","headers":{"host":"x"}},"req.size":3}"#;
+        let record = parse(line);
+        assert_eq!(record["req.method"], "GET");
+        assert_eq!(record["req.headers.host"], "x");
+        assert_eq!(record["req.size"], "3");
+
+        let opts = JsonOptions { flatten: false };
+        assert!(parse_object(line, &opts).is_err());
+        assert!(parse_object(r#"{"a":[{"b":1}]}"#, &opts).is_ok());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for line in &["", "[]", "{", r#"{"a"}"#, r#"{"a":01}"#, r#"{"a":1.}"#, r#"{"a":tru}"#,
+                      r#"{"a":"\x"}"#, r#"{"a":1} x"#, r#"{"a":[1,]}"#, r#"{"a":"\ud800"}"#] {
+            assert!(parse_object(line, &JsonOptions::default()).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let deep_array = format!(r#"{{"a":{}"#, "[".repeat(100_000));
+        let deep_object = format!("{}1", r#"{"a":"#.repeat(100_000));
+        let deep_in_array = format!(r#"{{"a":{}"#, r#"[{"a":"#.repeat(100_000));
+        for line in &[deep_array, deep_object, deep_in_array] {
+            let err = parse_object(line, &JsonOptions::default()).unwrap_err();
+            assert!(format!("{}", err).contains("nesting too deep"), "{}", err);
+        }
+        let line = format!(r#"{{"a":{}{}}}"#, "[".repeat(100), "]".repeat(100));
+        assert_eq!(parse(&line)["a"].len(), 200);
+    }
+}
@@ -1,4 +1,13 @@
 #[macro_use]
 pub mod error;
+pub mod cidr;
+pub mod combined;
 pub mod config;
+pub mod fxhash;
+pub mod gzip;
+pub mod hll;
+pub mod json;
 pub mod ltsv;
+pub mod regex;
+pub mod timefmt;
+pub mod useragent;
@@ -1,195 +1,6346 @@
-use std::io::{self, BufRead, BufReader, Stdin, stdin};
-use std::fs::File;
-use std::path::Path;
-use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write, stdin};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::iter;
+use std::vec;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+use std::str::FromStr;
 
+use cidr::{self, Cidr};
+use combined;
 use error::*;
+#[cfg(feature = "fxhash")]
+use fxhash::FxBuildHasher;
+use gzip::{self, GzDecoder, GzEncoder};
+use hll::HyperLogLog;
+use json::{self, JsonOptions};
+use regex::Regex;
+use timefmt::{self, TimeFormat};
+use useragent::{UaFacet, UaRules};
 
-pub type Record = HashMap<String, String>;
-pub type FieldGroupCount = HashMap<String, i32>;
+/// The hasher of `Record` and `FieldGroupCount`: the standard, DoS-resistant
+/// SipHash by default, or FxHash with the `fxhash` feature. FxHash is faster
+/// on short labels and values, but input crafted to collide can slow hash
+/// maps down to linear time, so leave it off for untrusted input.
+#[cfg(not(feature = "fxhash"))]
+pub type LabelHasher = RandomState;
+#[cfg(feature = "fxhash")]
+pub type LabelHasher = FxBuildHasher;
 
-pub enum LineReader {
-    Stdin(Stdin),
-    FileIn(BufReader<File>),
+pub type RecordWith<S> = HashMap<String, String, S>;
+pub type GroupCountWith<S> = HashMap<String, i32, S>;
+pub type Record = RecordWith<LabelHasher>;
+pub type FieldGroupCount = GroupCountWith<LabelHasher>;
+
+/// Options controlling how input lines are turned into records.
+///
+/// Only the functions taking `ParseOptions`, mostly the `_with` variants,
+/// honor them; the others read with the defaults, so they can't be cancelled,
+/// report no progress and fail on the first malformed line.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Skip malformed lines instead of failing on them.
+    pub lenient: bool,
+    /// Skip comment lines, i.e. lines starting with this prefix (such as `#`).
+    /// Leading whitespace makes a line an ordinary one. Off by default, since
+    /// `#` may legitimately start a label.
+    pub comment_prefix: Option<String>,
+    /// Unescape `\t`, `\n`, `\r` and `\\` in values when parsing, and escape
+    /// them when serializing.
+    pub escape: bool,
+    /// The character separating a label from its value. Only its first
+    /// occurrence in a field separates; later ones belong to the value.
+    pub kv_separator: char,
+    /// What to do with blank lines.
+    pub blank_lines: BlankPolicy,
+    /// How values are normalized before `group_by` and `order_by` compare
+    /// them.
+    pub normalize: KeyNormalize,
+    /// A callback reporting progress while the input is read.
+    pub progress: Option<ProgressHook>,
+    /// A flag checked before every line is read. Once it is set, reading
+    /// stops with `Error::Cancelled`. Plain functions such as `cardinality`
+    /// can't be cancelled; use their `_with` variants.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The longest line accepted, in bytes without the line terminator. A
+    /// longer line fails with a parse error, or is skipped and counted in
+    /// `LineReader::long_lines` in lenient mode. Either way it is never held
+    /// in memory whole.
+    pub max_line_len: usize,
+    /// A budget for the lines `order_by` holds in memory. Going over it fails
+    /// with `Error::MemoryLimit` instead of exhausting memory. Each line is
+    /// accounted as its length plus `LINE_OVERHEAD`.
+    pub max_memory_bytes: Option<usize>,
+    /// What ends each record, in the input and in output written with these
+    /// options.
+    pub delimiter: RecordDelimiter,
+    /// What to do with lines that aren't valid UTF-8.
+    pub invalid_utf8: Utf8Policy,
+    /// The number of distinct values `group_by` is expected to count, so its
+    /// map can be sized up front. 0 lets the map grow as needed.
+    pub hint_groups: usize,
+    /// The number of records `collect_records_with` is expected to return,
+    /// likewise.
+    pub hint_records: usize,
 }
 
-impl LineReader {
-    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+/// The approximate memory a buffered line takes beyond its text.
+pub const LINE_OVERHEAD: usize = 64;
+
+/// The default `ParseOptions::max_line_len`, 16 MiB.
+pub const DEFAULT_MAX_LINE_LEN: usize = 16 << 20;
+
+/// How far the reading of an input has got.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Records read, including malformed ones skipped in lenient mode.
+    pub records: u64,
+    pub bytes_read: u64,
+    /// The time since the input was opened.
+    pub elapsed: Duration,
+}
+
+/// A callback invoked after every `interval` records and once more at EOF.
+/// It is `Send`, so options carrying it can be moved to another thread.
+#[derive(Clone)]
+pub struct ProgressHook {
+    interval: u64,
+    callback: Arc<Mutex<dyn FnMut(Progress) + Send>>,
+}
+
+impl ProgressHook {
+    /// Creates a hook calling `f` every `interval` records. An interval of 0
+    /// is taken as 1.
+    pub fn new<F>(interval: u64, f: F) -> ProgressHook
+        where F: FnMut(Progress) + Send + 'static
+    {
+        ProgressHook {
+            interval: interval.max(1),
+            callback: Arc::new(Mutex::new(f)),
+        }
+    }
+}
+
+// `ParseOptions` must stay `Send` for reading on a worker thread.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<ParseOptions>();
+};
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressHook {{ interval: {} }}", self.interval)
+    }
+}
+
+/// A normalization applied to values used as grouping or sort keys. The
+/// values themselves are output unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyNormalize {
+    /// Compare values as they are.
+    Exact,
+    /// Fold ASCII letters to lowercase.
+    AsciiCase,
+    /// Fold all letters to lowercase using the Unicode case mappings.
+    UnicodeCase,
+}
+
+impl KeyNormalize {
+    /// Returns the key `value` is compared by.
+    pub fn apply(&self, value: &str) -> String {
         match *self {
-            LineReader::Stdin(ref mut r) => r.read_line(buf),
-            LineReader::FileIn(ref mut r) => r.read_line(buf),
+            KeyNormalize::Exact => value.to_string(),
+            KeyNormalize::AsciiCase => value.to_ascii_lowercase(),
+            KeyNormalize::UnicodeCase => value.to_lowercase(),
         }
     }
 }
 
-pub fn open_file(name: &str) -> Result<LineReader, Error> {
-    match name {
-        "-" => Ok(LineReader::Stdin(stdin())),
-        _ => {
-            let f = File::open(&Path::new(name))?;
-            Ok(LineReader::FileIn(BufReader::new(f)))
+/// The byte records end with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordDelimiter {
+    /// `\n`, optionally preceded by `\r`.
+    Newline,
+    /// `\0`, as written by `find -print0` and read by `xargs -0`. Values may
+    /// then contain newlines, though escaping is still needed to write them.
+    Nul,
+}
+
+impl RecordDelimiter {
+    pub fn byte(self) -> u8 {
+        match self {
+            RecordDelimiter::Newline => b'\n',
+            RecordDelimiter::Nul => 0,
+        }
+    }
+
+    /// Strips the delimiter from the end of `record`.
+    fn trim(self, record: &str) -> &str {
+        match self {
+            RecordDelimiter::Newline => trim_newline(record),
+            RecordDelimiter::Nul => record.strip_suffix('\0').unwrap_or(record),
         }
     }
 }
 
-pub fn parse_head(input: &mut LineReader) -> Result<Record, Error> {
-    let found: String;
-    loop {
-        let mut line = String::new();
-        match input.read_line(&mut line).map_err(Error::Io) {
-            Ok(0) | Ok(1) => continue,
-            Ok(_) => {
-                found = line;
-                break;
-            }
-            Err(err) => return Err(err),
+/// How lines that aren't valid UTF-8 are treated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Utf8Policy {
+    /// Fail with a parse error naming the line number, or skip the line in
+    /// lenient mode.
+    Strict,
+    /// Replace each invalid sequence with U+FFFD and carry on.
+    Lossy,
+}
+
+/// How blank lines in the input are treated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlankPolicy {
+    /// Skip them silently.
+    Skip,
+    /// Fail with a parse error naming the line number.
+    Error,
+    /// Skip them, counting them in `LineReader::blank_lines`.
+    Count,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            lenient: false,
+            comment_prefix: None,
+            escape: false,
+            kv_separator: ':',
+            blank_lines: BlankPolicy::Skip,
+            normalize: KeyNormalize::Exact,
+            progress: None,
+            cancel: None,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            max_memory_bytes: None,
+            delimiter: RecordDelimiter::Newline,
+            invalid_utf8: Utf8Policy::Strict,
+            hint_groups: 0,
+            hint_records: 0,
+        }
+    }
+}
+
+/// A line-oriented LTSV input. A UTF-8 byte order mark at the very start of
+/// the stream is dropped, and gzip-compressed stdin is decompressed.
+///
+/// `LineReader` also implements `Read` and `BufRead`. Its `read_line` counts
+/// lines and drops the byte order mark as above, while the other methods pass
+/// the bytes through unchanged.
+pub struct LineReader {
+    source: Source,
+    /// The file name, for error messages.
+    name: Option<String>,
+    line_number: u64,
+    blank_lines: u64,
+    bytes_read: u64,
+    total_len: Option<u64>,
+    start_offset: u64,
+    /// The length of the last line read if it lacked its terminator.
+    partial: u64,
+    skipped: u64,
+    long_lines: u64,
+    records: u64,
+    opened: Instant,
+    reported_eof: bool,
+}
+
+enum Source {
+    /// Stdin before its first read, when it's checked for gzip.
+    Stdin,
+    FileIn(BufReader<File>),
+    Boxed(Box<dyn BufRead>),
+}
+
+const BOM: &str = "\u{feff}";
+
+impl LineReader {
+    fn new(source: Source) -> LineReader {
+        LineReader {
+            source,
+            name: None,
+            line_number: 0,
+            blank_lines: 0,
+            bytes_read: 0,
+            total_len: None,
+            start_offset: 0,
+            partial: 0,
+            skipped: 0,
+            long_lines: 0,
+            records: 0,
+            opened: Instant::now(),
+            reported_eof: false,
+        }
+    }
+
+    /// The number of lines read so far.
+    pub fn line_number(&self) -> u64 {
+        self.line_number
+    }
+
+    /// The number of blank lines counted under `BlankPolicy::Count`.
+    pub fn blank_lines(&self) -> u64 {
+        self.blank_lines
+    }
+
+    /// The number of over-long lines skipped in lenient mode.
+    pub fn long_lines(&self) -> u64 {
+        self.long_lines
+    }
+
+    /// The number of bytes consumed so far. For compressed input these are
+    /// decompressed bytes.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The size of the input file, or `None` when reading from anything else.
+    pub fn total_len(&self) -> Option<u64> {
+        self.total_len
+    }
+
+    /// The offset in the input just past the last complete line read, to be
+    /// passed to `open_file_at` to carry on from there later. A last line
+    /// without a terminator, as when a log is being written to, doesn't count
+    /// as read, so the next run reads it again once it is complete.
+    pub fn offset(&self) -> u64 {
+        self.start_offset + self.bytes_read - self.partial
+    }
+
+    /// The length of the partial line `open_file_at` skipped because its
+    /// offset was not at the start of a line.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Reads from any buffered reader, such as a network stream, a
+    /// decompressor or an in-memory `Cursor`.
+    pub fn from_reader<R: BufRead + 'static>(r: R) -> LineReader {
+        LineReader::new(Source::Boxed(Box::new(r)))
+    }
+
+    fn source(&mut self) -> io::Result<&mut dyn BufRead> {
+        if let Source::Stdin = self.source {
+            self.source = Source::Boxed(sniff_gzip(stdin().lock())?);
+        }
+        Ok(match self.source {
+            Source::Stdin => unreachable!(),
+            Source::FileIn(ref mut r) => r,
+            Source::Boxed(ref mut r) => &mut **r,
+        })
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.read_record(buf, RecordDelimiter::Newline)
+    }
+
+    /// Appends the bytes up to and including the next `delimiter` to `buf`,
+    /// as `BufRead::read_until` does, and returns their number, 0 at EOF.
+    /// The record is counted as a line, and a byte order mark is dropped from
+    /// the first one. Invalid UTF-8 is an error, leaving `buf` unchanged.
+    pub fn read_record(&mut self,
+                       buf: &mut String,
+                       delimiter: RecordDelimiter)
+                       -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.source()?.read_until(delimiter.byte(), &mut bytes)?;
+        self.bytes_read += n as u64;
+        if n == 0 {
+            return Ok(0);
         }
+        self.partial = if bytes.last() == Some(&delimiter.byte()) { 0 } else { n as u64 };
+        let start = buf.len();
+        buf.push_str(&String::from_utf8(bytes).map_err(|_| invalid_utf8())?);
+        self.finish_line(buf, start);
+        Ok(n)
     }
 
-    let mut record = Record::new();
-    for field in found.split('\t').collect::<Vec<&str>>().into_iter() {
-        let v = field.splitn(2, ':').collect::<Vec<&str>>();
-        match v.len() {
-            0 | 1 => {
-                return Err(ParseError { msg: format!("invalid ltsv field: {}", field) })
-                    .map_err(Error::Parse);
+    /// Like `read_record` with `opts.delimiter`, but a record of more than
+    /// `opts.max_line_len` bytes, not counting its terminator, is consumed
+    /// without being stored, and invalid UTF-8 is handled as `opts` says.
+    fn read_line_max(&mut self, buf: &mut String, opts: &ParseOptions) -> io::Result<LineRead> {
+        let (max, delimiter) = (opts.max_line_len, opts.delimiter);
+        let limit = max.saturating_add("\r\n".len());
+        let mut bytes = Vec::new();
+        let mut len = 0;
+        let mut overflowed = false;
+        let mut found = false;
+        while !found {
+            let used = {
+                let available = match self.source()?.fill_buf() {
+                    Ok(available) => available,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                if available.is_empty() {
+                    break;
+                }
+                let used = match available.iter().position(|&b| b == delimiter.byte()) {
+                    Some(i) => {
+                        found = true;
+                        i + 1
+                    }
+                    None => available.len(),
+                };
+                if !overflowed {
+                    bytes.extend_from_slice(&available[..used]);
+                }
+                used
+            };
+            self.source()?.consume(used);
+            self.bytes_read += used as u64;
+            len += used as u64;
+            if bytes.len() > limit {
+                overflowed = true;
+                bytes = Vec::new();
+            }
+        }
+        if len == 0 {
+            return Ok(LineRead::Eof);
+        }
+        self.partial = if found { 0 } else { len };
+        let content_len = match delimiter {
+            RecordDelimiter::Newline => trim_newline_bytes(&bytes).len(),
+            RecordDelimiter::Nul => bytes.strip_suffix(b"\0").unwrap_or(&bytes).len(),
+        };
+        if overflowed || content_len > max {
+            self.line_number += 1;
+            return Ok(LineRead::TooLong(if found { len - 1 } else { len }));
+        }
+        let line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(err) => {
+                if opts.invalid_utf8 == Utf8Policy::Strict {
+                    self.line_number += 1;
+                    return Ok(LineRead::InvalidUtf8(err.utf8_error().valid_up_to()));
+                }
+                String::from_utf8_lossy(err.as_bytes()).into_owned()
             }
-            2 => record.insert(v[0].to_string(), v[1].to_string()),
-            _ => {
-                return Err(ParseError { msg: format!("unreachable error: {}", field) })
-                    .map_err(Error::Parse);
+        };
+        let start = buf.len();
+        buf.push_str(&line);
+        self.finish_line(buf, start);
+        Ok(LineRead::Line)
+    }
+
+    /// Counts the line just read into `buf` from `start` on, dropping a byte
+    /// order mark from the first one.
+    fn finish_line(&mut self, buf: &mut String, start: usize) {
+        self.line_number += 1;
+        if self.line_number == 1 && buf[start..].starts_with(BOM) {
+            buf.replace_range(start..start + BOM.len(), "");
+        }
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+}
+
+/// The outcome of `LineReader::read_line_max`.
+enum LineRead {
+    Eof,
+    Line,
+    /// A line over the limit, of this many bytes without its newline.
+    TooLong(u64),
+    /// A line with invalid UTF-8 after this many bytes.
+    InvalidUtf8(usize),
+}
+
+impl Read for LineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source()?.read(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+impl BufRead for LineReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.source()?.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Ok(source) = self.source() {
+            source.consume(amt);
+            self.bytes_read += amt as u64;
+        }
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        LineReader::read_line(self, buf)
+    }
+}
+
+/// Returns `r`, decompressed if it starts with the gzip magic bytes. The bytes
+/// peeked at are put back in front of the stream.
+fn sniff_gzip<R: BufRead + 'static>(mut r: R) -> io::Result<Box<dyn BufRead>> {
+    let mut head = Vec::new();
+    while head.len() < gzip::MAGIC.len() {
+        let n = {
+            let buf = r.fill_buf()?;
+            if buf.is_empty() {
+                break;
             }
+            let n = buf.len().min(gzip::MAGIC.len() - head.len());
+            head.extend_from_slice(&buf[..n]);
+            n
         };
+        r.consume(n);
+    }
+    let is_gzip = head == gzip::MAGIC;
+    let input = io::Cursor::new(head).chain(r);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(GzDecoder::new(input))))
+    } else {
+        Ok(Box::new(input))
+    }
+}
+
+/// Opens `name` for reading, where `-` means stdin.
+pub fn open_file(name: &str) -> Result<LineReader, Error> {
+    match name {
+        "-" => Ok(LineReader::new(Source::Stdin)),
+        _ => open_path(name),
+    }
+}
+
+/// Opens the file at `path` for reading. Unlike `open_file`, `-` is not
+/// treated specially.
+pub fn open_path<P: AsRef<Path>>(path: P) -> Result<LineReader, Error> {
+    let f = File::open(path.as_ref())?;
+    let len = f.metadata()?.len();
+    let mut reader = LineReader::new(Source::FileIn(BufReader::new(f)));
+    reader.name = Some(path.as_ref().display().to_string());
+    reader.total_len = Some(len);
+    Ok(reader)
+}
+
+/// Opens the file `name` for reading from byte `offset` on, as returned by
+/// `LineReader::offset` after an earlier run.
+///
+/// If `offset` falls inside a line, as when the file has been rewritten
+/// since, the rest of that line is skipped and its length reported by
+/// `LineReader::skipped_bytes`. An offset past the end of the file and stdin
+/// are refused.
+pub fn open_file_at(name: &str, offset: u64) -> Result<LineReader, Error> {
+    if name == "-" {
+        return Err(Error::InvalidArgument("can't seek in stdin".to_string()));
+    }
+    let mut f = File::open(name)?;
+    let len = f.metadata()?.len();
+    if offset > len {
+        let msg = format!("offset {} is past the end of {} ({} bytes)", offset, name, len);
+        return Err(Error::InvalidArgument(msg));
+    }
+    let mut at_line_start = true;
+    if offset > 0 {
+        f.seek(SeekFrom::Start(offset - 1))?;
+        let mut previous = [0];
+        f.read_exact(&mut previous)?;
+        at_line_start = previous[0] == b'\n';
+    }
+    f.seek(SeekFrom::Start(offset))?;
+    let mut reader = LineReader::new(Source::FileIn(BufReader::new(f)));
+    reader.name = Some(name.to_string());
+    reader.total_len = Some(len);
+    reader.start_offset = offset;
+    if !at_line_start {
+        let mut partial = Vec::new();
+        reader.source()?.read_until(b'\n', &mut partial)?;
+        reader.skipped = partial.len() as u64;
+        reader.start_offset += reader.skipped;
+    }
+    Ok(reader)
+}
+
+/// Connects to `addr` and reads from the connection, where `addr` is
+/// `unix:PATH` for a Unix domain socket or `tcp:HOST:PORT`. The input ends
+/// when the peer closes the connection.
+pub fn open_socket(addr: &str) -> Result<LineReader, Error> {
+    open_socket_with_timeout(addr, None)
+}
+
+/// Like `open_socket`, but a read that has waited longer than `timeout` fails
+/// with an `Error::Io`.
+pub fn open_socket_with_timeout(addr: &str,
+                                timeout: Option<Duration>)
+                                -> Result<LineReader, Error> {
+    if let Some(host_port) = addr.strip_prefix("tcp:") {
+        let stream = TcpStream::connect(host_port)?;
+        stream.set_read_timeout(timeout)?;
+        return Ok(LineReader::from_reader(BufReader::new(stream)));
+    }
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return open_unix_socket(path, timeout);
+    }
+    Err(Error::InvalidArgument(format!("unsupported socket address: {}", addr)))
+}
+
+#[cfg(unix)]
+fn open_unix_socket(path: &str, timeout: Option<Duration>) -> Result<LineReader, Error> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(timeout)?;
+    Ok(LineReader::from_reader(BufReader::new(stream)))
+}
+
+#[cfg(not(unix))]
+fn open_unix_socket(path: &str, _: Option<Duration>) -> Result<LineReader, Error> {
+    Err(Error::InvalidArgument(format!("unix sockets are not supported: {}", path)))
+}
+
+pub fn parse_head(input: &mut LineReader) -> Result<Record, Error> {
+    parse_head_with(input, &ParseOptions::default())
+}
+
+/// Parses the first record of the input. An input without any record is a
+/// parse error.
+pub fn parse_head_with(input: &mut LineReader, opts: &ParseOptions) -> Result<Record, Error> {
+    let mut found = String::new();
+    if !next_line_with(input, opts, &mut found)? {
+        return Err(Error::Parse(ParseError::new("no ltsv record found")));
     }
 
+    let mut record = Record::default();
+    parse_fields(&found, opts.kv_separator, &mut |label, value| {
+            record.insert(label.to_string(), unescape_with(opts, value));
+            Ok(())
+        })
+        .map_err(|err| at_line(input, err))?;
     Ok(record)
 }
 
+/// Returns the labels of the first record in the order they appear, such as
+/// to pass to `to_csv` as its columns. A repeated label is listed once. The
+/// record is consumed, so the input has to be reopened to process it.
+pub fn header_labels(input: &mut LineReader) -> Result<Vec<String>, Error> {
+    let mut line = String::new();
+    if !next_line(input, &mut line)? {
+        return Err(Error::Parse(ParseError::new("no ltsv record found")));
+    }
+    let mut seen = HashSet::new();
+    Ok(parse_pairs(&line, ':').map_err(|err| at_line(input, err))?
+        .into_iter()
+        .map(|(label, _)| label)
+        .filter(|label| seen.insert(label.clone()))
+        .collect())
+}
+
 pub fn each_record<F>(reader: &mut LineReader, f: F) -> Result<(), Error>
     where F: Fn(&Record)
 {
-    loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line) {
-            Err(err) => return Err(err).map_err(Error::Io),
-            Ok(0) => break, // EOF
-            Ok(_) => {
-                line.pop(); // remove '\n'
-                if line.len() == 0 {
-                    continue;
-                }
+    each_record_with(reader, &ParseOptions::default(), f)
+}
 
-                let mut record = Record::new();
-                for item in line.split('\t').collect::<Vec<&str>>().into_iter() {
-                    let v = item.splitn(2, ':').collect::<Vec<&str>>();
-                    match v.len() {
-                        0 | 1 => {
-                            return Err(ParseError { msg: format!("invalid ltsv item: {}", item) })
-                                .map_err(Error::Parse);
-                        }
-                        2 => record.insert(v[0].to_string(), v[1].to_string()),
-                        _ => {
-                            return Err(ParseError { msg: format!("unreachable error: {}", item) })
-                                .map_err(Error::Parse);
-                        }
-                    };
-                }
+pub fn each_record_with<F>(reader: &mut LineReader, opts: &ParseOptions, f: F) -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    while let Some(record) = next_record(reader, opts)? {
+        f(&record);
+    }
+    Ok(())
+}
 
-                f(&record);
-            }
+/// Like `each_record`, but an error from `f`, such as `Error::OutputClosed`
+/// when writing the record out, stops reading and is returned.
+pub fn try_each_record<F>(reader: &mut LineReader, mut f: F) -> Result<(), Error>
+    where F: FnMut(&Record) -> Result<(), Error>
+{
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        f(&record)?;
+    }
+    Ok(())
+}
+
+/// Like `each_record`, also calling `progress` with the number of records
+/// read so far and `LineReader::bytes_read` after every `every` records.
+/// `ParseOptions::progress` is the equivalent for the other passes. An
+/// `every` of 0 is an error.
+pub fn each_record_progress<F, P>(reader: &mut LineReader,
+                                  f: F,
+                                  progress: P,
+                                  every: usize)
+                                  -> Result<(), Error>
+    where F: Fn(&Record),
+          P: Fn(usize, u64)
+{
+    if every == 0 {
+        return Err(Error::InvalidArgument("every must be greater than 0".to_string()));
+    }
+    let mut n: usize = 0;
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        f(&record);
+        n += 1;
+        if n.is_multiple_of(every) {
+            progress(n, reader.bytes_read());
         }
     }
     Ok(())
 }
 
-pub fn group_by(reader: &mut LineReader, label: &String) -> Result<FieldGroupCount, Error> {
-    let mut group = FieldGroupCount::new();
-    loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line) {
-            Err(err) => return Err(err).map_err(Error::Io),
-            Ok(0) => break, // EOF
-            Ok(_) => {
-                line.pop(); // remove '\n'
-                if line.len() == 0 {
-                    continue;
-                }
+/// Invokes `f` on consecutive batches of `chunk_size` records, the last one
+/// possibly shorter. An error from `f` stops reading and is returned.
+pub fn each_chunk<F>(reader: &mut LineReader, chunk_size: usize, mut f: F) -> Result<(), Error>
+    where F: FnMut(&[Record]) -> Result<(), Error>
+{
+    if chunk_size == 0 {
+        return Err(Error::InvalidArgument("chunk size must be greater than 0".to_string()));
+    }
+    let mut chunk = Vec::with_capacity(chunk_size);
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        chunk.push(record);
+        if chunk.len() == chunk_size {
+            f(&chunk)?;
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        f(&chunk)?;
+    }
+    Ok(())
+}
 
-                for item in line.split('\t').collect::<Vec<&str>>().into_iter() {
-                    let v = item.splitn(2, ':').collect::<Vec<&str>>();
-                    match v.len() {
-                        0 | 1 => {
-                            return Err(ParseError { msg: format!("invalid ltsv item: {}", item) })
-                                .map_err(Error::Parse);
-                        }
-                        2 => {
-                            if label != &v[0] {
-                                continue;
-                            }
-                            let count = group.entry(v[1].to_string()).or_insert(0);
-                            *count += 1;
-                        }
-                        _ => {
-                            return Err(ParseError { msg: format!("unreachable error: {}", item) })
-                                .map_err(Error::Parse);
-                        }
-                    }
-                }
+/// Invokes `f` on every line of JSON Lines input, parsed as a JSON object
+/// with nested objects flattened. See `json::parse_object` for how values
+/// are converted.
+pub fn each_record_json<F>(reader: &mut LineReader, f: F) -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    each_record_json_with(reader, &JsonOptions::default(), f)
+}
+
+pub fn each_record_json_with<F>(reader: &mut LineReader,
+                               opts: &JsonOptions,
+                               f: F)
+                               -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        f(&json::parse_object(&line, opts).map_err(|err| at_line(reader, err))?);
+    }
+    Ok(())
+}
+
+/// Invokes `f` on every line of an access log in the combined format. See
+/// `combined::parse_line` for the labels.
+pub fn each_record_combined<F>(reader: &mut LineReader, f: F) -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        f(&combined::parse_line(&line).map_err(|err| at_line(reader, err))?);
+    }
+    Ok(())
+}
+
+/// Converts JSON Lines input into LTSV with labels in sorted order, so that
+/// the LTSV functions can work on it. Returns the number of records written.
+pub fn json_to_ltsv<W: Write>(reader: &mut LineReader,
+                              opts: &JsonOptions,
+                              writer: &mut W)
+                              -> Result<u64, Error> {
+    let mut written = 0;
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = json::parse_object(&line, opts).map_err(|err| at_line(reader, err))?;
+        let mut pairs = record.iter().collect::<Vec<_>>();
+        pairs.sort();
+        write_pairs(writer, pairs, &ParseOptions::default())?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Invokes `f` on every `step`-th record only (the 0th, `step`-th, `2*step`-th, ...).
+///
+/// Skipped lines are counted but not parsed, so a malformed line that falls
+/// between two sampled records is not reported. A `step` of 0 is an error.
+pub fn each_record_step<F>(reader: &mut LineReader, step: usize, f: F) -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    if step == 0 {
+        return Err(Error::InvalidArgument("step must be greater than 0".to_string()));
+    }
+    let mut n = 0;
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        if n % step == 0 {
+            f(&parse_read(reader, &line)?);
+        }
+        n += 1;
+    }
+    Ok(())
+}
+
+pub fn head_records(reader: &mut LineReader, n: usize) -> Result<Vec<Record>, Error> {
+    head_records_with(reader, n, &ParseOptions::default())
+}
+
+/// Returns the first `n` records. Reading stops as soon as `n` records have
+/// been parsed, so the rest of the input is left unread.
+pub fn head_records_with(reader: &mut LineReader,
+                         n: usize,
+                         opts: &ParseOptions)
+                         -> Result<Vec<Record>, Error> {
+    let mut records = Vec::new();
+    while records.len() < n {
+        match next_record(reader, opts)? {
+            Some(record) => records.push(record),
+            None => break,
+        }
+    }
+    Ok(records)
+}
+
+pub fn tail_records(reader: &mut LineReader, n: usize) -> Result<Vec<Record>, Error> {
+    tail_records_with(reader, n, &ParseOptions::default())
+}
+
+/// Returns the last `n` records. Only the last `n` raw lines are kept in
+/// memory; they are parsed once the end of input is reached.
+pub fn tail_records_with(reader: &mut LineReader,
+                         n: usize,
+                         opts: &ParseOptions)
+                         -> Result<Vec<Record>, Error> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut lines = VecDeque::with_capacity(n);
+    let mut line = String::new();
+    while next_line_with(reader, opts, &mut line)? {
+        if !is_well_formed(&line, opts.kv_separator) {
+            if opts.lenient {
+                continue;
             }
+            parse_record_with(&line, opts).map_err(|err| at_line(reader, err))?;
         }
+        if lines.len() == n {
+            lines.pop_front();
+        }
+        lines.push_back(line.clone());
     }
+    lines.iter().map(|line| parse_record_with(line, opts)).collect()
+}
+
+pub fn group_by(reader: &mut LineReader, label: &String) -> Result<FieldGroupCount, Error> {
+    group_by_with(reader, label, &ParseOptions::default())
+}
+
+pub fn group_by_with(reader: &mut LineReader,
+                     label: &String,
+                     opts: &ParseOptions)
+                     -> Result<FieldGroupCount, Error> {
+    group_by_detailed(reader, label, opts).map(|result| result.counts)
+}
+
+/// Like `group_by`, but keeps only the values counted at least `min` times.
+pub fn group_by_min_count(reader: &mut LineReader,
+                          label: &String,
+                          min: i32)
+                          -> Result<FieldGroupCount, Error> {
+    let mut group = group_by(reader, label)?;
+    group.retain(|_, count| *count >= min);
     Ok(group)
 }
 
-pub fn order_by(reader: &mut LineReader, label: &String) -> Result<Vec<String>, Error> {
-    let mut lines = Vec::new();
-    loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line) {
-            Err(err) => return Err(err).map_err(Error::Io),
-            Ok(0) => break, // EOF
-            Ok(_) => lines.push(line),
+/// Counts records by `key_fn` of their `label` value. Records without the
+/// label, or for which `key_fn` returns `None`, are skipped.
+pub fn group_by_map<F>(reader: &mut LineReader,
+                       label: &String,
+                       key_fn: F)
+                       -> Result<FieldGroupCount, Error>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut group = FieldGroupCount::default();
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        if let Some(key) = record.get(label).and_then(|value| key_fn(value)) {
+            *group.entry(key).or_insert(0) += 1;
         }
     }
-    lines.sort_by(|a, b| {
-        let av = match line2record(a) {
-            None => "".to_string(),
-            Some(record) => {
-                match record.get(label) {
-                    Some(v) => v.to_string(),
-                    None => "".to_string(),
-                }
-            }
-        };
-        let bv = match line2record(b) {
-            None => "".to_string(),
-            Some(record) => {
-                match record.get(label) {
-                    Some(v) => v.to_string(),
-                    None => "".to_string(),
-                }
-            }
-        };
-        av.cmp(&bv)
-    });
+    Ok(group)
+}
 
-    Ok(lines)
+/// Counts records by the class of the User-Agent in `label`, such as its
+/// browser family, using the built-in rules of `useragent`.
+pub fn group_by_ua_class(reader: &mut LineReader,
+                         label: &String,
+                         facet: UaFacet)
+                         -> Result<FieldGroupCount, Error> {
+    group_by_ua_class_with(reader, label, facet, &UaRules::default())
 }
 
-fn line2record(line: &String) -> Option<Record> {
-    let mut record = Record::new();
-    for field in line.split('\t').collect::<Vec<_>>().into_iter() {
-        let v = field.splitn(2, ':').collect::<Vec<_>>();
-        match v.len() {
-            2 => record.insert(v[0].to_string(), v[1].to_string()),
-            _ => return None,
-        };
+/// Like `group_by_ua_class`, classifying with `rules` instead.
+pub fn group_by_ua_class_with(reader: &mut LineReader,
+                              label: &String,
+                              facet: UaFacet,
+                              rules: &UaRules)
+                              -> Result<FieldGroupCount, Error> {
+    group_by_map(reader, label, |ua| Some(rules.classify(ua).facet(facet)))
+}
+
+/// Counts records by the values of all of `labels`, in that order. A missing
+/// label counts as an empty value; `composite_key` builds the same kind of
+/// key as a single string.
+pub fn group_by_multi(reader: &mut LineReader,
+                      labels: &[String])
+                      -> Result<HashMap<Vec<String>, i32>, Error> {
+    let mut group = HashMap::new();
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        let key = labels.iter()
+            .map(|label| record.get(label).cloned().unwrap_or_default())
+            .collect();
+        *group.entry(key).or_insert(0) += 1;
     }
-    Some(record)
+    Ok(group)
 }
 
-#[cfg(test)]
-mod test {
-    #[test]
-    fn test_parse_head() {}
+/// Counts records by a capture group of `pattern` in their `label` value,
+/// taking the leftmost match. `group` is the number of the group, `0` for
+/// the whole match, or its name.
+///
+/// Values that don't match, or whose group took no part in the match, are
+/// counted under `NO_MATCH` if `keep_unmatched` is set and skipped otherwise.
+/// Records without the label are skipped. An invalid pattern or group fails
+/// before any input is read.
+pub fn group_by_capture(reader: &mut LineReader,
+                        label: &String,
+                        pattern: &str,
+                        group: &str,
+                        keep_unmatched: bool)
+                        -> Result<FieldGroupCount, Error> {
+    let re = Regex::new(pattern)?;
+    let index = match group.parse::<usize>() {
+        Ok(index) if index < re.captures_len() => Some(index),
+        Ok(_) => None,
+        Err(_) => re.group_index(group),
+    }
+    .ok_or_else(|| Error::InvalidArgument(format!("no group {} in {:?}", group, pattern)))?;
+    group_by_map(reader, label, |value| {
+        match re.captures(value).and_then(|caps| caps[index]) {
+            Some(key) => Some(key.to_string()),
+            None if keep_unmatched => Some(NO_MATCH.to_string()),
+            None => None,
+        }
+    })
+}
+
+/// The group of the values `group_by_capture` finds no match in.
+pub const NO_MATCH: &str = "(no match)";
+
+/// Built-in key functions for `group_by_map`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyTransform {
+    /// The class of an HTTP status, such as `2xx` for `204`.
+    StatusClass,
+    /// A URI without its query string and fragment.
+    StripQuery,
+    /// The hour of a timestamp in UTC, `00` to `23`.
+    HourOfDay(TimeFormat),
+    /// The first `n` characters.
+    Prefix(usize),
+}
+
+impl KeyTransform {
+    /// Returns the key of `value`, or `None` if it can't be derived.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        match *self {
+            KeyTransform::StatusClass => {
+                let bytes = value.as_bytes();
+                if bytes.len() != 3 || !bytes.iter().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                Some(format!("{}xx", &value[..1]))
+            }
+            KeyTransform::StripQuery => {
+                let end = value.find(['?', '#']).unwrap_or(value.len());
+                Some(value[..end].to_string())
+            }
+            KeyTransform::HourOfDay(ref fmt) => {
+                fmt.parse(value).map(|t| format!("{:02}", t.rem_euclid(86400) / 3600))
+            }
+            KeyTransform::Prefix(n) => Some(value.chars().take(n).collect()),
+        }
+    }
+}
+
+/// The counts of `group_by_detailed` along with how many records they cover.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupResult {
+    pub counts: FieldGroupCount,
+    /// Records that don't carry the label.
+    pub missing: u64,
+    /// Records read, not counting blank lines, comments, and malformed lines
+    /// skipped in lenient mode.
+    pub total_records: u64,
+}
+
+/// Like `group_by_with`, but also reports how many records lacked `label`.
+pub fn group_by_detailed(reader: &mut LineReader,
+                         label: &String,
+                         opts: &ParseOptions)
+                         -> Result<GroupResult, Error> {
+    let mut result = GroupResult {
+        counts: FieldGroupCount::with_capacity_and_hasher(opts.hint_groups, Default::default()),
+        missing: 0,
+        total_records: 0,
+    };
+    let mut line = String::new();
+    loop {
+        match next_line_with(reader, opts, &mut line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(Error::Cancelled(_)) => return Err(Error::Cancelled(Some(result.counts))),
+            Err(err) => return Err(err),
+        }
+        if opts.lenient && !is_well_formed(&line, opts.kv_separator) {
+            continue;
+        }
+        let mut found = false;
+        let counts = &mut result.counts;
+        parse_fields(&line, opts.kv_separator, &mut |l, value| {
+                if label == l {
+                    found = true;
+                    *counts.entry(unescape_with(opts, value)).or_insert(0) += 1;
+                }
+                Ok(())
+            })
+            .map_err(|err| at_line(reader, err))?;
+        result.total_records += 1;
+        if !found {
+            result.missing += 1;
+        }
+    }
+    if opts.normalize != KeyNormalize::Exact {
+        result.counts = merge_normalized(result.counts, opts.normalize);
+    }
+    Ok(result)
+}
+
+/// Merges the groups whose values normalize to the same key. Each merged
+/// group is named after its most frequent original value, the smallest one
+/// on ties.
+fn merge_normalized(counts: FieldGroupCount, normalize: KeyNormalize) -> FieldGroupCount {
+    let mut merged: HashMap<String, (i32, String, i32)> = HashMap::new();
+    for (value, count) in counts {
+        let entry = merged.entry(normalize.apply(&value)).or_insert((0, value.clone(), count));
+        entry.0 += count;
+        if count > entry.2 || (count == entry.2 && value < entry.1) {
+            entry.1 = value;
+            entry.2 = count;
+        }
+    }
+    merged.into_iter().map(|(_, (total, name, _))| (name, total)).collect()
+}
+
+/// The sum of all counts in `group`.
+pub fn group_total(group: &FieldGroupCount) -> u64 {
+    group.values().map(|&count| count as u64).sum()
+}
+
+/// Returns the groups sorted by count descending (ties by value), each with
+/// its percentage of `group_total`. Every percentage is computed from the
+/// exact counts.
+pub fn group_with_percent(group: &FieldGroupCount) -> Vec<(String, u64, f64)> {
+    let total = group_total(group);
+    let mut rows = group.iter()
+        .map(|(value, &count)| {
+            let count = count as u64;
+            (value.clone(), count, count as f64 * 100.0 / total as f64)
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    rows
+}
+
+/// Returns the input lines sorted by the value of `label`.
+///
+/// The sort is stable: lines with equal values keep their input order. Lines
+/// without `label` sort as if its value were empty, and malformed lines go to
+/// the end, also in input order. Blank lines are handled as
+/// `ParseOptions::blank_lines` says and never returned.
+pub fn order_by(reader: &mut LineReader, label: &String) -> Result<Vec<String>, Error> {
+    order_by_with(reader, label, &ParseOptions::default())
+}
+
+pub fn order_by_with(reader: &mut LineReader,
+                     label: &String,
+                     opts: &ParseOptions)
+                     -> Result<Vec<String>, Error> {
+    order_lines_by(reader,
+                   label,
+                   opts,
+                   |value| Some(opts.normalize.apply(value.map_or("", |v| v.as_str()))))
+}
+
+/// Like `order_by`, but compares the values as `kind` says. Lines lacking the
+/// label, or whose value doesn't parse under `kind`, sort after the rest, in
+/// input order and before the malformed lines.
+pub fn order_by_kind(reader: &mut LineReader,
+                     label: &String,
+                     kind: &SortKind)
+                     -> Result<Vec<String>, Error> {
+    order_by_kind_with(reader, label, kind, &ParseOptions::default())
+}
+
+pub fn order_by_kind_with(reader: &mut LineReader,
+                          label: &String,
+                          kind: &SortKind,
+                          opts: &ParseOptions)
+                          -> Result<Vec<String>, Error> {
+    order_lines_by(reader, label, opts, |value| match kind.key(value.map(String::as_str)) {
+        SortKey::Missing => None,
+        key => Some(key),
+    })
+}
+
+/// Returns the input lines sorted by the time in `label`, parsed once per line
+/// with the strftime-style `fmt`. As with `order_by_kind`, lines lacking the
+/// label or whose time doesn't parse sort after the rest.
+pub fn order_by_time(reader: &mut LineReader,
+                     label: &String,
+                     fmt: &str)
+                     -> Result<Vec<String>, Error> {
+    order_by_time_with(reader, label, fmt, &ParseOptions::default())
+}
+
+pub fn order_by_time_with(reader: &mut LineReader,
+                          label: &String,
+                          fmt: &str,
+                          opts: &ParseOptions)
+                          -> Result<Vec<String>, Error> {
+    order_lines_by(reader,
+                   label,
+                   opts,
+                   |value| value.and_then(|v| timefmt::strptime(v, fmt)))
+}
+
+fn order_lines_by<K, F>(reader: &mut LineReader,
+                        label: &String,
+                        opts: &ParseOptions,
+                        key: F)
+                        -> Result<Vec<String>, Error>
+    where K: Ord,
+          F: Fn(Option<&String>) -> Option<K>
+{
+    let mut keyed = Vec::new();
+    let mut buffered = 0;
+    loop {
+        check_cancelled(opts)?;
+        let mut line = String::new();
+        match reader.read_line_max(&mut line, opts) {
+            Err(err) => return Err(Error::Io(err)),
+            Ok(LineRead::Eof) => {
+                report_eof(reader, opts);
+                break;
+            }
+            Ok(LineRead::TooLong(len)) => {
+                if let Some(err) = too_long(reader, opts, len) {
+                    return Err(err);
+                }
+            }
+            Ok(LineRead::InvalidUtf8(valid_len)) => {
+                if !opts.lenient {
+                    return Err(not_utf8(reader, valid_len));
+                }
+            }
+            Ok(LineRead::Line) if opts.delimiter.trim(&line).is_empty() => {
+                blank_line(reader, opts)?
+            }
+            Ok(LineRead::Line) if is_comment(opts, &line) => continue,
+            Ok(LineRead::Line) => {
+                count_record(reader, opts);
+                buffered += line.len() + LINE_OVERHEAD;
+                if opts.max_memory_bytes.is_some_and(|max| buffered > max) {
+                    return Err(Error::MemoryLimit {
+                        lines_buffered: keyed.len() as u64 + 1,
+                        bytes: buffered as u64,
+                    });
+                }
+                let slot = match parse_line_with(opts.delimiter.trim(&line), opts) {
+                    Ok(record) => key(record.get(label)).map_or(SortSlot::Missing, SortSlot::Keyed),
+                    Err(_) => SortSlot::Malformed,
+                };
+                keyed.push((slot, line));
+            }
+        }
+    }
+    Ok(sort_keyed(keyed))
+}
+
+/// Returns the records sorted by the value of `label`. Like `order_by`, the
+/// sort is stable; records without `label` go to the end.
+pub fn order_records_by(reader: &mut LineReader, label: &String) -> Result<Vec<Record>, Error> {
+    order_records_by_with(reader, label, &ParseOptions::default())
+}
+
+pub fn order_records_by_with(reader: &mut LineReader,
+                             label: &String,
+                             opts: &ParseOptions)
+                             -> Result<Vec<Record>, Error> {
+    let mut keyed = Vec::new();
+    while let Some(record) = next_record(reader, opts)? {
+        let slot = record.get(label).cloned().map_or(SortSlot::Missing, SortSlot::Keyed);
+        keyed.push((slot, record));
+    }
+    Ok(sort_keyed(keyed))
+}
+
+/// Where a line or record goes in the `order_by` family's output: first the
+/// keyed ones by key, then those lacking the label or whose value has no key
+/// of the requested kind, then malformed lines. The sort is stable, so every
+/// group but the first keeps its input order.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortSlot<K> {
+    Keyed(K),
+    Missing,
+    Malformed,
+}
+
+/// Stably sorts `items` by their slots.
+fn sort_keyed<K: Ord, T>(mut items: Vec<(SortSlot<K>, T)>) -> Vec<T> {
+    items.sort_by(|(a, _), (b, _)| a.cmp(b));
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Counts the records that carry `label` and those that don't. A label with an
+/// empty value counts as present.
+pub fn presence_count(reader: &mut LineReader, label: &String) -> Result<(u64, u64), Error> {
+    let (mut present, mut absent) = (0, 0);
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        if parse_read(reader, &line)?.contains_key(label) {
+            present += 1;
+        } else {
+            absent += 1;
+        }
+    }
+    Ok((present, absent))
+}
+
+/// Draws a uniform sample of `n` records in a single pass (reservoir
+/// sampling, Algorithm R). The same `seed` always yields the same sample;
+/// `None` seeds from the process's random state.
+pub fn sample_records(reader: &mut LineReader,
+                      n: usize,
+                      seed: Option<u64>)
+                      -> Result<Vec<Record>, Error> {
+    let mut rng = Rng::new(seed);
+    let mut reservoir = Vec::with_capacity(n);
+    let mut seen = 0u64;
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        seen += 1;
+        if reservoir.len() < n {
+            reservoir.push(record);
+        } else {
+            let j = rng.below(seen) as usize;
+            if j < n {
+                reservoir[j] = record;
+            }
+        }
+    }
+    Ok(reservoir)
+}
+
+/// Invokes `f` on each record independently with probability `p`.
+pub fn sample_fraction<F>(reader: &mut LineReader,
+                          p: f64,
+                          seed: Option<u64>,
+                          f: F)
+                          -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    if !(0.0..=1.0).contains(&p) {
+        return Err(Error::InvalidArgument(format!("sampling fraction out of range: {}", p)));
+    }
+    let mut rng = Rng::new(seed);
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        if rng.next_f64() < p {
+            f(&record);
+        }
+    }
+    Ok(())
+}
+
+/// Counts the numeric values of `label` per bucket of `bucket_width`, each
+/// value being floored to the start of its bucket. Buckets are sorted in
+/// ascending order; missing and non-numeric values are skipped.
+pub fn histogram(reader: &mut LineReader,
+                 label: &String,
+                 bucket_width: f64)
+                 -> Result<Vec<(f64, u64)>, Error> {
+    if bucket_width.is_nan() || bucket_width <= 0.0 {
+        return Err(Error::InvalidArgument(format!("bucket width must be positive: {}",
+                                                  bucket_width)));
+    }
+    let mut buckets = HashMap::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        let value = match record.get(label).and_then(|v| v.parse::<f64>().ok()) {
+            Some(v) if v.is_finite() => v,
+            _ => continue,
+        };
+        *buckets.entry((value / bucket_width).floor() as i64).or_insert(0) += 1;
+    }
+    let mut buckets = buckets.into_iter().collect::<Vec<(i64, u64)>>();
+    buckets.sort();
+    Ok(buckets.into_iter().map(|(i, count)| (i as f64 * bucket_width, count)).collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Emit only left records that have a match on the right.
+    Inner,
+    /// Emit every left record, merged with its right match if there is one.
+    LeftOuter,
+}
+
+/// Joins the records of `left` with those of `right` on the value of `key`.
+///
+/// The right side is loaded into memory first, keyed by `key`; when a key
+/// occurs more than once there, the last record wins. Right records lacking
+/// `key` are ignored. Left records are then streamed, and each match gets the
+/// right-side labels (except `key` itself) merged in under `prefix`. If a
+/// prefixed label already exists on the left record, the right-side value
+/// replaces it.
+pub fn join<'a>(left: &'a mut LineReader,
+                right: &mut LineReader,
+                key: &str,
+                kind: JoinKind,
+                prefix: &str)
+                -> impl Iterator<Item = Result<Record, Error>> + 'a {
+    let (index, mut failed) = match index_by(right, &key.to_string()) {
+        Ok(index) => (index, None),
+        Err(err) => (HashMap::new(), Some(err)),
+    };
+    let key = key.to_string();
+    let prefix = prefix.to_string();
+    let opts = ParseOptions::default();
+    let mut done = false;
+    iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        if let Some(err) = failed.take() {
+            done = true;
+            return Some(Err(err));
+        }
+        loop {
+            let mut record = match next_record(left, &opts) {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    done = true;
+                    return None;
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            };
+            match record.get(&key).and_then(|v| index.get(v)) {
+                Some(matched) => {
+                    for (label, value) in matched {
+                        if label != &key {
+                            record.insert(format!("{}{}", prefix, label), value.clone());
+                        }
+                    }
+                }
+                None if kind == JoinKind::Inner => continue,
+                None => {}
+            }
+            return Some(Ok(record));
+        }
+    })
+}
+
+/// Collects the inner join of `left` and `right` on `key`, as done by `join`
+/// without a prefix: on a label collision the right-side value wins.
+pub fn inner_join(left: &mut LineReader,
+                  right: &mut LineReader,
+                  key: &str)
+                  -> Result<Vec<Record>, Error> {
+    join(left, right, key, JoinKind::Inner, "").collect()
+}
+
+/// Collects the left outer join of `left` and `right` on `key`: every left
+/// record is emitted, merged with its right match if there is one. As with
+/// `inner_join`, the right-side value wins on a label collision.
+pub fn left_join(left: &mut LineReader,
+                 right: &mut LineReader,
+                 key: &str)
+                 -> Result<Vec<Record>, Error> {
+    join(left, right, key, JoinKind::LeftOuter, "").collect()
+}
+
+/// How label values are compared when sorting.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SortKind {
+    /// Lexicographic comparison of the raw strings.
+    String,
+    /// Comparison of the values parsed as `f64`.
+    Numeric,
+    /// Chronological comparison of the values parsed as timestamps.
+    Time(TimeFormat),
+    /// Natural comparison as done by `natural_cmp`, so that `img2.png` comes
+    /// before `img10.png`.
+    Natural,
+}
+
+/// Compares `a` and `b` in natural order: runs of ASCII digits compare by
+/// their numeric value, everything else character by character.
+///
+/// Numbers that differ only in leading zeros (`007` and `7`) compare equal,
+/// so a stable sort keeps such values in input order.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (ca, cb) = match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) => (ca, cb),
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let (na, rest_a) = split_digits(a);
+            let (nb, rest_b) = split_digits(b);
+            let (na, nb) = (na.trim_start_matches('0'), nb.trim_start_matches('0'));
+            match na.len().cmp(&nb.len()).then_with(|| na.cmp(nb)) {
+                Ordering::Equal => {}
+                ord => return ord,
+            }
+            a = rest_a;
+            b = rest_b;
+        } else {
+            match ca.cmp(&cb) {
+                Ordering::Equal => {}
+                ord => return ord,
+            }
+            a = &a[ca.len_utf8()..];
+            b = &b[cb.len_utf8()..];
+        }
+    }
+}
+
+fn split_digits(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// A sort key decorated from a label value. Missing values, and values that
+/// don't parse under the requested `SortKind`, compare lower than any other.
+#[derive(Clone, Debug)]
+enum SortKey {
+    Missing,
+    Str(String),
+    Num(f64),
+    Time(i64),
+    Natural(String),
+}
+
+impl SortKind {
+    fn key(&self, value: Option<&str>) -> SortKey {
+        let value = match value {
+            Some(value) => value,
+            None => return SortKey::Missing,
+        };
+        let key = match *self {
+            SortKind::String => Some(SortKey::Str(value.to_string())),
+            SortKind::Numeric => value.trim().parse::<f64>().ok().map(SortKey::Num),
+            SortKind::Time(ref fmt) => fmt.parse(value).map(SortKey::Time),
+            SortKind::Natural => Some(SortKey::Natural(value.to_string())),
+        };
+        key.unwrap_or(SortKey::Missing)
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Str(a), SortKey::Str(b)) => a.cmp(b),
+            (SortKey::Num(a), SortKey::Num(b)) => a.total_cmp(b),
+            (SortKey::Time(a), SortKey::Time(b)) => a.cmp(b),
+            (SortKey::Natural(a), SortKey::Natural(b)) => natural_cmp(a, b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &SortKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+impl SortKey {
+    fn rank(&self) -> u8 {
+        match *self {
+            SortKey::Missing => 0,
+            SortKey::Str(_) => 1,
+            SortKey::Num(_) => 2,
+            SortKey::Time(_) => 3,
+            SortKey::Natural(_) => 4,
+        }
+    }
+}
+
+/// Merges several inputs that are each already sorted by `label` into one
+/// sorted stream of lines (without their trailing newline).
+///
+/// Lines lacking the label, or whose value doesn't parse under `kind`, are
+/// treated as the smallest key, so they are emitted as soon as they reach the
+/// front of their input. Lines with equal keys come out in input order, the
+/// earlier reader first.
+pub fn merge_sorted(readers: Vec<LineReader>,
+                    label: &str,
+                    kind: SortKind)
+                    -> impl Iterator<Item = Result<String, Error>> {
+    let label = label.to_string();
+    let mut readers = readers;
+    let mut heap = BinaryHeap::new();
+    let mut pending = None;
+    for (i, reader) in readers.iter_mut().enumerate() {
+        match merge_next(reader, &label, &kind) {
+            Ok(Some((key, line))) => heap.push(Reverse((key, i, line))),
+            Ok(None) => {}
+            Err(err) => {
+                pending = Some(err);
+                break;
+            }
+        }
+    }
+    iter::from_fn(move || {
+        if let Some(err) = pending.take() {
+            heap.clear();
+            return Some(Err(err));
+        }
+        let Reverse((_, i, line)) = heap.pop()?;
+        match merge_next(&mut readers[i], &label, &kind) {
+            Ok(Some((key, next))) => heap.push(Reverse((key, i, next))),
+            Ok(None) => {}
+            Err(err) => pending = Some(err),
+        }
+        Some(Ok(line))
+    })
+}
+
+fn merge_next(reader: &mut LineReader,
+              label: &str,
+              kind: &SortKind)
+              -> Result<Option<(SortKey, String)>, Error> {
+    let mut line = String::new();
+    if !next_line(reader, &mut line)? {
+        return Ok(None);
+    }
+    let key = kind.key(parse_read(reader, &line)?.get(label).map(|v| v.as_str()));
+    Ok(Some((key, line)))
+}
+
+/// Returns a copy of `record` with every label found in `mapping` renamed to
+/// its mapped name; other labels pass through unchanged.
+///
+/// When several labels end up with the same name, the last one wins: labels
+/// that pass through unchanged are written first, then the renamed ones in
+/// the sorted order of their original names.
+pub fn rename(record: &Record, mapping: &HashMap<String, String>) -> Record {
+    let mut renamed = Record::default();
+    let mut sources = Vec::new();
+    for (label, value) in record {
+        match mapping.get(label) {
+            Some(target) => sources.push((label, target, value)),
+            None => {
+                renamed.insert(label.clone(), value.clone());
+            }
+        }
+    }
+    sources.sort();
+    for (_, target, value) in sources {
+        renamed.insert(target.clone(), value.clone());
+    }
+    renamed
+}
+
+/// Returns every label found in the input with the number of records it
+/// appears in, sorted by label.
+pub fn list_labels(reader: &mut LineReader) -> Result<Vec<(String, u64)>, Error> {
+    list_labels_limit(reader, None)
+}
+
+/// Like `list_labels`, but stops after `limit` records when given.
+pub fn list_labels_limit(reader: &mut LineReader,
+                         limit: Option<usize>)
+                         -> Result<Vec<(String, u64)>, Error> {
+    let mut counts = HashMap::new();
+    let mut n = 0;
+    let mut line = String::new();
+    while limit.is_none_or(|limit| n < limit) && next_line(reader, &mut line)? {
+        for label in parse_read(reader, &line)?.into_keys() {
+            *counts.entry(label).or_insert(0) += 1;
+        }
+        n += 1;
+    }
+    let mut labels = counts.into_iter().collect::<Vec<(String, u64)>>();
+    labels.sort();
+    Ok(labels)
+}
+
+/// Returns a copy of `record` with each label of `defaults` that the record
+/// lacks inserted with its default value. Existing values are kept.
+pub fn with_defaults(record: &Record, defaults: &HashMap<String, String>) -> Record {
+    let mut filled = record.clone();
+    fill_missing(&mut filled, defaults);
+    filled
+}
+
+/// Like `with_defaults`, but fills in `record` itself. Meant for
+/// `map_records`, where it can be combined with other changes to the record.
+pub fn fill_missing(record: &mut Record, defaults: &HashMap<String, String>) {
+    for (label, value) in defaults {
+        if !record.contains_key(label) {
+            record.insert(label.clone(), value.clone());
+        }
+    }
+}
+
+/// What `rename_labels` does when a renamed label clashes with another label
+/// of the same record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameCollision {
+    /// Fail with a parse error naming the label.
+    Error,
+    /// Keep the renamed label and drop the one it collides with.
+    PreferRenamed,
+}
+
+/// Copies `reader` to `writer` as LTSV, renaming labels per `mapping`, and
+/// returns the number of records written.
+///
+/// All renames are applied at once rather than chained, so `a -> b` and
+/// `b -> c` turn `a:1 b:2` into `b:1 c:2`. Fields keep their position in the
+/// line. Records without any of the source labels are passed through.
+pub fn rename_labels<W: Write>(reader: &mut LineReader,
+                               mapping: &HashMap<String, String>,
+                               collision: RenameCollision,
+                               writer: &mut W)
+                               -> Result<u64, Error> {
+    let (opts, mut n) = (ParseOptions::default(), 0);
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        // A collision can replace the value of an earlier field, so the
+        // fields are held until the line is done, borrowed from it.
+        let mut fields: Vec<(&str, &str, bool)> = Vec::new();
+        parse_fields(&line, ':', &mut |label, value| {
+                let (label, renamed) = match mapping.get(label) {
+                    Some(target) => (target.as_str(), true),
+                    None => (label, false),
+                };
+                match fields.iter().position(|&(l, _, _)| l == label) {
+                    None => fields.push((label, value, renamed)),
+                    Some(_) if collision == RenameCollision::Error => {
+                        let msg = format!("label collision: {}", label);
+                        return Err(Error::Parse(ParseError::new(msg).with_field(label)));
+                    }
+                    Some(i) => {
+                        if renamed || !fields[i].2 {
+                            fields[i] = (label, value, renamed);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|err| at_line(reader, err))?;
+        let mut out = String::with_capacity(line.len());
+        for (label, value, _) in fields {
+            push_pair(&mut out, label, value, &opts)?;
+        }
+        write_line(writer, out, &opts)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Copies `reader` to `writer` as LTSV after letting `f` modify each record,
+/// and returns the number of records written.
+///
+/// Labels that survive keep their original position; labels added by `f` are
+/// appended in sorted order.
+pub fn map_records<F, W>(reader: &mut LineReader, f: F, writer: &mut W) -> Result<u64, Error>
+    where F: FnMut(&mut Record),
+          W: Write
+{
+    map_records_with(reader, &ParseOptions::default(), f, writer)
+}
+
+/// Like `map_records`, reading and writing records as `opts` says.
+pub fn map_records_with<F, W>(reader: &mut LineReader,
+                              opts: &ParseOptions,
+                              mut f: F,
+                              writer: &mut W)
+                              -> Result<u64, Error>
+    where F: FnMut(&mut Record),
+          W: Write
+{
+    let mut n = 0;
+    let mut line = String::new();
+    while next_line_with(reader, opts, &mut line)? {
+        let pairs = parse_pairs(&line, opts.kv_separator).map_err(|err| at_line(reader, err))?;
+        let mut record = pairs.iter()
+            .map(|(label, value)| (label.clone(), unescape_with(opts, value)))
+            .collect::<Record>();
+        f(&mut record);
+        write_record_ordered(writer, &record, pairs.iter().map(|(label, _)| label), opts)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Formats `record` as an LTSV line (without a trailing newline), labels in
+/// sorted order. Values containing tabs or newlines are refused unless
+/// `opts.escape` is set, in which case they are escaped.
+pub fn serialize_record(record: &Record, opts: &ParseOptions) -> Result<String, Error> {
+    let mut pairs = record.iter().collect::<Vec<_>>();
+    pairs.sort();
+    format_pairs(pairs, opts)
+}
+
+/// Adds `new_label` to each record, with the value computed by `deriver`.
+/// Records for which `deriver` returns `None` are written unchanged.
+pub fn add_derived<F, W>(reader: &mut LineReader,
+                         new_label: &str,
+                         deriver: F,
+                         writer: &mut W)
+                         -> Result<u64, Error>
+    where F: Fn(&Record) -> Option<String>,
+          W: Write
+{
+    map_records(reader,
+                |record| if let Some(value) = deriver(record) {
+                    record.insert(new_label.to_string(), value);
+                },
+                writer)
+}
+
+/// What `fill_defaults` did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FillStats {
+    /// Records written.
+    pub total: u64,
+    /// Number of records each default was inserted into, for the defaults
+    /// used at least once.
+    pub patched: BTreeMap<String, u64>,
+}
+
+/// Copies `reader` to `writer` as LTSV with the labels in `defaults` added to
+/// the records lacking them, as `fill_missing` does. Inserted labels follow
+/// the original ones in sorted order.
+pub fn fill_defaults<W: Write>(reader: &mut LineReader,
+                               defaults: &HashMap<String, String>,
+                               writer: &mut W)
+                               -> Result<FillStats, Error> {
+    let mut patched = BTreeMap::new();
+    let total = map_records(reader,
+                            |record| {
+                                for label in defaults.keys() {
+                                    if !record.contains_key(label) {
+                                        *patched.entry(label.clone()).or_insert(0) += 1;
+                                    }
+                                }
+                                fill_missing(record, defaults);
+                            },
+                            writer)?;
+    Ok(FillStats { total, patched })
+}
+
+/// The problems found by `lint`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LintReport {
+    /// Number of lines read, blank and malformed ones included.
+    pub total: u64,
+    /// Number of blank lines.
+    pub blank: u64,
+    /// Malformed lines, as 1-based line numbers with the parse error message.
+    pub errors: Vec<(usize, String)>,
+    /// Number of labels repeated within their record, over all records.
+    pub duplicate_labels: u64,
+}
+
+/// Checks every line of the input and collects parse problems into a report
+/// instead of stopping at the first one. I/O errors still abort.
+pub fn lint(reader: &mut LineReader) -> Result<LintReport, Error> {
+    let mut report = LintReport::default();
+    loop {
+        let mut line = String::new();
+        match reader.read_line_max(&mut line, &ParseOptions::default()) {
+            Err(err) => return Err(Error::Io(err)),
+            Ok(LineRead::Eof) => break,
+            Ok(LineRead::TooLong(len)) => {
+                report.total += 1;
+                let msg = format!("line too long: {} bytes", len);
+                report.errors.push((report.total as usize, msg));
+            }
+            Ok(LineRead::InvalidUtf8(valid_len)) => {
+                report.total += 1;
+                let msg = format!("invalid UTF-8 after {} bytes", valid_len);
+                report.errors.push((report.total as usize, msg));
+            }
+            Ok(LineRead::Line) => {
+                report.total += 1;
+                let line = trim_newline(&line);
+                if line.is_empty() {
+                    report.blank += 1;
+                    continue;
+                }
+                match parse_pairs(line, ':') {
+                    Ok(pairs) => {
+                        let labels = pairs.iter().map(|(label, _)| label).collect::<HashSet<_>>();
+                        report.duplicate_labels += (pairs.len() - labels.len()) as u64;
+                    }
+                    Err(err) => report.errors.push((report.total as usize, err.to_string())),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Converts the input to TSV rows: a header row of `columns`, then one row
+/// per record with the values in column order and missing values left empty.
+/// Tabs within a value are replaced by spaces so the columns stay aligned.
+pub fn to_tsv(reader: &mut LineReader, columns: &[String]) -> Result<Vec<String>, Error> {
+    let mut rows = vec![columns.join("\t")];
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        let row = columns.iter()
+            .map(|column| record.get(column).map_or(String::new(), |v| v.replace('\t', " ")))
+            .collect::<Vec<String>>()
+            .join("\t");
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Copies `reader` to `writer` as LTSV without the given labels, and returns
+/// the number of records written. The other fields keep their order.
+pub fn drop_labels<W: Write>(reader: &mut LineReader,
+                             labels: &[String],
+                             writer: &mut W)
+                             -> Result<u64, Error> {
+    let (opts, mut n) = (ParseOptions::default(), 0);
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let mut out = String::with_capacity(line.len());
+        parse_fields(&line, ':', &mut |label, value| {
+                if labels.iter().any(|l| l == label) {
+                    return Ok(());
+                }
+                push_pair(&mut out, label, value, &opts)
+            })
+            .map_err(|err| at_line(reader, err))?;
+        write_line(writer, out, &opts)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// How `mask_labels` replaces a value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mask {
+    /// Replace every value with the given string, e.g. `***`.
+    Replace(String),
+    /// Replace each value with its 64-bit FNV-1a hash in hex. Equal values
+    /// give equal hashes across runs, so masked labels can still be joined
+    /// or grouped on. The hash is unsalted: low-entropy values such as IPv4
+    /// addresses can be recovered by brute force.
+    Hash,
+}
+
+/// Copies `reader` to `writer` as LTSV with the values of the given labels
+/// masked, and returns the number of records written.
+pub fn mask_labels<W: Write>(reader: &mut LineReader,
+                             labels: &[String],
+                             mask: &Mask,
+                             writer: &mut W)
+                             -> Result<u64, Error> {
+    let (opts, mut n) = (ParseOptions::default(), 0);
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let mut out = String::with_capacity(line.len());
+        parse_fields(&line, ':', &mut |label, value| {
+                if !labels.iter().any(|l| l == label) {
+                    return push_pair(&mut out, label, value, &opts);
+                }
+                match *mask {
+                    Mask::Replace(ref replacement) => {
+                        push_pair(&mut out, label, replacement, &opts)
+                    }
+                    Mask::Hash => {
+                        let hash = format!("{:016x}", fnv1a(value.as_bytes()));
+                        push_pair(&mut out, label, &hash, &opts)
+                    }
+                }
+            })
+            .map_err(|err| at_line(reader, err))?;
+        write_line(writer, out, &opts)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Converts the input to CSV rows: a header row of `columns`, then one row
+/// per record. Missing values are left empty and fields are quoted as
+/// RFC 4180 requires.
+/// A row is one element even when a quoted field holds a line break.
+pub fn to_csv(reader: &mut LineReader, columns: &[String]) -> Result<Vec<String>, Error> {
+    let mut rows = vec![csv_row(columns.iter().map(|c| c.as_str()))];
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        let fields = columns.iter().map(|column| record.get(column).map_or("", |v| v.as_str()));
+        rows.push(csv_row(fields));
+    }
+    Ok(rows)
+}
+
+/// Streams the CSV form of the input (see `to_csv`) to `out`, one row at a
+/// time, so the whole result is never held in memory.
+pub fn write_csv<W: Write>(reader: &mut LineReader,
+                           columns: &[String],
+                           out: &mut W)
+                           -> Result<(), Error> {
+    write_csv_row(out, columns.iter().map(|c| c.as_str()))?;
+    write_csv_records(reader, columns, out)
+}
+
+fn write_csv_records<W: Write>(reader: &mut LineReader,
+                               columns: &[String],
+                               out: &mut W)
+                               -> Result<(), Error> {
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        write_csv_row(out,
+                      columns.iter().map(|column| record.get(column).map_or("", |v| v.as_str())))?;
+    }
+    Ok(())
+}
+
+/// Converts files to CSV with a fixed column list, formatting the header
+/// once so that one converter can be reused across a batch of files.
+pub struct CsvConverter {
+    columns: Vec<String>,
+    header: Vec<u8>,
+}
+
+impl CsvConverter {
+    pub fn new(columns: &[String]) -> CsvConverter {
+        let mut header = Vec::new();
+        write_csv_row(&mut header, columns.iter().map(|c| c.as_str()))
+            .expect("writing to a Vec can't fail");
+        CsvConverter {
+            columns: columns.to_vec(),
+            header,
+        }
+    }
+
+    /// Writes the CSV form of the file `name` (`-` for stdin) to `out`, as
+    /// `write_csv` does.
+    pub fn write_file<W: Write>(&self, name: &str, out: &mut W) -> Result<(), Error> {
+        let mut reader = open_file(name)?;
+        out.write_all(&self.header)?;
+        write_csv_records(&mut reader, &self.columns, out)
+    }
+}
+
+/// The checks `validate` applies on top of the LTSV syntax rules.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationRules {
+    /// Labels every record must carry.
+    pub required: Vec<String>,
+    /// How many offending lines to keep as examples per category.
+    pub max_examples: usize,
+}
+
+/// The lines violating one validation rule.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Violations {
+    pub count: u64,
+    /// The first offending lines, as 1-based line numbers with the raw text.
+    pub examples: Vec<(usize, String)>,
+}
+
+impl Violations {
+    fn add(&mut self, line_no: usize, line: &str, max_examples: usize) {
+        self.count += 1;
+        if self.examples.len() < max_examples {
+            self.examples.push((line_no, line.to_string()));
+        }
+    }
+}
+
+/// The result of `validate`. Each category counts offending lines, so a line
+/// with several problems appears in several categories.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    pub lines: u64,
+    /// Fields without a `:` separator.
+    pub missing_separator: Violations,
+    /// Fields with an empty label.
+    pub empty_label: Violations,
+    /// Labels with characters outside `[0-9A-Za-z_.-]`.
+    pub invalid_label: Violations,
+    /// Labels occurring more than once in a line.
+    pub duplicate_label: Violations,
+    /// Values containing control characters.
+    pub control_character: Violations,
+    /// Records lacking one of the required labels.
+    pub missing_required: Violations,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        [&self.missing_separator,
+         &self.empty_label,
+         &self.invalid_label,
+         &self.duplicate_label,
+         &self.control_character,
+         &self.missing_required]
+            .iter()
+            .all(|v| v.count == 0)
+    }
+}
+
+/// Checks every line against the LTSV specification and `rules`, and reports
+/// all problems found. Whether a problem is fatal is left to the caller.
+pub fn validate(reader: &mut LineReader,
+                rules: &ValidationRules)
+                -> Result<ValidationReport, Error> {
+    let mut report = ValidationReport::default();
+    let k = rules.max_examples;
+    loop {
+        let mut line = String::new();
+        match reader.read_line_max(&mut line, &ParseOptions::default()) {
+            Err(err) => return Err(Error::Io(err)),
+            Ok(LineRead::Eof) => break,
+            Ok(LineRead::TooLong(len)) => {
+                return Err(too_long_error(reader, &ParseOptions::default(), len));
+            }
+            Ok(LineRead::InvalidUtf8(valid_len)) => return Err(not_utf8(reader, valid_len)),
+            Ok(LineRead::Line) => {
+                report.lines += 1;
+                let n = report.lines as usize;
+                let line = trim_newline(&line);
+                if line.is_empty() {
+                    continue;
+                }
+                let (mut no_sep, mut empty, mut invalid, mut dup, mut ctrl) =
+                    (false, false, false, false, false);
+                let mut labels = HashSet::new();
+                for item in line.split('\t') {
+                    let (label, value) = match item.find(':') {
+                        Some(i) => (&item[..i], &item[i + 1..]),
+                        None => {
+                            no_sep = true;
+                            continue;
+                        }
+                    };
+                    empty |= label.is_empty();
+                    invalid |= !label.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+                    dup |= !labels.insert(label);
+                    ctrl |= value.chars().any(|c| c.is_control());
+                }
+                let flags = [(no_sep, &mut report.missing_separator),
+                             (empty, &mut report.empty_label),
+                             (invalid, &mut report.invalid_label),
+                             (dup, &mut report.duplicate_label),
+                             (ctrl, &mut report.control_character)];
+                for (flagged, violations) in flags {
+                    if flagged {
+                        violations.add(n, line, k);
+                    }
+                }
+                if rules.required.iter().any(|label| !labels.contains(label.as_str())) {
+                    report.missing_required.add(n, line, k);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// How `check_schema` treats records lacking a required label.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchemaMode {
+    /// Fail with a parse error naming the line and the label.
+    RequireAll,
+    /// Count them in `SchemaReport::missing`.
+    WarnMissing,
+}
+
+/// The result of `check_schema`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SchemaReport {
+    /// The labels checked for.
+    pub required: Vec<String>,
+    pub records: u64,
+    /// The records lacking each required label, for those lacking in any.
+    pub missing: BTreeMap<String, Violations>,
+    /// The records carrying each label outside `required`.
+    pub unexpected: BTreeMap<String, Violations>,
+}
+
+impl SchemaReport {
+    /// Whether every record had exactly the required labels.
+    pub fn conforms(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// The offending lines `SchemaReport` keeps as examples per label.
+pub const SCHEMA_EXAMPLES: usize = 5;
+
+/// Checks that every record carries all of `required`, as `mode` says, and
+/// reports the labels found beyond them. Line numbers count blank lines.
+pub fn check_schema(reader: &mut LineReader,
+                    required: &[String],
+                    mode: SchemaMode)
+                    -> Result<SchemaReport, Error> {
+    schema_check(reader, Some(required), mode)
+}
+
+/// Like `check_schema`, requiring the labels of the first record.
+pub fn schema_from_head(reader: &mut LineReader,
+                        mode: SchemaMode)
+                        -> Result<SchemaReport, Error> {
+    schema_check(reader, None, mode)
+}
+
+fn schema_check(reader: &mut LineReader,
+                required: Option<&[String]>,
+                mode: SchemaMode)
+                -> Result<SchemaReport, Error> {
+    let mut report = SchemaReport::default();
+    let mut schema = required.map(|labels| labels.iter().cloned().collect::<HashSet<String>>());
+    if let Some(labels) = required {
+        report.required = labels.to_vec();
+    }
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let pairs = parse_pairs(&line, ':').map_err(|err| at_line(reader, err))?;
+        let n = reader.line_number as usize;
+        report.records += 1;
+        let required = schema.get_or_insert_with(|| {
+            let mut labels = HashSet::new();
+            for (label, _) in &pairs {
+                if labels.insert(label.clone()) {
+                    report.required.push(label.clone());
+                }
+            }
+            labels
+        });
+        let labels = pairs.iter().map(|(label, _)| label.as_str()).collect::<HashSet<&str>>();
+        for label in &report.required {
+            if labels.contains(label.as_str()) {
+                continue;
+            }
+            if mode == SchemaMode::RequireAll {
+                let msg = format!("line {}: missing label {}", n, label);
+                return Err(Error::Parse(line_error(reader, msg).with_field(label.as_str())));
+            }
+            report.missing.entry(label.clone()).or_default().add(n, &line, SCHEMA_EXAMPLES);
+        }
+        let mut extra = labels.into_iter().filter(|l| !required.contains(*l)).collect::<Vec<_>>();
+        extra.sort();
+        for label in extra {
+            report.unexpected.entry(label.to_string()).or_default().add(n, &line, SCHEMA_EXAMPLES);
+        }
+    }
+    Ok(report)
+}
+
+/// Returns each distinct line once, in the order first seen. Blank lines are
+/// skipped. Every distinct line is kept in memory.
+pub fn dedup(reader: &mut LineReader) -> Result<Vec<String>, Error> {
+    let mut seen = HashSet::new();
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        if seen.insert(line.clone()) {
+            lines.push(line.clone());
+        }
+    }
+    Ok(lines)
+}
+
+/// Collapses runs of identical consecutive lines, like `uniq -c`, returning
+/// each run's line with its length in input order. Blank lines are skipped.
+pub fn uniq_consecutive(reader: &mut LineReader) -> Result<Vec<(String, u64)>, Error> {
+    let mut runs: Vec<(String, u64)> = Vec::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        match runs.last_mut() {
+            Some(&mut (ref last, ref mut count)) if *last == line => *count += 1,
+            _ => runs.push((line.clone(), 1)),
+        }
+    }
+    Ok(runs)
+}
+
+/// Counts the distinct values of `label`, skipping records without it.
+///
+/// Every distinct value is kept in memory; `cardinality_approx` estimates
+/// the count in fixed memory instead.
+pub fn cardinality(reader: &mut LineReader, label: &String) -> Result<usize, Error> {
+    cardinality_with(reader, label, &ParseOptions::default())
+}
+
+pub fn cardinality_with(reader: &mut LineReader,
+                        label: &String,
+                        opts: &ParseOptions)
+                        -> Result<usize, Error> {
+    let mut seen = HashSet::new();
+    while let Some(mut record) = next_record(reader, opts)? {
+        if let Some(value) = record.remove(label) {
+            seen.insert(value);
+        }
+    }
+    Ok(seen.len())
+}
+
+/// The approximate counterpart of `cardinality`, an alias of
+/// `distinct_count_approx`.
+pub fn cardinality_approx(reader: &mut LineReader,
+                          label: &String,
+                          precision: u8)
+                          -> Result<u64, Error> {
+    distinct_count_approx(reader, label, precision)
+}
+
+/// Estimates the number of distinct values of `label` with a HyperLogLog
+/// sketch of the given `precision`, in fixed memory. See `HyperLogLog` for
+/// the expected error.
+pub fn distinct_count_approx(reader: &mut LineReader,
+                             label: &String,
+                             precision: u8)
+                             -> Result<u64, Error> {
+    distinct_sketch(reader, label, precision).map(|hll| hll.estimate())
+}
+
+/// Builds the HyperLogLog sketch `distinct_count_approx` estimates from, so
+/// that sketches of several inputs can be merged.
+pub fn distinct_sketch(reader: &mut LineReader,
+                       label: &String,
+                       precision: u8)
+                       -> Result<HyperLogLog, Error> {
+    let mut hll = HyperLogLog::new(precision)?;
+    while let Some(record) = next_record(reader, &ParseOptions::default())? {
+        if let Some(value) = record.get(label) {
+            hll.insert(value);
+        }
+    }
+    Ok(hll)
+}
+
+/// A SpaceSaving summary tracking the most frequent values of a stream in at
+/// most `capacity` entries.
+///
+/// After `n` insertions, the count of a tracked value overestimates its true
+/// count by at most `n / capacity`, and every value occurring more than
+/// `n / capacity` times is tracked.
+#[derive(Clone, Debug)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+    by_count: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Result<SpaceSaving, Error> {
+        if capacity == 0 {
+            return Err(Error::InvalidArgument("capacity must be greater than 0".to_string()));
+        }
+        Ok(SpaceSaving {
+            capacity,
+            counts: HashMap::new(),
+            by_count: BTreeSet::new(),
+        })
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let count = match self.counts.get(value) {
+            Some(&count) => {
+                self.by_count.remove(&(count, value.to_string()));
+                count
+            }
+            None if self.counts.len() < self.capacity => 0,
+            None => {
+                // Evict the least frequent entry; its count bounds the
+                // newcomer's true count from above.
+                let (min, evicted) = self.by_count.pop_first().unwrap();
+                self.counts.remove(&evicted);
+                min
+            }
+        };
+        self.counts.insert(value.to_string(), count + 1);
+        self.by_count.insert((count + 1, value.to_string()));
+    }
+
+    /// The number of values tracked, never more than the capacity.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// The `k` values with the highest estimated counts, most frequent first
+    /// and ties by value.
+    pub fn top(&self, k: usize) -> Vec<(String, u64)> {
+        let mut top = self.counts.iter().map(|(v, &c)| (v.clone(), c)).collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(k);
+        top
+    }
+}
+
+/// Returns the approximately `k` most frequent values of `label`, tracking
+/// at most `capacity` candidates. See `SpaceSaving` for the error bounds;
+/// `group_by` gives exact counts when memory allows.
+pub fn heavy_hitters(reader: &mut LineReader,
+                     label: &String,
+                     k: usize,
+                     capacity: usize)
+                     -> Result<Vec<(String, u64)>, Error> {
+    heavy_hitters_with(reader, label, k, capacity, &ParseOptions::default())
+}
+
+pub fn heavy_hitters_with(reader: &mut LineReader,
+                          label: &String,
+                          k: usize,
+                          capacity: usize,
+                          opts: &ParseOptions)
+                          -> Result<Vec<(String, u64)>, Error> {
+    let mut summary = SpaceSaving::new(capacity)?;
+    while let Some(record) = next_record(reader, opts)? {
+        if let Some(value) = record.get(label) {
+            summary.insert(value);
+        }
+    }
+    Ok(summary.top(k))
+}
+
+/// An output file, gzip-compressed or not.
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl OutputFile {
+    /// Creates the file at `path`, overwriting it, and compresses what's
+    /// written when `gzip` is set or the name ends in `.gz`.
+    pub fn create<P: AsRef<Path>>(path: P, gzip: bool) -> Result<OutputFile, Error> {
+        let path = path.as_ref();
+        Ok(OutputFile::wrap(File::create(path)?, gzip || is_gz(path)))
+    }
+
+    /// Opens the file at `path` for appending, compressing as `create` does.
+    /// Compressed output is appended as another gzip member, which
+    /// decompresses as the continuation of the earlier ones.
+    pub fn append<P: AsRef<Path>>(path: P, gzip: bool) -> Result<OutputFile, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(OutputFile::wrap(file, gzip || is_gz(path)))
+    }
+
+    fn wrap(file: File, gzip: bool) -> OutputFile {
+        if gzip {
+            OutputFile::Gzip(GzEncoder::new(BufWriter::new(file)))
+        } else {
+            OutputFile::Plain(BufWriter::new(file))
+        }
+    }
+
+    /// Completes the gzip stream, if any, and flushes. Dropping the file does
+    /// the same but ignores errors, so call this to hear about a full disk.
+    /// See `GzEncoder` for what a failed write leaves behind.
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            OutputFile::Plain(mut w) => w.flush()?,
+            OutputFile::Gzip(w) => {
+                w.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            OutputFile::Plain(ref mut w) => w.write(buf),
+            OutputFile::Gzip(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            OutputFile::Plain(ref mut w) => w.flush(),
+            OutputFile::Gzip(ref mut w) => w.flush(),
+        }
+    }
+}
+
+fn is_gz(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Options for `split_by`.
+#[derive(Clone, Debug)]
+pub struct SplitOptions {
+    /// The most output files kept open at once. Beyond it the least recently
+    /// used one is closed, and reopened for appending when needed again.
+    pub max_open: usize,
+    /// The file receiving the records without the label.
+    pub missing_file: String,
+    /// Compress the output files with gzip, adding `.gz` to their names.
+    /// Reopening a file starts another gzip member, so compression suffers
+    /// when `max_open` is much below the number of values.
+    pub gzip: bool,
+}
+
+impl Default for SplitOptions {
+    fn default() -> SplitOptions {
+        SplitOptions {
+            max_open: 64,
+            missing_file: "_missing.ltsv".to_string(),
+            gzip: false,
+        }
+    }
+}
+
+/// What `split_by` wrote.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SplitStats {
+    /// The number of records written to each output file.
+    pub records: BTreeMap<PathBuf, u64>,
+}
+
+impl SplitStats {
+    /// The files written, in sorted order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.records.keys().cloned().collect()
+    }
+}
+
+/// Copies each record into a file in `dir` named after its value of `label`,
+/// such as `a.example.com.ltsv`, or `a.example.com.ltsv.gz` when compressing.
+/// Existing files are overwritten.
+///
+/// Values are made safe as file names by percent-encoding every byte other
+/// than ASCII letters, digits, `-` and `.`, and a leading `.`; so `_` is
+/// encoded too and no value maps to the missing file's default name. The
+/// empty value maps to `%.ltsv`.
+pub fn split_by(reader: &mut LineReader,
+                label: &String,
+                dir: &Path,
+                opts: &SplitOptions)
+                -> Result<SplitStats, Error> {
+    if opts.max_open == 0 {
+        return Err(Error::InvalidArgument("max_open must be greater than 0".to_string()));
+    }
+    let mut stats = SplitStats::default();
+    // Open writers with the tick they were last used at.
+    let mut open: HashMap<PathBuf, (OutputFile, u64)> = HashMap::new();
+    let mut tick = 0;
+    let suffix = if opts.gzip { ".gz" } else { "" };
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let path = match parse_read(reader, &line)?.get(label) {
+            Some(value) => dir.join(format!("{}.ltsv{}", file_name_for(value), suffix)),
+            None if is_gz(Path::new(&opts.missing_file)) => dir.join(&opts.missing_file),
+            None => dir.join(format!("{}{}", opts.missing_file, suffix)),
+        };
+        tick += 1;
+        if !open.contains_key(&path) {
+            if open.len() >= opts.max_open {
+                let lru = open.iter().min_by_key(|(_, (_, used))| *used).map(|(p, _)| p.clone());
+                if let Some((w, _)) = lru.and_then(|p| open.remove(&p)) {
+                    w.finish()?;
+                }
+            }
+            let file = if stats.records.contains_key(&path) {
+                OutputFile::append(&path, opts.gzip)?
+            } else {
+                OutputFile::create(&path, opts.gzip)?
+            };
+            open.insert(path.clone(), (file, tick));
+        }
+        let entry = open.get_mut(&path).unwrap();
+        entry.1 = tick;
+        writeln!(entry.0, "{}", line)?;
+        *stats.records.entry(path).or_insert(0) += 1;
+    }
+    for (_, (w, _)) in open {
+        w.finish()?;
+    }
+    Ok(stats)
+}
+
+fn file_name_for(value: &str) -> String {
+    if value.is_empty() {
+        return "%".to_string();
+    }
+    let mut name = String::new();
+    for (i, b) in value.bytes().enumerate() {
+        if b.is_ascii_alphanumeric() || b == b'-' || (b == b'.' && i > 0) {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name
+}
+
+/// Which of several records sharing a key `dedup_by` keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keep {
+    First,
+    Last,
+}
+
+/// What `dedup_by` did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DedupStats {
+    /// Records read.
+    pub total: u64,
+    /// Records written.
+    pub emitted: u64,
+    /// Records dropped as duplicates, or for lacking the label.
+    pub dropped: u64,
+}
+
+/// Copies `reader` to `writer` keeping one record per value of `label`.
+/// Records without the label are passed through.
+///
+/// `Keep::First` streams, remembering only the keys seen. `Keep::Last` holds
+/// the latest line for every key, and the records without the label, until
+/// EOF; the kept records are then written in input order.
+pub fn dedup_by<W: Write>(reader: &mut LineReader,
+                          label: &String,
+                          keep: Keep,
+                          writer: &mut W)
+                          -> Result<DedupStats, Error> {
+    dedup_by_with(reader, label, keep, true, writer)
+}
+
+/// Like `dedup_by`, but records without the label are dropped unless
+/// `keep_missing` is set.
+pub fn dedup_by_with<W: Write>(reader: &mut LineReader,
+                               label: &String,
+                               keep: Keep,
+                               keep_missing: bool,
+                               writer: &mut W)
+                               -> Result<DedupStats, Error> {
+    let mut stats = DedupStats::default();
+    let mut seen = HashSet::new();
+    let mut latest: HashMap<String, (u64, String)> = HashMap::new();
+    let mut missing = Vec::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let seq = stats.total;
+        stats.total += 1;
+        let key = match parse_read(reader, &line)?.remove(label) {
+            Some(key) => key,
+            None if keep_missing && keep == Keep::Last => {
+                missing.push((seq, line.clone()));
+                continue;
+            }
+            None if keep_missing => {
+                writeln!(writer, "{}", line)?;
+                stats.emitted += 1;
+                continue;
+            }
+            None => continue,
+        };
+        match keep {
+            Keep::First => {
+                if seen.insert(key) {
+                    writeln!(writer, "{}", line)?;
+                    stats.emitted += 1;
+                }
+            }
+            Keep::Last => {
+                latest.insert(key, (seq, line.clone()));
+            }
+        }
+    }
+    let mut kept = latest.into_values().collect::<Vec<_>>();
+    kept.append(&mut missing);
+    kept.sort();
+    for (_, line) in &kept {
+        writeln!(writer, "{}", line)?;
+        stats.emitted += 1;
+    }
+    stats.dropped = stats.total - stats.emitted;
+    Ok(stats)
+}
+
+/// Returns the first line for each value of `label`, dropping later lines
+/// with a value already seen, like `dedup_by` with `Keep::First`. Lines
+/// without the label are all kept.
+pub fn dedup_lines_by(reader: &mut LineReader, label: &String) -> Result<Vec<String>, Error> {
+    let mut out = Vec::new();
+    dedup_by(reader, label, Keep::First, &mut out)?;
+    Ok(String::from_utf8_lossy(&out).lines().map(|line| line.to_string()).collect())
+}
+
+/// Options for `profile`.
+#[derive(Clone, Debug)]
+pub struct ProfileOptions {
+    /// Number of distinct values tracked exactly per label; beyond it the
+    /// distinct count is reported as `Distinct::AtLeast(cap)`.
+    pub distinct_cap: usize,
+    /// Number of example values kept per label.
+    pub examples: usize,
+    /// Formats tried when checking whether values are timestamps.
+    pub time_formats: Vec<TimeFormat>,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> ProfileOptions {
+        ProfileOptions {
+            distinct_cap: 10_000,
+            examples: 3,
+            time_formats: vec![TimeFormat::Strftime("%d/%b/%Y:%H:%M:%S %z".to_string()),
+                               TimeFormat::Strftime("%Y-%m-%dT%H:%M:%S%z".to_string())],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distinct {
+    Exact(u64),
+    /// More distinct values than the configured cap were seen.
+    AtLeast(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    Integer,
+    Float,
+    Timestamp,
+    String,
+}
+
+/// Statistics about one label, as computed by `profile`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelProfile {
+    /// Number of records carrying the label.
+    pub count: u64,
+    /// `count` divided by the number of records in the input.
+    pub fill_rate: f64,
+    pub distinct: Distinct,
+    /// Shortest and longest value, in characters.
+    pub min_len: usize,
+    pub max_len: usize,
+    pub all_integer: bool,
+    pub all_float: bool,
+    pub all_timestamp: bool,
+    /// The first distinct values seen.
+    pub examples: Vec<String>,
+}
+
+impl LabelProfile {
+    /// The narrowest type all values of the label parse as.
+    pub fn value_type(&self) -> ValueType {
+        if self.all_integer {
+            ValueType::Integer
+        } else if self.all_float {
+            ValueType::Float
+        } else if self.all_timestamp {
+            ValueType::Timestamp
+        } else {
+            ValueType::String
+        }
+    }
+}
+
+/// Profiles every label of the input in one pass: how often it occurs, its
+/// cardinality, value lengths and the type its values parse as.
+pub fn profile(reader: &mut LineReader,
+               opts: &ProfileOptions)
+               -> Result<HashMap<String, LabelProfile>, Error> {
+    let mut profiles = HashMap::new();
+    let mut seen: HashMap<String, Option<HashSet<String>>> = HashMap::new();
+    let mut total = 0;
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        total += 1;
+        for (label, value) in parse_read(reader, &line)? {
+            let len = value.chars().count();
+            let p = profiles.entry(label.clone()).or_insert_with(|| {
+                LabelProfile {
+                    count: 0,
+                    fill_rate: 0.0,
+                    distinct: Distinct::Exact(0),
+                    min_len: len,
+                    max_len: len,
+                    all_integer: true,
+                    all_float: true,
+                    all_timestamp: true,
+                    examples: Vec::new(),
+                }
+            });
+            p.count += 1;
+            p.min_len = p.min_len.min(len);
+            p.max_len = p.max_len.max(len);
+            p.all_integer &= value.parse::<i64>().is_ok();
+            p.all_float &= value.parse::<f64>().is_ok();
+            p.all_timestamp &= opts.time_formats.iter().any(|fmt| fmt.parse(&value).is_some());
+
+            let values = seen.entry(label).or_insert_with(|| Some(HashSet::new()));
+            let capped = match *values {
+                Some(ref mut values) => {
+                    if !values.contains(&value) {
+                        if p.examples.len() < opts.examples {
+                            p.examples.push(value.clone());
+                        }
+                        values.insert(value);
+                    }
+                    values.len() > opts.distinct_cap
+                }
+                None => false,
+            };
+            if capped {
+                *values = None;
+            }
+            p.distinct = match *values {
+                Some(ref values) => Distinct::Exact(values.len() as u64),
+                None => Distinct::AtLeast(opts.distinct_cap as u64),
+            };
+        }
+    }
+    for p in profiles.values_mut() {
+        p.fill_rate = p.count as f64 / total as f64;
+    }
+    Ok(profiles)
+}
+
+/// The value used for records lacking a label in a `CrossTab`.
+pub const MISSING: &str = "(missing)";
+
+/// A matrix of record counts by the values of two labels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrossTab {
+    pub row_label: String,
+    pub col_label: String,
+    /// Sorted values of the row label, with `MISSING` last if present.
+    pub rows: Vec<String>,
+    /// Sorted values of the column label, with `MISSING` last if present.
+    pub columns: Vec<String>,
+    /// `counts[i][j]` is the number of records with `rows[i]` and `columns[j]`.
+    pub counts: Vec<Vec<u64>>,
+}
+
+impl CrossTab {
+    /// Renders the matrix as a text table with right-aligned columns.
+    pub fn format_table(&self) -> String {
+        let mut cells = vec![self.header()];
+        for (row, counts) in self.rows.iter().zip(&self.counts) {
+            let mut cols = vec![row.clone()];
+            cols.extend(counts.iter().map(|c| c.to_string()));
+            cells.push(cols);
+        }
+        let widths = (0..self.columns.len() + 1)
+            .map(|j| cells.iter().map(|row| row[j].chars().count()).max().unwrap_or(0))
+            .collect::<Vec<usize>>();
+        let mut table = String::new();
+        for row in cells {
+            let line = row.iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(j, (cell, &width))| {
+                    if j == 0 {
+                        format!("{:<1$}", cell, width)
+                    } else {
+                        format!("{:>1$}", cell, width)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("  ");
+            table.push_str(line.trim_end());
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Renders the matrix as CSV rows, header first, one element per row.
+    pub fn to_csv(&self) -> Vec<String> {
+        let header = self.header();
+        let mut rows = vec![csv_row(header.iter().map(|s| s.as_str()))];
+        for (row, counts) in self.rows.iter().zip(&self.counts) {
+            let counts = counts.iter().map(|c| c.to_string()).collect::<Vec<String>>();
+            rows.push(csv_row(iter::once(row.as_str()).chain(counts.iter().map(|c| c.as_str()))));
+        }
+        rows
+    }
+
+    fn header(&self) -> Vec<String> {
+        let mut header = vec![format!("{}\\{}", self.row_label, self.col_label)];
+        header.extend(self.columns.iter().cloned());
+        header
+    }
+}
+
+/// Counts records by the values of `row_label` and `col_label`. Records
+/// lacking either label are counted under `MISSING` when `include_missing` is
+/// set, and dropped otherwise.
+pub fn crosstab(reader: &mut LineReader,
+                row_label: &String,
+                col_label: &String,
+                include_missing: bool)
+                -> Result<CrossTab, Error> {
+    let mut cells = HashMap::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let mut record = parse_read(reader, &line)?;
+        let (row, col) = match (record.remove(row_label), record.remove(col_label)) {
+            (Some(row), Some(col)) => (row, col),
+            (row, col) if include_missing => {
+                (row.unwrap_or_else(|| MISSING.to_string()),
+                 col.unwrap_or_else(|| MISSING.to_string()))
+            }
+            _ => continue,
+        };
+        *cells.entry((row, col)).or_insert(0) += 1;
+    }
+    let sorted_keys = |keys: HashSet<&String>| {
+        let mut keys = keys.into_iter().cloned().collect::<Vec<String>>();
+        keys.sort_by_key(|k| (k == MISSING, k.clone()));
+        keys
+    };
+    let rows = sorted_keys(cells.keys().map(|(row, _)| row).collect());
+    let columns = sorted_keys(cells.keys().map(|(_, col)| col).collect());
+    let counts = rows.iter()
+        .map(|row| {
+            columns.iter()
+                .map(|col| *cells.get(&(row.clone(), col.clone())).unwrap_or(&0))
+                .collect()
+        })
+        .collect();
+    Ok(CrossTab {
+        row_label: row_label.clone(),
+        col_label: col_label.clone(),
+        rows,
+        columns,
+        counts,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
+
+impl CmpOp {
+    /// Returns whether `a op b` holds.
+    pub fn eval<T: PartialOrd>(self, a: &T, b: &T) -> bool {
+        match self {
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Ge => a >= b,
+            CmpOp::Gt => a > b,
+        }
+    }
+}
+
+/// Invokes `f` on each record whose `label` value has a length satisfying
+/// `length op len`. Lengths count Unicode scalar values, not bytes. Records
+/// lacking the label are skipped.
+pub fn filter_len<F>(reader: &mut LineReader,
+                     label: &String,
+                     op: CmpOp,
+                     len: usize,
+                     f: F)
+                     -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        if record.get(label).is_some_and(|v| op.eval(&v.chars().count(), &len)) {
+            f(&record);
+        }
+    }
+    Ok(())
+}
+
+/// Invokes `f` on each record that carries `label`, whatever its value.
+pub fn filter_has<F>(reader: &mut LineReader, label: &String, f: F) -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        if record.contains_key(label) {
+            f(&record);
+        }
+    }
+    Ok(())
+}
+
+/// What `filter_cidr` does with a record whose address doesn't parse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnparsedAddr {
+    /// Leave the record out.
+    Skip,
+    /// Pass the record to `f`.
+    Keep,
+    /// Fail with a parse error naming the line number.
+    Error,
+}
+
+/// Invokes `f` on each record whose `label` holds an IPv4 or IPv6 address
+/// in one of the `cidrs` blocks, or outside all of them when `include` is
+/// false. `cidr::PRIVATE_RANGES` lists the internal blocks. Records lacking
+/// the label are skipped; those whose address doesn't parse are handled as
+/// `unparsed` says. A block that doesn't parse is an error.
+pub fn filter_cidr<F>(reader: &mut LineReader,
+                      label: &String,
+                      cidrs: &[&str],
+                      include: bool,
+                      unparsed: UnparsedAddr,
+                      f: F)
+                      -> Result<(), Error>
+    where F: Fn(&Record)
+{
+    let blocks = cidrs.iter().map(|s| Cidr::new(s)).collect::<Result<Vec<Cidr>, Error>>()?;
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let record = parse_read(reader, &line)?;
+        let value = match record.get(label) {
+            Some(value) => value,
+            None => continue,
+        };
+        let keep = match cidr::parse_addr(value) {
+            Some(addr) => blocks.iter().any(|block| block.contains(addr)) == include,
+            None => {
+                match unparsed {
+                    UnparsedAddr::Skip => false,
+                    UnparsedAddr::Keep => true,
+                    UnparsedAddr::Error => {
+                        let msg = format!("line {}: invalid IP address in {}: {:?}",
+                                          reader.line_number,
+                                          label,
+                                          value);
+                        let err = line_error(reader, msg).with_field(label.as_str());
+                        return Err(Error::Parse(err));
+                    }
+                }
+            }
+        };
+        if keep {
+            f(&record);
+        }
+    }
+    Ok(())
+}
+
+/// The number of records of one group within one time window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RatePoint {
+    /// Start of the window, in seconds since the Unix epoch.
+    pub start: i64,
+    /// Value of the group label, empty when not grouping.
+    pub group: String,
+    pub count: u64,
+    /// `count` per second.
+    pub rate: f64,
+}
+
+/// Counts records per time window of `window_secs` and per value of
+/// `group_label`, and turns the counts into per-second rates.
+///
+/// Windows are aligned to multiples of `window_secs` since the epoch, so
+/// out-of-order records still land in the right window. Records whose time
+/// doesn't parse are skipped; those lacking the group label are counted under
+/// `MISSING`. Points are sorted by window, then group, with `MISSING` last.
+pub fn rates(reader: &mut LineReader,
+             time_label: &String,
+             window_secs: u64,
+             format: &TimeFormat,
+             group_label: Option<&String>)
+             -> Result<Vec<RatePoint>, Error> {
+    if window_secs == 0 {
+        return Err(Error::InvalidArgument("window must be greater than 0".to_string()));
+    }
+    let window = window_secs as i64;
+    let mut counts = HashMap::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        let mut record = parse_read(reader, &line)?;
+        let time = match record.get(time_label).and_then(|v| format.parse(v)) {
+            Some(time) => time,
+            None => continue,
+        };
+        let group = match group_label {
+            Some(label) => record.remove(label).unwrap_or_else(|| MISSING.to_string()),
+            None => String::new(),
+        };
+        *counts.entry((time.div_euclid(window) * window, group)).or_insert(0) += 1;
+    }
+    let mut points = counts.into_iter()
+        .map(|((start, group), count)| {
+            RatePoint {
+                start,
+                group,
+                count,
+                rate: count as f64 / window as f64,
+            }
+        })
+        .collect::<Vec<RatePoint>>();
+    points.sort_by(|a, b| {
+        (a.start, a.group == MISSING, &a.group).cmp(&(b.start, b.group == MISSING, &b.group))
+    });
+    Ok(points)
+}
+
+/// Counts records per second of `time_label`, parsed with the strftime-style
+/// `fmt`, in chronological order. Seconds without records are left out and
+/// records whose time doesn't parse are skipped.
+pub fn rate_per_second(reader: &mut LineReader,
+                       time_label: &String,
+                       fmt: &str)
+                       -> Result<Vec<(i64, u64)>, Error> {
+    let format = TimeFormat::Strftime(fmt.to_string());
+    let points = rates(reader, time_label, 1, &format, None)?;
+    Ok(points.into_iter().map(|p| (p.start, p.count)).collect())
+}
+
+/// Parses every record of the input into a `Vec`, failing on the first
+/// malformed line.
+pub fn collect_records(reader: &mut LineReader) -> Result<Vec<Record>, Error> {
+    collect_records_with(reader, &ParseOptions::default())
+}
+
+/// Like `collect_records`, reading records as `opts` says.
+pub fn collect_records_with(reader: &mut LineReader,
+                            opts: &ParseOptions)
+                            -> Result<Vec<Record>, Error> {
+    let mut records = Vec::with_capacity(opts.hint_records);
+    while let Some(record) = next_record(reader, opts)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Loads every record carrying `label` into a map keyed by its value, for
+/// lookups after a single pass. Records without `label` are skipped; when
+/// several records share a value, the last one is kept.
+pub fn index_by(reader: &mut LineReader, label: &String) -> Result<HashMap<String, Record>, Error> {
+    index_by_with(reader, label, &ParseOptions::default())
+}
+
+pub fn index_by_with(reader: &mut LineReader,
+                     label: &String,
+                     opts: &ParseOptions)
+                     -> Result<HashMap<String, Record>, Error> {
+    let mut index = HashMap::new();
+    while let Some(record) = next_record(reader, opts)? {
+        if let Some(value) = record.get(label).cloned() {
+            index.insert(value, record);
+        }
+    }
+    Ok(index)
+}
+
+/// Builds a record one field at a time:
+/// `RecordBuilder::new().field("host", "a").field("status", "200").build()`.
+/// A label given twice keeps its last value.
+#[derive(Clone, Debug, Default)]
+pub struct RecordBuilder {
+    record: Record,
+}
+
+impl RecordBuilder {
+    pub fn new() -> RecordBuilder {
+        RecordBuilder::default()
+    }
+
+    pub fn field<L, V>(mut self, label: L, value: V) -> RecordBuilder
+        where L: Into<String>,
+              V: Into<String>
+    {
+        self.record.insert(label.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Record {
+        self.record
+    }
+}
+
+/// Makes a record of `pairs`, a label given twice keeping its last value.
+pub fn from_pairs(pairs: &[(&str, &str)]) -> Record {
+    pairs.iter().map(|&(label, value)| (label.to_string(), value.to_string())).collect()
+}
+
+/// Returns `record` with the time in `label` parsed with `from_fmt` and
+/// written in UTC with `to_fmt`, both strftime-style patterns as described at
+/// `TimeFormat::Strftime`. A record lacking the label is returned unchanged;
+/// a value that doesn't parse is an error.
+pub fn reformat_time(record: &Record,
+                     label: &String,
+                     from_fmt: &str,
+                     to_fmt: &str)
+                     -> Result<Record, Error> {
+    let mut record = record.clone();
+    if let Some(value) = record.get_mut(label) {
+        let t = match timefmt::strptime(value, from_fmt) {
+            Some(t) => t,
+            None => {
+                let msg = format!("can't parse {} as {:?}: {:?}", label, from_fmt, value);
+                return Err(Error::Parse(ParseError::new(msg).with_field(label.as_str())));
+            }
+        };
+        *value = timefmt::strftime(t, to_fmt).ok_or_else(|| {
+                Error::InvalidArgument(format!("unsupported time format: {}", to_fmt))
+            })?;
+    }
+    Ok(record)
+}
+
+/// Joins the values of `labels` in `record` with `sep` into one key, a missing
+/// label giving an empty value. Keys are only unambiguous if `sep` doesn't
+/// occur in the values.
+pub fn composite_key(record: &Record, labels: &[String], sep: char) -> String {
+    let mut key = String::new();
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 {
+            key.push(sep);
+        }
+        if let Some(value) = record.get(label) {
+            key.push_str(value);
+        }
+    }
+    key
+}
+
+/// Returns the value of `label` parsed as a `T`, or `None` if the record
+/// lacks the label or the value doesn't parse.
+pub fn get_parsed<T: FromStr>(record: &Record, label: &str) -> Option<T> {
+    record.get(label).and_then(|value| value.parse().ok())
+}
+
+/// Typed access to the values of a record. Each getter returns `Ok(None)`
+/// when the label is missing and an error naming the label and the value
+/// when it is present but doesn't parse.
+pub trait RecordExt {
+    fn get_str(&self, label: &str) -> Option<&str>;
+
+    fn get_parsed<T: FromStr>(&self, label: &str) -> Result<Option<T>, ParseError> {
+        parse_value(label, self.get_str(label), |value| value.parse().ok())
+    }
+
+    fn get_f64(&self, label: &str) -> Result<Option<f64>, ParseError> {
+        self.get_parsed(label)
+    }
+
+    fn get_u64(&self, label: &str) -> Result<Option<u64>, ParseError> {
+        self.get_parsed(label)
+    }
+
+    /// Accepts `1`, `true` and `yes` as true and `0`, `false` and `no` as
+    /// false, in any case.
+    fn get_bool(&self, label: &str) -> Result<Option<bool>, ParseError> {
+        let parse = |value: &str| match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Some(true),
+            "0" | "false" | "no" => Some(false),
+            _ => None,
+        };
+        parse_value(label, self.get_str(label), parse)
+    }
+
+    /// Returns the time in `label` as seconds since the Unix epoch.
+    fn get_time(&self, label: &str, format: &TimeFormat) -> Result<Option<i64>, ParseError> {
+        parse_value(label, self.get_str(label), |value| format.parse(value))
+    }
+}
+
+impl<S: BuildHasher> RecordExt for RecordWith<S> {
+    fn get_str(&self, label: &str) -> Option<&str> {
+        self.get(label).map(String::as_str)
+    }
+}
+
+fn parse_value<T, F>(label: &str, value: Option<&str>, parse: F) -> Result<Option<T>, ParseError>
+    where F: FnOnce(&str) -> Option<T>
+{
+    match value {
+        None => Ok(None),
+        Some(value) => {
+            parse(value).map(Some).ok_or_else(|| {
+                let msg = format!("invalid value for {}: {:?}", label, value);
+                ParseError::new(msg).with_field(label)
+            })
+        }
+    }
+}
+
+/// The fields in which two records differ, each list sorted by label.
+///
+/// The lists were kept when `diff_streams` came to reuse this type, rather
+/// than turned into maps, so that `diff` callers are unaffected; sorted by
+/// label, they are as deterministic and can be searched with
+/// `binary_search_by`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecordDiff {
+    /// Labels only the second record carries.
+    pub added: Vec<String>,
+    /// Labels only the first record carries.
+    pub removed: Vec<String>,
+    /// Labels carried by both with different values, as `(label, old, new)`.
+    pub changed: Vec<(String, String, String)>,
+}
+
+impl RecordDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares record `a` with record `b`, as `diff_records` does.
+pub fn diff(a: &Record, b: &Record) -> RecordDiff {
+    diff_records(a, b)
+}
+
+/// Compares record `a` with record `b`.
+pub fn diff_records(a: &Record, b: &Record) -> RecordDiff {
+    let mut d = RecordDiff::default();
+    for (label, old) in a {
+        match b.get(label) {
+            None => d.removed.push(label.clone()),
+            Some(new) if new != old => d.changed.push((label.clone(), old.clone(), new.clone())),
+            Some(_) => {}
+        }
+    }
+    d.added = b.keys().filter(|label| !a.contains_key(*label)).cloned().collect();
+    d.added.sort();
+    d.removed.sort();
+    d.changed.sort();
+    d
+}
+
+/// A difference found by `diff_streams`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyedDiff {
+    /// A key whose records differ, with the fields the right record changes.
+    Changed(String, RecordDiff),
+    /// A key only the left stream carries.
+    LeftOnly(String),
+    /// A key only the right stream carries.
+    RightOnly(String),
+}
+
+/// Compares the records of `left` and `right` matched by the value of
+/// `key_label`, yielding the keys whose records differ and those found on one
+/// side only. Identical records and records lacking the label are skipped.
+///
+/// The smaller side, by file size, is loaded into memory as `join` does, the
+/// last record winning for a repeated key; the right side when a size isn't
+/// known. The other side is streamed: each of its records is compared with
+/// the loaded one for its key as it is read, so a key repeated there is
+/// reported for every occurrence that differs, in input order. The keys only
+/// the loaded side carries follow at the end, sorted. Memory grows with the
+/// loaded side only.
+pub fn diff_streams<'a>(left: &'a mut LineReader,
+                        right: &'a mut LineReader,
+                        key_label: &str)
+                        -> impl Iterator<Item = Result<KeyedDiff, Error>> + 'a {
+    diff_streams_with(left, right, key_label, ParseOptions::default())
+}
+
+/// Like `diff_streams`, reading both sides as `opts` says. The options are
+/// taken by value since the iterator outlives the call.
+pub fn diff_streams_with<'a>(left: &'a mut LineReader,
+                             right: &'a mut LineReader,
+                             key_label: &str,
+                             opts: ParseOptions)
+                             -> impl Iterator<Item = Result<KeyedDiff, Error>> + 'a {
+    let load_left = match (left.total_len(), right.total_len()) {
+        (Some(l), Some(r)) => l < r,
+        (Some(_), None) => true,
+        _ => false,
+    };
+    let (streamed, loaded) = if load_left { (right, left) } else { (left, right) };
+    let key = key_label.to_string();
+    // Each loaded record along with whether a streamed record has matched it.
+    let (mut index, mut failed) = match index_by_with(loaded, &key, &opts) {
+        Ok(index) => (index.into_iter().map(|(k, record)| (k, (record, false))).collect(), None),
+        Err(err) => (HashMap::new(), Some(err)),
+    };
+    let only = move |on_loaded: bool, key: String| if on_loaded == load_left {
+        KeyedDiff::LeftOnly(key)
+    } else {
+        KeyedDiff::RightOnly(key)
+    };
+    let mut rest: Option<vec::IntoIter<String>> = None;
+    iter::from_fn(move || {
+        if let Some(err) = failed.take() {
+            rest = Some(Vec::new().into_iter());
+            return Some(Err(err));
+        }
+        while rest.is_none() {
+            let record = match next_record(streamed, &opts) {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    let mut keys = index.drain()
+                        .filter(|(_, (_, matched))| !matched)
+                        .map(|(k, _)| k)
+                        .collect::<Vec<_>>();
+                    keys.sort();
+                    rest = Some(keys.into_iter());
+                    break;
+                }
+                Err(err) => {
+                    rest = Some(Vec::new().into_iter());
+                    return Some(Err(err));
+                }
+            };
+            let value = match record.get(&key) {
+                Some(value) => value,
+                None => continue,
+            };
+            let (other, matched) = match index.get_mut(value) {
+                Some(entry) => entry,
+                None => return Some(Ok(only(false, value.clone()))),
+            };
+            *matched = true;
+            let d = if load_left {
+                diff_records(other, &record)
+            } else {
+                diff_records(&record, other)
+            };
+            if !d.is_empty() {
+                return Some(Ok(KeyedDiff::Changed(value.clone(), d)));
+            }
+        }
+        let key = rest.as_mut()?.next()?;
+        Some(Ok(only(true, key)))
+    })
+}
+
+/// Returns a copy of `record` with the value of `label`, if present, replaced
+/// by `f` applied to it.
+pub fn map_field<F>(record: &Record, label: &String, f: F) -> Record
+    where F: Fn(&str) -> String
+{
+    let mut mapped = record.clone();
+    if let Some(value) = mapped.get_mut(label) {
+        *value = f(value);
+    }
+    mapped
+}
+
+/// Adds the query parameters of the URI in `uri_label` to `record`, each as
+/// a label of its name after `prefix`: `q.page:2` for `/a?page=2` with prefix
+/// `q.`. Names and values are percent-decoded, with `+` taken as a space; a
+/// parameter without `=` gets an empty value. When a parameter is repeated,
+/// the first value wins. Existing labels of the same names are replaced.
+pub fn expand_query(record: &mut Record, uri_label: &str, prefix: &str) {
+    expand_query_with(record, uri_label, prefix, None)
+}
+
+/// Like `expand_query`, but the values of a repeated parameter are joined
+/// with `join` if given.
+pub fn expand_query_with(record: &mut Record, uri_label: &str, prefix: &str, join: Option<&str>) {
+    let params = match record.get(uri_label) {
+        Some(uri) => query_params(uri),
+        None => return,
+    };
+    let mut added = HashSet::new();
+    for (name, value) in params {
+        let label = format!("{}{}", prefix, name);
+        if added.insert(label.clone()) {
+            record.insert(label, value);
+        } else if let Some(sep) = join {
+            let joined = record.get_mut(&label).unwrap();
+            joined.push_str(sep);
+            joined.push_str(&value);
+        }
+    }
+}
+
+/// Counts records by the value of the query parameter `param` of the URI in
+/// `uri_label`, decoded as `expand_query` does. Records without the
+/// parameter are skipped.
+pub fn group_by_query_param(reader: &mut LineReader,
+                            uri_label: &String,
+                            param: &str)
+                            -> Result<FieldGroupCount, Error> {
+    group_by_map(reader, uri_label, |uri| {
+        query_params(uri).into_iter().find(|(name, _)| name == param).map(|(_, value)| value)
+    })
+}
+
+/// The decoded parameters of the query string of `uri`, in order.
+fn query_params(uri: &str) -> Vec<(String, String)> {
+    let uri = uri.split('#').next().unwrap_or("");
+    let query = match uri.split_once('?') {
+        Some((_, query)) => query,
+        None => return Vec::new(),
+    };
+    query.split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes and `+` in a query string component. Malformed
+/// escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| ::std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Returns the first record of the input, or `None` if it has none. Nothing
+/// past that record is read.
+pub fn first_record(reader: &mut LineReader) -> Result<Option<Record>, Error> {
+    next_record(reader, &ParseOptions::default())
+}
+
+/// Returns the first record satisfying `pred`, or `None` if there is none.
+/// Reading stops at the match.
+pub fn find<P>(reader: &mut LineReader, pred: P) -> Result<Option<Record>, Error>
+    where P: Fn(&Record) -> bool
+{
+    find_with(reader, &ParseOptions::default(), pred)
+}
+
+pub fn find_with<P>(reader: &mut LineReader,
+                    opts: &ParseOptions,
+                    pred: P)
+                    -> Result<Option<Record>, Error>
+    where P: Fn(&Record) -> bool
+{
+    while let Some(record) = next_record(reader, opts)? {
+        if pred(&record) {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the last record of the input, or `None` if it has none. Only the
+/// last line is kept in memory and parsed, so earlier malformed lines go
+/// unnoticed.
+pub fn last_record(reader: &mut LineReader) -> Result<Option<Record>, Error> {
+    let (mut line, mut last) = (String::new(), None);
+    while next_line(reader, &mut line)? {
+        last = Some((reader.line_number, line.clone()));
+    }
+    last.map(|(n, line)| parse_record(&line).map_err(|err| at_line_number(reader, n, err)))
+        .transpose()
+}
+
+/// Summary statistics of a series of numbers. All but `count` are `NaN` for
+/// an empty series.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// Accumulates `Stats` one value at a time.
+struct StatsBuilder {
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+impl StatsBuilder {
+    fn new() -> StatsBuilder {
+        StatsBuilder {
+            count: 0,
+            min: f64::NAN,
+            max: f64::NAN,
+            sum: 0.0,
+        }
+    }
+
+    fn push(&mut self, v: f64) {
+        self.min = v.min(self.min);
+        self.max = v.max(self.max);
+        self.sum += v;
+        self.count += 1;
+    }
+
+    fn build(&self) -> Stats {
+        Stats {
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: self.sum / self.count as f64,
+        }
+    }
+}
+
+/// Computes statistics of the input's line lengths in bytes, without their
+/// newline. Blank lines are skipped and lines are not parsed.
+pub fn line_length_stats(reader: &mut LineReader) -> Result<Stats, Error> {
+    let mut stats = StatsBuilder::new();
+    let mut line = String::new();
+    while next_line(reader, &mut line)? {
+        stats.push(line.len() as f64);
+    }
+    Ok(stats.build())
+}
+
+/// Sums the numeric values of `label` over all records. Records without
+/// `label` are skipped; a value that isn't a number is an error.
+pub fn sum(reader: &mut LineReader, label: &String) -> Result<f64, Error> {
+    sum_with(reader, label, &ParseOptions::default())
+}
+
+pub fn sum_with(reader: &mut LineReader,
+                label: &String,
+                opts: &ParseOptions)
+                -> Result<f64, Error> {
+    sum_count(reader, label, opts).map(|(sum, _)| sum)
+}
+
+/// Averages the numeric values of `label` over all records, like `sum`. The
+/// mean of no values is `NaN`.
+pub fn mean(reader: &mut LineReader, label: &String) -> Result<f64, Error> {
+    mean_with(reader, label, &ParseOptions::default())
+}
+
+pub fn mean_with(reader: &mut LineReader,
+                 label: &String,
+                 opts: &ParseOptions)
+                 -> Result<f64, Error> {
+    sum_count(reader, label, opts).map(|(sum, count)| sum / count as f64)
+}
+
+fn sum_count(reader: &mut LineReader,
+             label: &String,
+             opts: &ParseOptions)
+             -> Result<(f64, u64), Error> {
+    let (mut sum, mut count) = (0.0, 0);
+    while let Some(record) = next_record(reader, opts)? {
+        if let Some(value) = record.get(label) {
+            match value.parse::<f64>() {
+                Ok(v) => sum += v,
+                Err(_) => {
+                    let msg = format!("not a number in {}: {}", label, value);
+                    return Err(Error::Parse(ParseError::new(msg).with_field(label.as_str())));
+                }
+            }
+            count += 1;
+        }
+    }
+    Ok((sum, count))
+}
+
+/// Parses a single LTSV line with the same rules as `each_record`. A trailing
+/// `\n` or `\r\n` is ignored; a field without a separator is an error that
+/// names the field.
+///
+/// Only the first separator of a field splits it, so `url:http://x` has the
+/// value `http://x`. Empty labels are accepted: `:orphan` gives the label `""`
+/// the value `orphan`. `validate` reports them for inputs that must follow
+/// the LTSV label syntax.
+pub fn parse_line(line: &str) -> Result<Record, Error> {
+    parse_line_with(line, &ParseOptions::default())
+}
+
+pub fn parse_line_with(line: &str, opts: &ParseOptions) -> Result<Record, Error> {
+    parse_record_with(trim_newline(line), opts)
+}
+
+fn trim_newline_bytes(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn not_utf8(reader: &LineReader, valid_len: usize) -> Error {
+    let msg = format!("line {} is not valid UTF-8: invalid byte after {} bytes",
+                      reader.line_number,
+                      valid_len);
+    Error::Parse(line_error(reader, msg))
+}
+
+/// The error for a line longer than `max` bytes, or `None` if it may be
+/// skipped and has been counted.
+fn too_long(reader: &mut LineReader, opts: &ParseOptions, len: u64) -> Option<Error> {
+    if opts.lenient {
+        reader.long_lines += 1;
+        return None;
+    }
+    Some(too_long_error(reader, opts, len))
+}
+
+fn too_long_error(reader: &LineReader, opts: &ParseOptions, len: u64) -> Error {
+    let msg = format!("line {} is too long: {} bytes, at most {} allowed",
+                      reader.line_number,
+                      len,
+                      opts.max_line_len);
+    Error::Parse(line_error(reader, msg))
+}
+
+fn trim_newline(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Reads the next non-blank line into `line`, without its trailing newline.
+/// Returns `false` at EOF.
+fn next_line(reader: &mut LineReader, line: &mut String) -> Result<bool, Error> {
+    next_line_with(reader, &ParseOptions::default(), line)
+}
+
+/// Like `next_line`, but also skips the lines `opts` says to ignore.
+fn next_line_with(reader: &mut LineReader,
+                  opts: &ParseOptions,
+                  line: &mut String)
+                  -> Result<bool, Error> {
+    loop {
+        check_cancelled(opts)?;
+        line.clear();
+        match reader.read_line_max(line, opts) {
+            Err(err) => return Err(Error::Io(err)),
+            Ok(LineRead::Eof) => {
+                report_eof(reader, opts);
+                return Ok(false);
+            }
+            Ok(LineRead::TooLong(len)) => {
+                if let Some(err) = too_long(reader, opts, len) {
+                    return Err(err);
+                }
+            }
+            Ok(LineRead::InvalidUtf8(valid_len)) => {
+                if !opts.lenient {
+                    return Err(not_utf8(reader, valid_len));
+                }
+            }
+            Ok(LineRead::Line) => {
+                let len = opts.delimiter.trim(line).len();
+                line.truncate(len);
+                if line.is_empty() {
+                    blank_line(reader, opts)?;
+                } else if !is_comment(opts, line) {
+                    count_record(reader, opts);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Handles a blank line as `opts.blank_lines` says.
+fn blank_line(reader: &mut LineReader, opts: &ParseOptions) -> Result<(), Error> {
+    match opts.blank_lines {
+        BlankPolicy::Skip => {}
+        BlankPolicy::Error => {
+            let msg = format!("blank line at line {}", reader.line_number);
+            return Err(Error::Parse(line_error(reader, msg)));
+        }
+        BlankPolicy::Count => reader.blank_lines += 1,
+    }
+    Ok(())
+}
+
+fn check_cancelled(opts: &ParseOptions) -> Result<(), Error> {
+    match opts.cancel {
+        Some(ref cancel) if cancel.load(AtomicOrdering::Relaxed) => Err(Error::Cancelled(None)),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a record read, reporting progress if an interval is complete.
+fn count_record(reader: &mut LineReader, opts: &ParseOptions) {
+    reader.records += 1;
+    if let Some(ref hook) = opts.progress {
+        if reader.records.is_multiple_of(hook.interval) {
+            report_progress(reader, hook);
+        }
+    }
+}
+
+/// Reports progress at EOF, once per reader.
+fn report_eof(reader: &mut LineReader, opts: &ParseOptions) {
+    if let Some(ref hook) = opts.progress {
+        if !reader.reported_eof {
+            reader.reported_eof = true;
+            report_progress(reader, hook);
+        }
+    }
+}
+
+fn report_progress(reader: &LineReader, hook: &ProgressHook) {
+    let mut callback = hook.callback.lock().unwrap_or_else(|err| err.into_inner());
+    callback(Progress {
+        records: reader.records,
+        bytes_read: reader.bytes_read,
+        elapsed: reader.opened.elapsed(),
+    });
+}
+
+fn is_comment(opts: &ParseOptions, line: &str) -> bool {
+    opts.comment_prefix.as_ref().is_some_and(|prefix| line.starts_with(prefix.as_str()))
+}
+
+/// Reads and parses the next record, skipping malformed lines in lenient mode.
+fn next_record(reader: &mut LineReader, opts: &ParseOptions) -> Result<Option<Record>, Error> {
+    let mut line = String::new();
+    while next_line_with(reader, opts, &mut line)? {
+        match parse_record_with(&line, opts) {
+            Ok(record) => return Ok(Some(record)),
+            Err(Error::Parse(_)) if opts.lenient => continue,
+            Err(err) => return Err(at_line(reader, err)),
+        }
+    }
+    Ok(None)
+}
+
+fn is_well_formed(line: &str, sep: char) -> bool {
+    line.split('\t').all(|item| item.contains(sep))
+}
+
+/// Parses the line just read from `reader`, numbering a parse error with it.
+fn parse_read(reader: &LineReader, line: &str) -> Result<Record, Error> {
+    parse_record(line).map_err(|err| at_line(reader, err))
+}
+
+fn parse_record(line: &str) -> Result<Record, Error> {
+    parse_record_with(line, &ParseOptions::default())
+}
+
+/// Parses `line` straight into a record, a repeated label keeping its last
+/// value.
+fn parse_record_with(line: &str, opts: &ParseOptions) -> Result<Record, Error> {
+    let mut record = Record::default();
+    parse_fields(line, opts.kv_separator, &mut |label, value| {
+            record.insert(label.to_string(), unescape_with(opts, value));
+            Ok(())
+        })?;
+    Ok(record)
+}
+
+fn unescape_with(opts: &ParseOptions, value: &str) -> String {
+    if opts.escape { unescape(value) } else { value.to_string() }
+}
+
+/// Calls `f` with the label and raw value of every field of `line`, in
+/// order. A field without `sep` is an error, after `f` has seen the fields
+/// before it; so is an error from `f`, which stops the parse.
+fn parse_fields<'l, F>(line: &'l str, sep: char, f: &mut F) -> Result<(), Error>
+    where F: FnMut(&'l str, &'l str) -> Result<(), Error>
+{
+    for field in line.split('\t') {
+        match field.split_once(sep) {
+            Some((label, value)) => f(label, value)?,
+            None => return Err(invalid_item(field, line)),
+        }
+    }
+    Ok(())
+}
+
+/// Splits a line into its `(label, value)` pairs, keeping their order and
+/// any repeated labels. Where a record will do, `parse_record` saves building
+/// the pairs.
+fn parse_pairs(line: &str, sep: char) -> Result<Vec<(String, String)>, Error> {
+    let mut pairs = Vec::new();
+    parse_fields(line, sep, &mut |label, value| {
+            pairs.push((label.to_string(), value.to_string()));
+            Ok(())
+        })?;
+    Ok(pairs)
+}
+
+/// The error for a malformed `item`, quoting the `line` it's in. Lines of
+/// over `MAX_QUOTED_CHARS` characters are cut short with an ellipsis.
+fn invalid_item(item: &str, line: &str) -> Error {
+    let quoted = match line.char_indices().nth(MAX_QUOTED_CHARS) {
+        Some((end, _)) => format!("{:?}...", &line[..end]),
+        None => format!("{:?}", line),
+    };
+    let msg = format!("invalid ltsv item: {} in {}", item, quoted);
+    Error::Parse(ParseError::new(msg).with_field(item))
+}
+
+const MAX_QUOTED_CHARS: usize = 256;
+
+/// Prefixes a parse error with the number of the line just read.
+fn at_line(reader: &LineReader, err: Error) -> Error {
+    at_line_number(reader, reader.line_number, err)
+}
+
+/// Like `at_line`, for the line numbered `line_number` read earlier.
+fn at_line_number(reader: &LineReader, line_number: u64, err: Error) -> Error {
+    match err {
+        Error::Parse(mut err) => {
+            err.msg = format!("line {}: {}", line_number, err.msg);
+            Error::Parse(locate(reader, err).with_line(line_number))
+        }
+        err => err,
+    }
+}
+
+/// The error `msg` about the line just read, whose number `msg` includes.
+fn line_error(reader: &LineReader, msg: String) -> ParseError {
+    locate(reader, ParseError::new(msg))
+}
+
+fn locate(reader: &LineReader, err: ParseError) -> ParseError {
+    let err = err.with_line(reader.line_number);
+    match reader.name {
+        Some(ref name) => err.with_file(name.as_str()),
+        None => err,
+    }
+}
+
+/// Writes `record` with the labels listed in `order` first, in that order,
+/// followed by its remaining labels sorted.
+fn write_record_ordered<'a, W, I>(out: &mut W,
+                                  record: &Record,
+                                  order: I,
+                                  opts: &ParseOptions)
+                                  -> Result<(), Error>
+    where W: Write,
+          I: IntoIterator<Item = &'a String>
+{
+    let mut fields = Vec::with_capacity(record.len());
+    for label in order {
+        if let Some((label, value)) = record.get_key_value(label) {
+            if !fields.iter().any(|&(l, _)| l == label) {
+                fields.push((label, value));
+            }
+        }
+    }
+    let mut rest = record.iter()
+        .filter(|&(l, _)| !fields.iter().any(|&(f, _)| f == l))
+        .collect::<Vec<_>>();
+    rest.sort();
+    fields.extend(rest);
+    write_pairs(out, fields, opts)
+}
+
+/// Writes `pairs` as one LTSV line formatted as `opts` says.
+fn write_pairs<'a, W, I>(out: &mut W, pairs: I, opts: &ParseOptions) -> Result<(), Error>
+    where W: Write,
+          I: IntoIterator<Item = (&'a String, &'a String)>
+{
+    write_line(out, format_pairs(pairs, opts)?, opts)
+}
+
+/// Writes `line`, formatted by `format_pairs` or `push_pair`, ending it as
+/// `opts` says.
+fn write_line<W: Write>(out: &mut W, mut line: String, opts: &ParseOptions) -> Result<(), Error> {
+    line.push(opts.delimiter.byte() as char);
+    out.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Formats `pairs` as an LTSV line without the trailing newline. Labels and
+/// values that can't be represented in LTSV (tabs, newlines, or the separator
+/// in a label) are refused; with `opts.escape`, the characters in values are
+/// escaped instead.
+fn format_pairs<'a, I>(pairs: I, opts: &ParseOptions) -> Result<String, Error>
+    where I: IntoIterator<Item = (&'a String, &'a String)>
+{
+    let mut line = String::new();
+    for (label, value) in pairs {
+        push_pair(&mut line, label, value, opts)?;
+    }
+    Ok(line)
+}
+
+/// Appends one field to `line`, which holds the fields before it.
+fn push_pair(line: &mut String,
+             label: &str,
+             value: &str,
+             opts: &ParseOptions)
+             -> Result<(), Error> {
+    let escape = opts.escape;
+    if label.contains(['\t', '\n', '\r', opts.kv_separator]) ||
+       (!escape && value.contains(['\t', '\n', '\r'])) {
+        let msg = format!("cannot serialize ltsv item: {}:{}", label, value);
+        return Err(Error::Parse(ParseError::new(msg).with_field(label)));
+    }
+    if !line.is_empty() {
+        line.push('\t');
+    }
+    line.push_str(label);
+    line.push(opts.kv_separator);
+    if escape {
+        for c in value.chars() {
+            match c {
+                '\\' => line.push_str("\\\\"),
+                '\t' => line.push_str("\\t"),
+                '\n' => line.push_str("\\n"),
+                '\r' => line.push_str("\\r"),
+                _ => line.push(c),
+            }
+        }
+    } else {
+        line.push_str(value);
+    }
+    Ok(())
+}
+
+/// Reverts the escaping done by `format_pairs`. Unknown escape sequences and
+/// a trailing lone backslash are kept as they are.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn write_csv_row<'a, W, I>(out: &mut W, fields: I) -> Result<(), Error>
+    where W: Write,
+          I: Iterator<Item = &'a str>
+{
+    let mut row = csv_row(fields);
+    row.push('\n');
+    out.write_all(row.as_bytes())?;
+    Ok(())
+}
+
+/// Formats one CSV row without its line terminator. A field holding a comma,
+/// quote or line break is quoted, so the row may span several lines.
+fn csv_row<'a, I>(fields: I) -> String
+    where I: Iterator<Item = &'a str>
+{
+    let mut row = String::new();
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            row.push(',');
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            row.push('"');
+            row.push_str(&field.replace('"', "\"\""));
+            row.push('"');
+        } else {
+            row.push_str(field);
+        }
+    }
+    row
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// A small splitmix64 generator, good enough for sampling.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: Option<u64>) -> Rng {
+        match seed {
+            Some(seed) => Rng(seed),
+            None => Rng(RandomState::new().build_hasher().finish()),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `[0, bound)`.
+    fn below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// Returns a value in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use super::*;
+
+    fn fixture(name: &str, content: &str) -> LineReader {
+        open_file(&fixture_path(name, content)).unwrap()
+    }
+
+    fn fixture_path(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("rlq-test-{}-{}.ltsv", process::id(), name));
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn values(records: &[Record], label: &str) -> Vec<String> {
+        records.iter().map(|r| r[label].clone()).collect()
+    }
+
+    #[test]
+    fn test_parse_head() {}
+
+    #[test]
+    fn test_each_record_step() {
+        let mut reader = fixture("each_record_step",
+                                 "n:0\nn:1\n\nn:2\nn:3\nn:4\nn:5\n");
+        let seen = Cell::new(0);
+        each_record_step(&mut reader, 2, |record| {
+                assert_eq!(record["n"].parse::<i32>().unwrap() % 2, 0);
+                seen.set(seen.get() + 1);
+            })
+            .unwrap();
+        assert_eq!(seen.get(), 3);
+    }
+
+    #[test]
+    fn test_each_record_step_zero() {
+        let mut reader = fixture("each_record_step_zero", "n:0\n");
+        match each_record_step(&mut reader, 0, |_| {}) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("expected an invalid argument error"),
+        }
+    }
+
+    #[test]
+    fn test_each_record_progress() {
+        let content = (0..25).map(|i| format!("n:{}\n", i)).collect::<String>();
+        let mut reader = fixture("each_record_progress", &content);
+        let seen = Cell::new(0);
+        let reports = RefCell::new(Vec::new());
+        each_record_progress(&mut reader,
+                             |_| seen.set(seen.get() + 1),
+                             |lines, bytes| reports.borrow_mut().push((lines, bytes)),
+                             10)
+            .unwrap();
+        assert_eq!(seen.get(), 25);
+        assert_eq!(*reports.borrow(), [(10, 40), (20, 90)]);
+
+        let mut reader = fixture("each_record_progress_zero", "n:0\n");
+        match each_record_progress(&mut reader, |_| {}, |_, _| {}, 0) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("expected an invalid argument error"),
+        }
+    }
+
+    #[test]
+    fn test_head_records() {
+        let mut reader = fixture("head_records", "n:0\nn:1\nn:2\nn:3\n");
+        assert_eq!(values(&head_records(&mut reader, 2).unwrap(), "n"), ["0", "1"]);
+        // The remaining input is still unread.
+        assert_eq!(values(&head_records(&mut reader, 10).unwrap(), "n"), ["2", "3"]);
+        assert!(head_records(&mut reader, 10).unwrap().is_empty());
+
+        let mut reader = fixture("head_records_zero", "n:0\n");
+        assert!(head_records(&mut reader, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_head_records_lenient() {
+        let content = "n:0\n\nbroken\nn:1\n\nn:2\n";
+        let mut reader = fixture("head_records_strict", content);
+        assert!(head_records(&mut reader, 2).is_err());
+
+        let opts = ParseOptions { lenient: true, ..ParseOptions::default() };
+        let mut reader = fixture("head_records_lenient", content);
+        assert_eq!(values(&head_records_with(&mut reader, 2, &opts).unwrap(), "n"),
+                   ["0", "1"]);
+    }
+
+    #[test]
+    fn test_tail_records() {
+        let mut reader = fixture("tail_records", "n:0\nn:1\nn:2\nn:3\n");
+        assert_eq!(values(&tail_records(&mut reader, 2).unwrap(), "n"), ["2", "3"]);
+
+        let mut reader = fixture("tail_records_all", "n:0\nn:1\n");
+        assert_eq!(values(&tail_records(&mut reader, 10).unwrap(), "n"), ["0", "1"]);
+
+        let mut reader = fixture("tail_records_zero", "n:0\n");
+        assert!(tail_records(&mut reader, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tail_records_lenient() {
+        let content = "n:0\nn:1\n\nbroken\nn:2\n\n";
+        let mut reader = fixture("tail_records_strict", content);
+        match tail_records(&mut reader, 2) {
+            Err(Error::Parse(e)) => {
+                assert!(e.msg.starts_with("line 4: "), "{}", e.msg);
+                assert_eq!(e.line(), Some(4));
+            }
+            r => panic!("expected a parse error: {:?}", r),
+        }
+
+        let opts = ParseOptions { lenient: true, ..ParseOptions::default() };
+        let mut reader = fixture("tail_records_lenient", content);
+        assert_eq!(values(&tail_records_with(&mut reader, 2, &opts).unwrap(), "n"),
+                   ["1", "2"]);
+    }
+
+    #[test]
+    fn test_presence_count() {
+        let mut reader = fixture("presence_count",
+                                 "host:a\ttrace:1\nhost:b\n\nhost:c\ttrace:\nhost:d\n");
+        assert_eq!(presence_count(&mut reader, &"trace".to_string()).unwrap(), (2, 2));
+    }
+
+    fn numbered(name: &str, n: usize) -> LineReader {
+        let content = (0..n).map(|i| format!("n:{}\n", i)).collect::<String>();
+        fixture(name, &content)
+    }
+
+    #[test]
+    fn test_sample_records() {
+        let a = sample_records(&mut numbered("sample_records_a", 100), 10, Some(42)).unwrap();
+        let b = sample_records(&mut numbered("sample_records_b", 100), 10, Some(42)).unwrap();
+        assert_eq!(a.len(), 10);
+        assert_eq!(values(&a, "n"), values(&b, "n"));
+
+        let all = sample_records(&mut numbered("sample_records_small", 3), 10, None).unwrap();
+        assert_eq!(all.len(), 3);
+    }
+
+    #[test]
+    fn test_sample_fraction() {
+        let count = |p| {
+            let seen = Cell::new(0);
+            sample_fraction(&mut numbered("sample_fraction", 50),
+                            p,
+                            Some(7),
+                            |_| seen.set(seen.get() + 1))
+                .unwrap();
+            seen.get()
+        };
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(1.0), 50);
+        assert!(sample_fraction(&mut numbered("sample_fraction_bad", 1), 1.5, None, |_| {})
+            .is_err());
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut reader = fixture("histogram",
+                                 "reqtime:25\nreqtime:5\nreqtime:abc\nhost:a\nreqtime:15\n\
+                                  reqtime:19.9\n");
+        assert_eq!(histogram(&mut reader, &"reqtime".to_string(), 10.0).unwrap(),
+                   [(0.0, 1), (10.0, 2), (20.0, 1)]);
+
+        let mut reader = fixture("histogram_width", "reqtime:5\n");
+        assert!(histogram(&mut reader, &"reqtime".to_string(), 0.0).is_err());
+    }
+
+    #[test]
+    fn test_join() {
+        let left = "session_id:1\tplan:old\nsession_id:2\nsession_id:3\nuri:/\n";
+        let right = "session_id:1\tuser_id:a\tplan:free\nsession_id:2\tuser_id:b\n\
+                     session_id:2\tuser_id:c\tplan:pro\n";
+
+        let mut l = fixture("join_inner_left", left);
+        let mut r = fixture("join_inner_right", right);
+        let joined = join(&mut l, &mut r, "session_id", JoinKind::Inner, "")
+            .collect::<Result<Vec<Record>, Error>>()
+            .unwrap();
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[0]["user_id"], "a");
+        assert_eq!(joined[0]["plan"], "free");
+        assert_eq!(joined[1]["user_id"], "c");
+        assert_eq!(joined[1].len(), 3);
+
+        let mut l = fixture("join_outer_left", left);
+        let mut r = fixture("join_outer_right", right);
+        let joined = join(&mut l, &mut r, "session_id", JoinKind::LeftOuter, "s.")
+            .collect::<Result<Vec<Record>, Error>>()
+            .unwrap();
+        assert_eq!(joined.len(), 4);
+        assert_eq!(joined[0]["plan"], "old");
+        assert_eq!(joined[0]["s.plan"], "free");
+        assert!(!joined[0].contains_key("s.session_id"));
+        assert_eq!(joined[2].len(), 1);
+        assert_eq!(joined[3]["uri"], "/");
+    }
+
+    #[test]
+    fn test_diff_streams() {
+        let old = "id:1\thost:a\tstatus:200\nid:2\thost:b\nid:3\thost:c\nhost:d\nid:5\thost:e\n";
+        let new = "id:3\thost:c\tua:curl\nid:1\thost:a\tstatus:200\nid:4\thost:x\n\
+                   id:2\thost:B\nid:0\thost:y\npadding:..........\n";
+        let changed = |key: &str, added: &[&str], removed: &[&str], changed: &[(&str, &str)]| {
+            let strings = |labels: &[&str]| labels.iter().map(|l| l.to_string()).collect();
+            let changed = changed.iter()
+                .map(|&(old, new)| ("host".to_string(), old.to_string(), new.to_string()))
+                .collect();
+            let d = RecordDiff { added: strings(added), removed: strings(removed), changed };
+            KeyedDiff::Changed(key.to_string(), d)
+        };
+        let only = |left: bool, key: &str| {
+            if left {
+                KeyedDiff::LeftOnly(key.to_string())
+            } else {
+                KeyedDiff::RightOnly(key.to_string())
+            }
+        };
+
+        // The smaller left side is loaded and the right one streamed.
+        let mut l = fixture("diff_streams_left", old);
+        let mut r = fixture("diff_streams_right", new);
+        let diffs = diff_streams(&mut l, &mut r, "id").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(diffs,
+                   [changed("3", &["ua"], &[], &[]),
+                    only(false, "4"),
+                    changed("2", &[], &[], &[("b", "B")]),
+                    only(false, "0"),
+                    only(true, "5")]);
+
+        // The other way round, the right side is loaded.
+        let padded = format!("{}padding:{}\n", old, ".".repeat(100));
+        let mut l = fixture("diff_streams_left_big", &padded);
+        let mut r = fixture("diff_streams_right_small", new);
+        let diffs = diff_streams(&mut l, &mut r, "id").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(diffs,
+                   [changed("2", &[], &[], &[("b", "B")]),
+                    changed("3", &["ua"], &[], &[]),
+                    only(true, "5"),
+                    only(false, "0"),
+                    only(false, "4")]);
+
+        // A repeated key is compared by its last record on the loaded side,
+        // and for every record on the streamed one.
+        let left = "id:1\thost:x\nid:2\thost:b\nid:1\thost:a\n";
+        let right = "id:2\thost:b\nid:1\thost:a\nid:2\thost:c\nid:3\nid:3\n";
+        let mut l = fixture("diff_streams_repeated_left", left);
+        let mut r = fixture("diff_streams_repeated_right", right);
+        let diffs = diff_streams(&mut l, &mut r, "id").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(diffs,
+                   [changed("2", &[], &[], &[("b", "c")]), only(false, "3"), only(false, "3")]);
+        let padded = format!("{}padding:{}\n", left, ".".repeat(100));
+        let mut l = fixture("diff_streams_repeated_left_big", &padded);
+        let mut r = fixture("diff_streams_repeated_right_small", right);
+        let diffs = diff_streams(&mut l, &mut r, "id").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(diffs,
+                   [changed("1", &[], &[], &[("x", "a")]),
+                    changed("2", &[], &[], &[("b", "c")]),
+                    only(false, "3")]);
+
+        let mut l = fixture("diff_streams_same_left", old);
+        let mut r = fixture("diff_streams_same_right", old);
+        assert_eq!(diff_streams(&mut l, &mut r, "id").count(), 0);
+
+        let mut l = fixture("diff_streams_error_left", old);
+        let mut r = fixture("diff_streams_error_right", "id:1\nbroken\nid:2\n");
+        let diffs = diff_streams(&mut l, &mut r, "id").collect::<Vec<_>>();
+        assert!(diffs.len() == 1 && diffs[0].is_err());
+    }
+
+    #[test]
+    fn test_join_right_error() {
+        let mut l = fixture("join_error_left", "session_id:1\n");
+        let mut r = fixture("join_error_right", "broken\n");
+        let mut joined = join(&mut l, &mut r, "session_id", JoinKind::Inner, "");
+        assert!(joined.next().unwrap().is_err());
+        assert!(joined.next().is_none());
+    }
+
+    #[test]
+    fn test_open_path() {
+        let path = env::temp_dir().join(format!("rlq-test-{}-open_path.ltsv", process::id()));
+        File::create(&path).unwrap().write_all(b"host:a\n").unwrap();
+        let mut reader = open_path(path.clone()).unwrap();
+        assert!(parse_head(&mut reader).unwrap().contains_key("host"));
+        assert!(open_path(path.as_path()).is_ok());
+        assert!(open_path(path.with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        let inputs = ["t:1\th:a\nt:4\th:a\nt:4\th:a2\nt:10\th:a\n",
+                      "t:2\th:b\nt:4\th:b\nt:11\th:b\n",
+                      "t:0\th:c\nt:3\th:c\nt:12\th:c\n"];
+        let readers = inputs.iter()
+            .enumerate()
+            .map(|(i, content)| fixture(&format!("merge_sorted_{}", i), content))
+            .collect::<Vec<LineReader>>();
+        let merged = merge_sorted(readers, "t", SortKind::Numeric)
+            .collect::<Result<Vec<String>, Error>>()
+            .unwrap();
+
+        let mut expected = inputs.concat().lines().map(|l| l.to_string()).collect::<Vec<String>>();
+        expected.sort_by_key(|l| parse_record(l).unwrap()["t"].parse::<i64>().unwrap());
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_merge_sorted_missing_label() {
+        let readers = vec![fixture("merge_sorted_missing_0", "t:1\nt:5\n"),
+                           fixture("merge_sorted_missing_1", "t:2\nh:x\nt:3\n")];
+        let merged = merge_sorted(readers, "t", SortKind::String)
+            .collect::<Result<Vec<String>, Error>>()
+            .unwrap();
+        assert_eq!(merged, ["t:1", "t:2", "h:x", "t:3", "t:5"]);
+    }
+
+    #[test]
+    fn test_merge_sorted_time() {
+        let fmt = TimeFormat::Strftime("%d/%b/%Y:%H:%M:%S".to_string());
+        let readers = vec![fixture("merge_sorted_time_0",
+                                   "time:31/Dec/2016:23:00:00\ntime:01/Jan/2017:01:00:00\n"),
+                           fixture("merge_sorted_time_1", "time:01/Jan/2017:00:00:00\n")];
+        let merged = merge_sorted(readers, "time", SortKind::Time(fmt))
+            .collect::<Result<Vec<String>, Error>>()
+            .unwrap();
+        assert_eq!(merged,
+                   ["time:31/Dec/2016:23:00:00",
+                    "time:01/Jan/2017:00:00:00",
+                    "time:01/Jan/2017:01:00:00"]);
+    }
+
+    fn record(pairs: &[(&str, &str)]) -> Record {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn mapping(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_rename() {
+        let r = record(&[("remote_addr", "10.0.0.1"), ("status", "200")]);
+        assert_eq!(rename(&r, &mapping(&[("remote_addr", "ip"), ("missing", "x")])),
+                   record(&[("ip", "10.0.0.1"), ("status", "200")]));
+    }
+
+    #[test]
+    fn test_rename_collision() {
+        let r = record(&[("a", "1"), ("b", "2"), ("ip", "0")]);
+        assert_eq!(rename(&r, &mapping(&[("a", "ip"), ("b", "ip")])),
+                   record(&[("ip", "2")]));
+    }
+
+    #[test]
+    fn test_list_labels() {
+        let content = "host:a\tstatus:200\nhost:b\tstatus:200\tupstream_time:0.1\n\nhost:c\n";
+        let mut reader = fixture("list_labels", content);
+        assert_eq!(list_labels(&mut reader).unwrap(),
+                   [("host".to_string(), 3), ("status".to_string(), 2),
+                    ("upstream_time".to_string(), 1)]);
+
+        let mut reader = fixture("list_labels_limit", content);
+        assert_eq!(list_labels_limit(&mut reader, Some(1)).unwrap(),
+                   [("host".to_string(), 1), ("status".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_with_defaults() {
+        let r = record(&[("host", "a"), ("status", "200")]);
+        assert_eq!(with_defaults(&r, &mapping(&[("status", "-"), ("request_id", "-")])),
+                   record(&[("host", "a"), ("status", "200"), ("request_id", "-")]));
+    }
+
+    #[test]
+    fn test_rename_labels() {
+        let mut reader = fixture("rename_labels",
+                                 "host:a\treq_time:0.1\tstatus:200\n\nhost:b\tstatus:404\n");
+        let mut out = Vec::new();
+        let n = rename_labels(&mut reader,
+                              &mapping(&[("req_time", "reqtime")]),
+                              RenameCollision::Error,
+                              &mut out)
+            .unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "host:a\treqtime:0.1\tstatus:200\nhost:b\tstatus:404\n");
+    }
+
+    #[test]
+    fn test_rename_labels_chained() {
+        let mut reader = fixture("rename_labels_chained", "a:1\tb:2\tc:3\n");
+        let mut out = Vec::new();
+        rename_labels(&mut reader,
+                      &mapping(&[("a", "b"), ("b", "c"), ("c", "d")]),
+                      RenameCollision::Error,
+                      &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "b:1\tc:2\td:3\n");
+    }
+
+    #[test]
+    fn test_rename_labels_collision() {
+        let content = "reqtime:9\treq_time:0.1\thost:a\n";
+        let m = mapping(&[("req_time", "reqtime")]);
+
+        let mut reader = fixture("rename_labels_collision_error", content);
+        let mut out = Vec::new();
+        assert!(rename_labels(&mut reader, &m, RenameCollision::Error, &mut out).is_err());
+
+        let mut reader = fixture("rename_labels_collision_prefer", content);
+        let mut out = Vec::new();
+        rename_labels(&mut reader, &m, RenameCollision::PreferRenamed, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "reqtime:0.1\thost:a\n");
+    }
+
+    #[test]
+    fn test_fill_defaults() {
+        let input = "host:a\tstatus:200\nrequest_id:r1\thost:b\nhost:c\tzone:\n";
+        let defaults = [("request_id", "-"), ("zone", ""), ("app", "web")]
+            .iter()
+            .map(|&(l, v)| (l.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        let mut out = Vec::new();
+        let stats = fill_defaults(&mut fixture("fill_defaults", input), &defaults, &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "host:a\tstatus:200\tapp:web\trequest_id:-\tzone:\n\
+                    request_id:r1\thost:b\tapp:web\tzone:\n\
+                    host:c\tzone:\tapp:web\trequest_id:-\n");
+        assert_eq!(stats.total, 3);
+        let patched = stats.patched.iter().map(|(l, &n)| (l.as_str(), n)).collect::<Vec<_>>();
+        assert_eq!(patched, [("app", 3), ("request_id", 2), ("zone", 2)]);
+
+        // Normalize, then cut down to two labels in the same pass.
+        let mut out = Vec::new();
+        map_records(&mut fixture("fill_defaults_cut", input),
+                    |record| {
+                        fill_missing(record, &defaults);
+                        record.retain(|label, _| label == "host" || label == "request_id");
+                    },
+                    &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "host:a\trequest_id:-\nrequest_id:r1\thost:b\nhost:c\trequest_id:-\n");
+    }
+
+    #[test]
+    fn test_map_records() {
+        let mut reader = fixture("map_records", "host:a\tstatus:503\tua:x\nhost:b\tstatus:200\n");
+        let mut out = Vec::new();
+        let n = map_records(&mut reader,
+                            |record| {
+                                record.remove("ua");
+                                record.insert("z".to_string(), "1".to_string());
+                                record.insert("class".to_string(), "c".to_string());
+                            },
+                            &mut out)
+            .unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "host:a\tstatus:503\tclass:c\tz:1\nhost:b\tstatus:200\tclass:c\tz:1\n");
+    }
+
+    #[test]
+    fn test_add_derived() {
+        let mut reader = fixture("add_derived",
+                                 "size:1000\treqtime:2\tstatus:200\nsize:10\treqtime:0\n\
+                                  status:500\n");
+        let mut out = Vec::new();
+        add_derived(&mut reader,
+                    "bytes_per_sec",
+                    |record| {
+                        let size = record.get("size")?.parse::<f64>().ok()?;
+                        let reqtime = record.get("reqtime")?.parse::<f64>().ok()?;
+                        if reqtime > 0.0 { Some((size / reqtime).to_string()) } else { None }
+                    },
+                    &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "size:1000\treqtime:2\tstatus:200\tbytes_per_sec:500\nsize:10\treqtime:0\n\
+                    status:500\n");
+    }
+
+    #[test]
+    fn test_add_derived_refuses_tabs() {
+        let mut reader = fixture("add_derived_tabs", "host:a\n");
+        let mut out = Vec::new();
+        assert!(add_derived(&mut reader, "bad", |_| Some("a\tb".to_string()), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut reader = fixture("lint", "host:a\n\nbroken\nhost:b\thost:c\thost:d\nhost:e\tx\n\n");
+        assert_eq!(lint(&mut reader).unwrap(),
+                   LintReport {
+                       total: 6,
+                       blank: 2,
+                       errors: vec![(3,
+                                     "Parse error: invalid ltsv item: broken in \"broken\""
+                                         .to_string()),
+                                    (5,
+                                     "Parse error: invalid ltsv item: x in \"host:e\\tx\""
+                                         .to_string())],
+                       duplicate_labels: 2,
+                   });
+
+        let mut reader = fixture("lint_crlf", "host:a\r\n\r\nhost:b\thost:c\r\nbroken\r\n");
+        assert_eq!(lint(&mut reader).unwrap(),
+                   LintReport {
+                       total: 4,
+                       blank: 1,
+                       errors: vec![(4,
+                                     "Parse error: invalid ltsv item: broken in \"broken\""
+                                         .to_string())],
+                       duplicate_labels: 1,
+                   });
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let bytes: &'static [u8] = b"host:a\nhost:b\xff\xfec\nhost:\xe3\x81\nhost:d\n";
+        let reader = || LineReader::from_reader(io::Cursor::new(bytes));
+        match collect_records(&mut reader()) {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.msg, "line 2 is not valid UTF-8: invalid byte after 6 bytes")
+            }
+            r => panic!("expected a parse error: {:?}", r),
+        }
+
+        let lenient = ParseOptions { lenient: true, ..ParseOptions::default() };
+        assert_eq!(values(&collect_with(&mut reader(), &lenient).unwrap(), "host"), ["a", "d"]);
+
+        let lossy = ParseOptions { invalid_utf8: Utf8Policy::Lossy, ..ParseOptions::default() };
+        let records = collect_with(&mut reader(), &lossy).unwrap();
+        assert_eq!(values(&records, "host"), ["a", "b\u{fffd}\u{fffd}c", "\u{fffd}", "d"]);
+        let lines = order_by_with(&mut reader(), &"host".to_string(), &lossy).unwrap();
+        assert_eq!(lines[3], "host:\u{fffd}\n");
+
+        let report = lint(&mut reader()).unwrap();
+        assert_eq!(report.errors,
+                   [(2, "invalid UTF-8 after 6 bytes".to_string()),
+                    (3, "invalid UTF-8 after 5 bytes".to_string())]);
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let content = (0..20)
+            .map(|i| match i % 10 {
+                3 => format!("host:{}\n", i),
+                _ => format!("host:{}\tn:{}\n", i, i),
+            })
+            .collect::<String>();
+        let required = labels(&["host", "n"]);
+        let mut reader = fixture("check_schema", &content);
+        let report = check_schema(&mut reader, &required, SchemaMode::WarnMissing).unwrap();
+        assert_eq!(report.records, 20);
+        assert_eq!(report.missing.keys().collect::<Vec<_>>(), ["n"]);
+        assert_eq!(report.missing["n"].count, 2);
+        assert_eq!(report.missing["n"].examples, [(4, "host:3".to_string()),
+                                                   (14, "host:13".to_string())]);
+        assert!(report.unexpected.is_empty() && !report.conforms());
+
+        let mut reader = fixture("check_schema_require", &content);
+        match check_schema(&mut reader, &required, SchemaMode::RequireAll) {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.msg, "line 4: missing label n");
+                assert_eq!((e.line(), e.field()), (Some(4), Some("n")));
+            }
+            r => panic!("expected a parse error: {:?}", r),
+        }
+
+        let content = "host:a\tn:1\n\nn:2\thost:b\nhost:c\tn:3\tua:x\n";
+        let mut reader = fixture("check_schema_head", content);
+        let report = schema_from_head(&mut reader, SchemaMode::RequireAll).unwrap();
+        assert_eq!((report.required, report.records), (required.clone(), 3));
+        assert!(report.missing.is_empty());
+        assert_eq!(report.unexpected["ua"].examples, [(4, "host:c\tn:3\tua:x".to_string())]);
+
+        let mut reader = fixture("check_schema_ok", "n:1\thost:a\nhost:b\tn:2\n");
+        assert!(check_schema(&mut reader, &required, SchemaMode::RequireAll).unwrap().conforms());
+    }
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_to_tsv() {
+        let mut reader = fixture("to_tsv", "host:a\tstatus:200\tua:x\nstatus:404\n\nhost:c\n");
+        let rows = to_tsv(&mut reader, &labels(&["host", "status"])).unwrap();
+        assert_eq!(rows, ["host\tstatus", "a\t200", "\t404", "c\t"]);
+        assert!(rows.iter().all(|row| row.split('\t').count() == 2));
+    }
+
+    #[test]
+    fn test_drop_labels() {
+        let mut reader = fixture("drop_labels",
+                                 "host:a\tcookie:c\tstatus:200\tauthorization:x\nstatus:404\n");
+        let mut out = Vec::new();
+        let n = drop_labels(&mut reader, &labels(&["cookie", "authorization"]), &mut out).unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(String::from_utf8(out).unwrap(), "host:a\tstatus:200\nstatus:404\n");
+    }
+
+    #[test]
+    fn test_mask_labels() {
+        let content = "remote_addr:10.0.0.1\tstatus:200\nstatus:404\nremote_addr:10.0.0.1\n\
+                       remote_addr:10.0.0.2\n";
+        let mut reader = fixture("mask_labels", content);
+        let mut out = Vec::new();
+        mask_labels(&mut reader,
+                    &labels(&["remote_addr"]),
+                    &Mask::Replace("***".to_string()),
+                    &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "remote_addr:***\tstatus:200\nstatus:404\nremote_addr:***\nremote_addr:***\n");
+
+        let mut reader = fixture("mask_labels_hash", content);
+        let mut out = Vec::new();
+        mask_labels(&mut reader, &labels(&["remote_addr"]), &Mask::Hash, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], format!("remote_addr:{:016x}\tstatus:200", fnv1a(b"10.0.0.1")));
+        assert_eq!(lines[1], "status:404");
+        assert_eq!(lines[2], format!("remote_addr:{:016x}", fnv1a(b"10.0.0.1")));
+        assert!(lines[3] != lines[2]);
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut reader = fixture("write_csv",
+                                 "host:a\tua:Mozilla/5.0 (X11, \"Linux\")\n\nhost:b,c\nua:x\n");
+        let mut out = Vec::new();
+        write_csv(&mut reader, &labels(&["host", "ua"]), &mut out).unwrap();
+        assert_eq!(out,
+                   b"host,ua\na,\"Mozilla/5.0 (X11, \"\"Linux\"\")\"\n\"b,c\",\n,x\n".to_vec());
+    }
+
+    #[test]
+    fn test_output_closed() {
+        let content = (0..1_000).map(|i| format!("host:a\tn:{}\n", i)).collect::<String>();
+        let (pipe_out, mut pipe_in) = io::pipe().unwrap();
+        drop(pipe_out);
+        let mut reader = fixture("output_closed", &content);
+        match write_csv(&mut reader, &labels(&["n"]), &mut pipe_in) {
+            Err(Error::OutputClosed) => {}
+            r => panic!("expected the output to be closed: {:?}", r),
+        }
+        // Nothing was read past the header.
+        assert_eq!(reader.line_number, 0);
+
+        let mut reader = fixture("output_closed_ltsv", &content);
+        let names = [("n".to_string(), "i".to_string())].iter().cloned().collect();
+        match rename_labels(&mut reader, &names, RenameCollision::Error, &mut pipe_in) {
+            Err(Error::OutputClosed) => {}
+            r => panic!("expected the output to be closed: {:?}", r),
+        }
+        assert_eq!(reader.line_number, 1);
+
+        let mut reader = fixture("output_closed_each", &content);
+        match try_each_record(&mut reader, |r| Ok(writeln!(pipe_in, "{}", r["n"])?)) {
+            Err(Error::OutputClosed) => {}
+            r => panic!("expected the output to be closed: {:?}", r),
+        }
+        assert_eq!(reader.line_number, 1);
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut reader = fixture("to_csv", "host:a\tstatus:200\nstatus:404\n");
+        assert_eq!(to_csv(&mut reader, &labels(&["status", "host"])).unwrap(),
+                   ["status,host", "200,a", "404,"]);
+
+        let mut reader = fixture("to_csv_newline", "status:200\n");
+        assert_eq!(to_csv(&mut reader, &labels(&["status\r\ncode", "status"])).unwrap(),
+                   ["\"status\r\ncode\",status", ",200"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let content = "host:a\tstatus:200\n\
+                       host:b\tbroken\n\
+                       :x\tstatus:200\n\
+                       h\u{fc}st:c\tstatus:200\n\
+                       host:d\thost:e\tstatus:200\n\
+                       host:f\u{7}\tstatus:200\n\
+                       host:g\n\
+                       \n\
+                       host:h\tbroken\n\
+                       host:i\tstatus:200\r\n\
+                       \r\n";
+        let mut reader = fixture("validate", content);
+        let rules = ValidationRules { required: labels(&["status"]), max_examples: 1 };
+        let report = validate(&mut reader, &rules).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.lines, 11);
+        assert_eq!(report.missing_separator.count, 2);
+        assert_eq!(report.missing_separator.examples, [(2, "host:b\tbroken".to_string())]);
+        assert_eq!(report.empty_label.examples, [(3, ":x\tstatus:200".to_string())]);
+        assert_eq!(report.invalid_label.count, 1);
+        assert_eq!(report.invalid_label.examples[0].0, 4);
+        assert_eq!(report.duplicate_label.examples[0].0, 5);
+        assert_eq!(report.control_character.count, 1);
+        assert_eq!(report.control_character.examples[0].0, 6);
+        assert_eq!(report.missing_required.count, 3);
+        assert_eq!(report.missing_required.examples[0].0, 2);
+
+        let mut reader = fixture("validate_ok", "host:a\tstatus:200\r\n\r\n");
+        assert!(validate(&mut reader, &rules).unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_uniq_consecutive() {
+        let mut reader = fixture("uniq_consecutive", "s:a\ns:a\n\ns:a\ns:b\ns:a\n");
+        assert_eq!(uniq_consecutive(&mut reader).unwrap(),
+                   [("s:a".to_string(), 3), ("s:b".to_string(), 1), ("s:a".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_profile() {
+        let content = "status:200\tsize:10\tmethod:GET\ttime:10/Oct/2000:13:55:36 -0700\n\
+                       status:404\tsize:1.5\tmethod:GET\n\
+                       status:200\tsize:-\tmethod:GET\n\
+                       status:500\tmethod:GET\n";
+        let mut reader = fixture("profile", content);
+        let profiles = profile(&mut reader, &ProfileOptions::default()).unwrap();
+
+        let status = &profiles["status"];
+        assert_eq!(status.count, 4);
+        assert_eq!(status.fill_rate, 1.0);
+        assert_eq!(status.distinct, Distinct::Exact(3));
+        assert_eq!(status.value_type(), ValueType::Integer);
+        assert_eq!(status.examples, ["200", "404", "500"]);
+
+        let size = &profiles["size"];
+        assert_eq!(size.fill_rate, 0.75);
+        assert_eq!((size.min_len, size.max_len), (1, 3));
+        assert_eq!(size.value_type(), ValueType::String);
+
+        let method = &profiles["method"];
+        assert_eq!(method.distinct, Distinct::Exact(1));
+        assert_eq!(method.examples, ["GET"]);
+
+        assert_eq!(profiles["time"].value_type(), ValueType::Timestamp);
+    }
+
+    #[test]
+    fn test_profile_distinct_cap() {
+        let opts = ProfileOptions { distinct_cap: 10, ..ProfileOptions::default() };
+        let profiles = profile(&mut numbered("profile_cap", 50), &opts).unwrap();
+        assert_eq!(profiles["n"].distinct, Distinct::AtLeast(10));
+        assert_eq!(profiles["n"].value_type(), ValueType::Integer);
+        let profiles = profile(&mut numbered("profile_uncapped", 10), &opts).unwrap();
+        assert_eq!(profiles["n"].distinct, Distinct::Exact(10));
+    }
+
+    #[test]
+    fn test_crosstab() {
+        let content = "vhost:b\tstatus:500\nvhost:a\tstatus:200\nvhost:c\tstatus:404\n\
+                       vhost:a\tstatus:200\nvhost:b\tstatus:200\nvhost:c\tstatus:500\n\
+                       vhost:a\nstatus:404\n";
+        let (vhost, status) = ("vhost".to_string(), "status".to_string());
+        let mut reader = fixture("crosstab", content);
+        let table = crosstab(&mut reader, &vhost, &status, false).unwrap();
+        assert_eq!(table.rows, ["a", "b", "c"]);
+        assert_eq!(table.columns, ["200", "404", "500"]);
+        assert_eq!(table.counts, [[2, 0, 0], [1, 0, 1], [0, 1, 1]]);
+        assert_eq!(table.format_table(),
+                   "vhost\\status  200  404  500\n\
+                    a               2    0    0\n\
+                    b               1    0    1\n\
+                    c               0    1    1\n");
+        assert_eq!(table.to_csv()[0], "vhost\\status,200,404,500");
+        assert_eq!(table.to_csv()[2], "b,1,0,1");
+
+        let mut reader = fixture("crosstab_missing", content);
+        let table = crosstab(&mut reader, &vhost, &status, true).unwrap();
+        assert_eq!(table.rows, ["a", "b", "c", MISSING]);
+        assert_eq!(table.columns, ["200", "404", "500", MISSING]);
+        assert_eq!(table.counts[0], [2, 0, 0, 1]);
+        assert_eq!(table.counts[3], [0, 1, 0, 0]);
+
+        let mut reader = fixture("crosstab_newline", content);
+        let table = crosstab(&mut reader, &"vhost\nname".to_string(), &status, true).unwrap();
+        assert_eq!(table.to_csv(), ["\"vhost\nname\\status\",200,404,500,(missing)",
+                                    "(missing),3,2,2,1"]);
+    }
+
+    #[test]
+    fn test_skip_comments() {
+        let content = "# schema: status\n# exported 2017-05-07\n#\nstatus:200\nstatus:404\n\
+                       # note\nstatus:200\tpath:/#top\n";
+        let prefix = Some("#".to_string());
+        let opts = ParseOptions { comment_prefix: prefix, ..ParseOptions::default() };
+        let status = "status".to_string();
+
+        let head = parse_head_with(&mut fixture("skip_comments_head", content), &opts).unwrap();
+        assert_eq!(head["status"], "200");
+
+        let seen = Cell::new(0);
+        each_record_with(&mut fixture("skip_comments_each", content), &opts, |record| {
+                assert!(record.contains_key("status"));
+                seen.set(seen.get() + 1);
+            })
+            .unwrap();
+        assert_eq!(seen.get(), 3);
+
+        let group = group_by_with(&mut fixture("skip_comments_group", content), &status, &opts)
+            .unwrap();
+        assert_eq!(group.len(), 2);
+        assert_eq!(group["200"], 2);
+
+        let lines = order_by_with(&mut fixture("skip_comments_order", content), &status, &opts)
+            .unwrap();
+        assert_eq!(lines, ["status:200\n", "status:200\tpath:/#top\n", "status:404\n"]);
+
+        let mut reader = fixture("skip_comments_value", "path:#top\n");
+        let records = head_records_with(&mut reader, 1, &opts).unwrap();
+        assert_eq!(records[0]["path"], "#top");
+
+        // Indented lines are not comments.
+        let mut reader = fixture("skip_comments_indented", " # note\nstatus:200\n");
+        assert!(head_records_with(&mut reader, 2, &opts).is_err());
+
+        // Comments are opt-in: by default `#` is an ordinary label character.
+        let group = group_by(&mut fixture("skip_comments_off", "#tag:x\n"), &"#tag".to_string())
+            .unwrap();
+        assert_eq!(group["x"], 1);
+    }
+
+    #[test]
+    fn test_filter_len() {
+        let mut reader = fixture("filter_len",
+                                 "ua:curl\nua:\u{3042}\u{3044}\u{3046}\nhost:a\nua:Mozilla/5.0\n");
+        let matched = RefCell::new(Vec::new());
+        filter_len(&mut reader, &"ua".to_string(), CmpOp::Gt, 4, |record| {
+                matched.borrow_mut().push(record["ua"].clone())
+            })
+            .unwrap();
+        assert_eq!(*matched.borrow(), ["Mozilla/5.0"]);
+
+        let mut reader = fixture("filter_len_chars", "ua:\u{3042}\u{3044}\u{3046}\n");
+        let seen = Cell::new(0);
+        filter_len(&mut reader, &"ua".to_string(), CmpOp::Eq, 3, |_| seen.set(seen.get() + 1))
+            .unwrap();
+        assert_eq!(seen.get(), 1);
+    }
+
+    #[test]
+    fn test_rates() {
+        let content = "t:100\tstatus:200\nt:119\tstatus:200\nt:61\tstatus:500\nt:125\tstatus:200\n\
+                       t:179\tstatus:200\nt:x\tstatus:200\nt:62\n";
+        let mut reader = fixture("rates", content);
+        let points = rates(&mut reader,
+                           &"t".to_string(),
+                           60,
+                           &TimeFormat::Epoch,
+                           Some(&"status".to_string()))
+            .unwrap();
+        let points = points.iter()
+            .map(|p| (p.start, p.group.as_str(), p.count, p.rate))
+            .collect::<Vec<_>>();
+        assert_eq!(points,
+                   [(60, "200", 2, 2.0 / 60.0),
+                    (60, "500", 1, 1.0 / 60.0),
+                    (60, MISSING, 1, 1.0 / 60.0),
+                    (120, "200", 2, 2.0 / 60.0)]);
+
+        let mut reader = fixture("rates_ungrouped", content);
+        let points = rates(&mut reader, &"t".to_string(), 100, &TimeFormat::Epoch, None).unwrap();
+        assert_eq!(points.iter().map(|p| (p.start, p.count)).collect::<Vec<_>>(),
+                   [(0, 2), (100, 4)]);
+        assert_eq!(points[1].rate, 0.04);
+    }
+
+    #[test]
+    fn test_collect_records() {
+        let mut reader = fixture("collect_records", "n:0\n\nn:1\thost:a\nn:2\n");
+        let records = collect_records(&mut reader).unwrap();
+        assert_eq!(values(&records, "n"), ["0", "1", "2"]);
+        assert_eq!(records[1]["host"], "a");
+
+        let mut reader = fixture("collect_records_error", "n:0\nbroken\n");
+        assert!(collect_records(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_capacity_hints() {
+        let content = (0..1_000).map(|i| format!("n:{}\tm:{}\n", i % 97, i)).collect::<String>();
+        let hinted = ParseOptions {
+            hint_groups: 4096,
+            hint_records: 10,
+            ..ParseOptions::default()
+        };
+        let n = "n".to_string();
+        assert_eq!(group_by_with(&mut fixture("hint_groups", &content), &n, &hinted).unwrap(),
+                   group_by(&mut fixture("hint_groups_default", &content), &n).unwrap());
+        assert_eq!(collect_records_with(&mut fixture("hint_records", &content), &hinted).unwrap(),
+                   collect_records(&mut fixture("hint_records_default", &content)).unwrap());
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        let opts = ParseOptions { escape: true, ..ParseOptions::default() };
+        let original = record(&[("json", "{\"a\":\t1}\n"), ("path", "C:\\dir\\"), ("cr", "a\rb")]);
+        let line = serialize_record(&original, &opts).unwrap();
+        assert_eq!(line, "cr:a\\rb\tjson:{\"a\":\\t1}\\n\tpath:C:\\\\dir\\\\");
+        assert!(serialize_record(&original, &ParseOptions::default()).is_err());
+
+        let mut reader = fixture("escape_round_trip", &format!("{}\n", line));
+        let parsed = head_records_with(&mut reader, 1, &opts).unwrap();
+        assert_eq!(parsed[0], original);
+    }
+
+    #[test]
+    fn test_escape_parse() {
+        let opts = ParseOptions { escape: true, ..ParseOptions::default() };
+        let content = "ua:a\\tb\tpath:\\x\\\nua:a\\tb\n";
+        let mut reader = fixture("escape_parse", content);
+        let records = collect_records(&mut reader).unwrap();
+        assert_eq!(records[0]["ua"], "a\\tb");
+
+        let mut reader = fixture("escape_parse_on", content);
+        let records = head_records_with(&mut reader, 2, &opts).unwrap();
+        assert_eq!(records[0]["ua"], "a\tb");
+        assert_eq!(records[0]["path"], "\\x\\");
+
+        let mut reader = fixture("escape_group_by", content);
+        let group = group_by_with(&mut reader, &"ua".to_string(), &opts).unwrap();
+        assert_eq!(group["a\tb"], 2);
+
+        let mut reader = fixture("escape_map_records", content);
+        let mut out = Vec::new();
+        map_records_with(&mut reader, &opts, |_| {}, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "ua:a\\tb\tpath:\\\\x\\\\\nua:a\\tb\n");
+    }
+
+    #[test]
+    fn test_kv_separator() {
+        let opts = ParseOptions { kv_separator: '=', ..ParseOptions::default() };
+        let content = "host=a\ttime=12:00:01\nhost=b\ttime=12:00:00\nhost=a\ttime=11:59:59\n";
+        let mut reader = fixture("kv_separator_head", content);
+        let head = parse_head_with(&mut reader, &opts).unwrap();
+        assert_eq!(head["time"], "12:00:01");
+
+        let mut reader = fixture("kv_separator_records", content);
+        let records = head_records_with(&mut reader, 3, &opts).unwrap();
+        assert_eq!(values(&records, "time"), ["12:00:01", "12:00:00", "11:59:59"]);
+
+        let mut reader = fixture("kv_separator_group_by", content);
+        let group = group_by_with(&mut reader, &"host".to_string(), &opts).unwrap();
+        assert_eq!(group["a"], 2);
+
+        let mut reader = fixture("kv_separator_order_by", content);
+        let lines = order_by_with(&mut reader, &"time".to_string(), &opts).unwrap();
+        assert_eq!(lines[0], "host=a\ttime=11:59:59\n");
+
+        let line = serialize_record(&records[0], &opts).unwrap();
+        assert_eq!(line, "host=a\ttime=12:00:01");
+        assert!(serialize_record(&record(&[("a=b", "c")]), &opts).is_err());
+    }
+
+    #[test]
+    fn test_kv_separator_missing() {
+        let opts = ParseOptions { kv_separator: '=', ..ParseOptions::default() };
+        let content = "host=a\nhost:b\nhost=c\n";
+        let mut reader = fixture("kv_separator_strict", content);
+        assert!(head_records_with(&mut reader, 3, &opts).is_err());
+
+        let lenient = ParseOptions { lenient: true, ..opts };
+        let mut reader = fixture("kv_separator_lenient", content);
+        let records = head_records_with(&mut reader, 3, &lenient).unwrap();
+        assert_eq!(values(&records, "host"), ["a", "c"]);
+    }
+
+    #[test]
+    fn test_order_by_malformed() {
+        let content = "t:2\tn:0\nbroken\nt:1\tn:1\nn:2\nt:2\tn:3\nalso broken\nt:1\tn:4\n";
+        let mut reader = fixture("order_by_malformed", content);
+        let lines = order_by(&mut reader, &"t".to_string()).unwrap();
+        assert_eq!(lines,
+                   ["n:2\n", "t:1\tn:1\n", "t:1\tn:4\n", "t:2\tn:0\n", "t:2\tn:3\n",
+                    "broken\n", "also broken\n"]);
+    }
+
+    #[test]
+    fn test_index_by() {
+        let content = "id:1\thost:a\nhost:none\nid:2\thost:b\nid:1\thost:c\n";
+        let index = index_by(&mut fixture("index_by", content), &"id".to_string()).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["1"]["host"], "c");
+        assert_eq!(index["2"], record(&[("id", "2"), ("host", "b")]));
+    }
+
+    #[test]
+    fn test_bom() {
+        let content = "\u{feff}host:a\tstatus:200\nhost:\u{feff}b\n\u{feff}host:c\n";
+        let mut reader = fixture("bom", content);
+        let head = parse_head(&mut reader).unwrap();
+        assert_eq!(head, record(&[("host", "a"), ("status", "200")]));
+
+        let group = group_by(&mut fixture("bom_group_by", content), &"host".to_string()).unwrap();
+        assert_eq!(group["a"], 1);
+        assert_eq!(group["\u{feff}b"], 1);
+        assert!(!group.contains_key("c"));
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let cursor = io::Cursor::new(b"\xef\xbb\xbfhost:a\nhost:b\nhost:a\n".to_vec());
+        let mut reader = LineReader::from_reader(cursor);
+        let group = group_by(&mut reader, &"host".to_string()).unwrap();
+        assert_eq!(group["a"], 2);
+        assert_eq!(group["b"], 1);
+
+        let mut reader = LineReader::from_reader(io::Cursor::new("n:0\nn:1\n"));
+        assert_eq!(values(&collect_records(&mut reader).unwrap(), "n"), ["0", "1"]);
+
+        let bytes: &'static [u8] = b"host:a\tstatus:200\nhost:b\nstatus:404\nhost:a\n";
+        let mut from_memory = LineReader::from_reader(io::Cursor::new(bytes));
+        let mut from_file = fixture("from_reader_file", ::std::str::from_utf8(bytes).unwrap());
+        assert_eq!(group_by(&mut from_memory, &"host".to_string()).unwrap(),
+                   group_by(&mut from_file, &"host".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_map_field() {
+        let original = record(&[("host", "Example.com"), ("method", "get")]);
+        let mapped = map_field(&original, &"method".to_string(), |v| v.to_uppercase());
+        assert_eq!(mapped, record(&[("host", "Example.com"), ("method", "GET")]));
+        assert_eq!(map_field(&original, &"path".to_string(), |_| "x".to_string()), original);
+    }
+
+    #[test]
+    fn test_each_record_json() {
+        let content = "{\"host\":\"a\",\"size\":1.0}\n\n{\"host\":\"b\",\"user\":null}\n";
+        let seen = RefCell::new(Vec::new());
+        each_record_json(&mut fixture("each_record_json", content),
+                         |record| seen.borrow_mut().push(record.clone()))
+            .unwrap();
+        let seen = seen.into_inner();
+        assert_eq!(seen, [record(&[("host", "a"), ("size", "1.0")]), record(&[("host", "b")])]);
+
+        let mut out = Vec::new();
+        let mut reader = fixture("json_to_ltsv", content);
+        assert_eq!(json_to_ltsv(&mut reader, &JsonOptions::default(), &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap(), "host:a\tsize:1.0\nhost:b\n");
+
+        let mut reader = fixture("each_record_json_error", "host:a\n");
+        assert!(each_record_json(&mut reader, |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_sum_mean() {
+        let content = "size:10\nsize:2.5\thost:a\nhost:b\nsize:-0.5\n";
+        let size = "size".to_string();
+        assert_eq!(sum(&mut fixture("sum", content), &size).unwrap(), 12.0);
+        assert_eq!(mean(&mut fixture("mean", content), &size).unwrap(), 4.0);
+        assert!(mean(&mut fixture("mean_empty", "host:a\n"), &size).unwrap().is_nan());
+        assert_eq!(sum(&mut fixture("sum_empty", ""), &size).unwrap(), 0.0);
+
+        let mut reader = fixture("sum_invalid", "size:1\nsize:-\n");
+        assert!(sum(&mut reader, &size).is_err());
+    }
+
+    #[test]
+    fn test_each_record_combined() {
+        let content = "::1 - - [07/May/2017:10:00:00 +0900] \"GET / HTTP/1.1\" 200 5 \"-\" \"\"\n\
+                       ::1 - - [07/May/2017:10:00:01 +0900] \"GET /x HTTP/1.1\" 404 - \"-\" \"\"\n";
+        let statuses = RefCell::new(Vec::new());
+        each_record_combined(&mut fixture("each_record_combined", content), |record| {
+                statuses.borrow_mut().push(record["status"].clone());
+            })
+            .unwrap();
+        assert_eq!(statuses.into_inner(), ["200", "404"]);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let expected = record(&[("host", "a"), ("status", "200")]);
+        assert_eq!(parse_line("host:a\tstatus:200").unwrap(), expected);
+        assert_eq!(parse_line("host:a\tstatus:200\n").unwrap(), expected);
+        assert_eq!(parse_line("host:a\tstatus:200\r\n").unwrap(), expected);
+        assert_eq!(parse_line("time:12:00:00").unwrap()["time"], "12:00:00");
+
+        for line in &["host:a\tbroken", "broken:x\tbroken"] {
+            let err = parse_line(line).unwrap_err();
+            let mut reader = fixture("parse_line_error", &format!("n:0\n{}\n", line));
+            let seen = each_record(&mut reader, |_| {}).unwrap_err();
+            assert_eq!(format!("{}", seen),
+                       format!("{}", err).replace("Parse error: ", "Parse error: line 2: "));
+            assert!(format!("{}", err).contains("invalid ltsv item: broken in "));
+        }
+    }
+
+    #[test]
+    fn test_crlf() {
+        let mut reader = fixture("crlf", "host:a\r\nhost:b\r\n");
+        assert_eq!(values(&collect_records(&mut reader).unwrap(), "host"), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_order_records_by() {
+        let content = "t:b\tn:0\nn:1\nt:a\tn:2\nt:b\tn:3\nt:\tn:4\n";
+        let records = order_records_by(&mut fixture("order_records_by", content),
+                                       &"t".to_string())
+            .unwrap();
+        assert_eq!(values(&records, "n"), ["4", "2", "0", "3", "1"]);
+    }
+
+    #[test]
+    fn test_bom_each_record_order_by() {
+        let content = "\u{feff}t:2\nt:1\n\u{feff}t:0\n";
+        let seen = Cell::new(0);
+        each_record(&mut fixture("bom_each_record", content), |record| {
+                if record.contains_key("t") {
+                    seen.set(seen.get() + 1);
+                }
+            })
+            .unwrap();
+        assert_eq!(seen.get(), 2);
+
+        let lines = order_by(&mut fixture("bom_order_by", content), &"t".to_string()).unwrap();
+        assert_eq!(lines, ["\u{feff}t:0\n", "t:1\n", "t:2\n"]);
+    }
+
+    #[test]
+    fn test_group_by_detailed() {
+        let content = "s:200\ns:200\nx:1\ns:404\n\ns:200\nx:2\ns:500\n\nx:3\ns:404\nbroken\n\
+                       s:200\n";
+        let s = "s".to_string();
+        let opts = ParseOptions { lenient: true, ..ParseOptions::default() };
+        let result = group_by_detailed(&mut fixture("group_by_detailed", content), &s, &opts)
+            .unwrap();
+        assert_eq!(result.total_records, 10);
+        assert_eq!(result.missing, 3);
+        assert_eq!(result.counts.values().sum::<i32>(), 7);
+        assert_eq!(result.counts["200"], 4);
+
+        let mut reader = fixture("group_by_detailed_strict", content);
+        assert!(group_by_detailed(&mut reader, &s, &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_blank_policy() {
+        let content = "n:0\n\nn:1\n\n\nn:2\n";
+        let mut reader = fixture("blank_skip", content);
+        assert_eq!(collect_records(&mut reader).unwrap().len(), 3);
+        assert_eq!(reader.blank_lines(), 0);
+
+        let opts = ParseOptions { blank_lines: BlankPolicy::Count, ..ParseOptions::default() };
+        let mut reader = fixture("blank_count", content);
+        assert_eq!(head_records_with(&mut reader, 10, &opts).unwrap().len(), 3);
+        assert_eq!(reader.blank_lines(), 3);
+        assert_eq!(reader.line_number(), 6);
+
+        let opts = ParseOptions { blank_lines: BlankPolicy::Error, ..ParseOptions::default() };
+        let err = head_records_with(&mut fixture("blank_error", content), 10, &opts).unwrap_err();
+        assert_eq!(format!("{}", err), "Parse error: blank line at line 2");
+        let mut reader = fixture("blank_error_none", "n:0\nn:1\n");
+        assert_eq!(head_records_with(&mut reader, 10, &opts).unwrap().len(), 2);
+
+        let (n, content) = ("n".to_string(), "n:2\n\nn:1\n\n\nn:0\n");
+        let lines = order_by(&mut fixture("blank_order_by", content), &n).unwrap();
+        assert_eq!(lines, ["n:0\n", "n:1\n", "n:2\n"]);
+        let opts = ParseOptions { blank_lines: BlankPolicy::Count, ..ParseOptions::default() };
+        let mut reader = fixture("blank_order_by_count", content);
+        assert_eq!(order_by_with(&mut reader, &n, &opts).unwrap().len(), 3);
+        assert_eq!(reader.blank_lines(), 3);
+        assert_eq!(reader.records, 3);
+        let opts = ParseOptions { blank_lines: BlankPolicy::Error, ..ParseOptions::default() };
+        let err = order_by_with(&mut fixture("blank_order_by_error", content), &n, &opts);
+        assert_eq!(format!("{}", err.unwrap_err()), "Parse error: blank line at line 2");
+    }
+
+    #[test]
+    fn test_group_with_percent() {
+        let mut group = FieldGroupCount::default();
+        group.insert("404".to_string(), 1);
+        group.insert("200".to_string(), 6);
+        group.insert("500".to_string(), 1);
+        assert_eq!(group_total(&group), 8);
+        assert_eq!(group_with_percent(&group),
+                   [("200".to_string(), 6, 75.0),
+                    ("404".to_string(), 1, 12.5),
+                    ("500".to_string(), 1, 12.5)]);
+        assert_eq!(group_with_percent(&group).iter().map(|row| row.2).sum::<f64>(), 100.0);
+
+        let mut single = FieldGroupCount::default();
+        single.insert("200".to_string(), 3);
+        assert_eq!(group_with_percent(&single), [("200".to_string(), 3, 100.0)]);
+
+        assert!(group_with_percent(&FieldGroupCount::default()).is_empty());
+        assert_eq!(group_total(&FieldGroupCount::default()), 0);
+    }
+
+    #[test]
+    fn test_filter_has() {
+        let content = "n:0\ttrace_id:a\nn:1\nn:2\ttrace_id:\nn:3\n";
+        let seen = RefCell::new(Vec::new());
+        filter_has(&mut fixture("filter_has", content), &"trace_id".to_string(), |record| {
+                seen.borrow_mut().push(record["n"].clone());
+            })
+            .unwrap();
+        assert_eq!(seen.into_inner(), ["0", "2"]);
+    }
+
+    #[test]
+    fn test_filter_cidr() {
+        let content = "n:0\taddr:10.1.2.3\nn:1\taddr:203.0.113.9\nn:2\taddr:::ffff:192.168.0.1\n\
+                       n:3\taddr:[2001:db8::1]\nn:4\taddr:-\nn:5\n";
+        let addr = "addr".to_string();
+        let private = &["10.0.0.0/8", "192.168.0.0/16"];
+        let run = |name, cidrs: &[&str], include, unparsed| {
+            let seen = RefCell::new(Vec::new());
+            filter_cidr(&mut fixture(name, content), &addr, cidrs, include, unparsed, |r| {
+                    seen.borrow_mut().push(r["n"].clone());
+                })
+                .map(|_| seen.into_inner())
+        };
+        assert_eq!(run("filter_cidr_in", private, true, UnparsedAddr::Skip).unwrap(),
+                   ["0", "2"]);
+        assert_eq!(run("filter_cidr_out", private, false, UnparsedAddr::Keep).unwrap(),
+                   ["1", "3", "4"]);
+        assert_eq!(run("filter_cidr_v6", &["2001:db8::/32"], true, UnparsedAddr::Skip).unwrap(),
+                   ["3"]);
+        match run("filter_cidr_error", private, false, UnparsedAddr::Error) {
+            Err(Error::Parse(e)) => assert!(e.msg.starts_with("line 5: "), "{}", e.msg),
+            r => panic!("expected a parse error: {:?}", r),
+        }
+        match run("filter_cidr_invalid", &["10.0.0.0/33"], true, UnparsedAddr::Skip) {
+            Err(Error::InvalidArgument(_)) => {}
+            r => panic!("expected an invalid argument error: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("img2.png", "img10.png"), Ordering::Less);
+        assert_eq!(natural_cmp("v1.9", "v1.10"), Ordering::Less);
+        assert_eq!(natural_cmp("v1.10", "v1.10a"), Ordering::Less);
+        assert_eq!(natural_cmp("10", "9"), Ordering::Greater);
+        assert_eq!(natural_cmp("99999999999999999999999", "100000000000000000000000"),
+                   Ordering::Less);
+        assert_eq!(natural_cmp("007", "7"), Ordering::Equal);
+        assert_eq!(natural_cmp("版2", "版10"), Ordering::Less);
+        assert_eq!(natural_cmp("ä1", "b1"), Ordering::Greater);
+        assert_eq!(natural_cmp("a", "1"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_order_by_time() {
+        let content = "t:10/Oct/2000:13:55:36 -0700\tn:0\n\
+                       t:yesterday\tn:1\n\
+                       t:09/Oct/2000:23:00:00 +0000\tn:2\n\
+                       n:3\n\
+                       broken\n\
+                       t:10/Oct/2000:22:00:00 +0200\tn:4\n\
+                       t:01/Jan/2001:00:00:00 +0000\tn:5\n";
+        let lines = order_by_time(&mut fixture("order_by_time", content),
+                                  &"t".to_string(),
+                                  "%d/%b/%Y:%H:%M:%S %z")
+            .unwrap();
+        let order = lines.iter()
+            .map(|line| parse_line(line).map(|r| r["n"].clone()).unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(order, ["2", "4", "0", "5", "1", "3", ""]);
+    }
+
+    #[test]
+    fn test_order_by_natural() {
+        let content = "v:img10\tn:0\nv:img2\tn:1\nv:img007\tn:2\nv:img7\tn:3\nn:4\nbroken\n";
+        let lines = order_by_kind(&mut fixture("order_by_natural", content),
+                                  &"v".to_string(),
+                                  &SortKind::Natural)
+            .unwrap();
+        assert_eq!(lines,
+                   ["v:img2\tn:1\n", "v:img007\tn:2\n", "v:img7\tn:3\n", "v:img10\tn:0\n",
+                    "n:4\n", "broken\n"]);
+    }
+
+    #[test]
+    fn test_csv_converter() {
+        let converter = CsvConverter::new(&labels(&["host", "ua"]));
+        let mut out = Vec::new();
+        for (name, content) in &[("csv_converter_a", "host:a\tua:x, y\n"),
+                                 ("csv_converter_b", "ua:z\n")] {
+            converter.write_file(&fixture_path(name, content), &mut out).unwrap();
+        }
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "host,ua\na,\"x, y\"\nhost,ua\n,z\n");
+        assert!(converter.write_file("/nonexistent/rlq.ltsv", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_key_normalize() {
+        let content = "m:GET\tn:0\nm:get\tn:1\nm:POST\tn:2\nm:GET\tn:3\nm:Get\tn:4\nm:Éa\tn:5\n\
+                       m:éb\tn:6\n";
+        let m = "m".to_string();
+        let opts = ParseOptions { normalize: KeyNormalize::AsciiCase, ..ParseOptions::default() };
+        let group = group_by_with(&mut fixture("normalize_group_by", content), &m, &opts).unwrap();
+        assert_eq!(group.len(), 4);
+        assert_eq!(group["GET"], 4);
+        assert_eq!(group["POST"], 1);
+
+        let group = group_by(&mut fixture("normalize_exact", content), &m).unwrap();
+        assert_eq!(group.len(), 6);
+
+        let opts = ParseOptions { normalize: KeyNormalize::UnicodeCase, ..ParseOptions::default() };
+        let lines = order_by_with(&mut fixture("normalize_order_by", content), &m, &opts).unwrap();
+        let order = lines.iter().map(|line| line.trim_end().rsplit(':').next().unwrap());
+        assert_eq!(order.collect::<Vec<_>>(), ["0", "1", "3", "4", "2", "5", "6"]);
+    }
+
+    #[test]
+    fn test_first_last_record() {
+        let content = "\nn:0\nn:1\nn:2\n\n";
+        let mut reader = fixture("first_record", content);
+        assert_eq!(first_record(&mut reader).unwrap(), Some(record(&[("n", "0")])));
+        assert_eq!(first_record(&mut reader).unwrap(), Some(record(&[("n", "1")])));
+
+        let mut reader = fixture("last_record", content);
+        assert_eq!(last_record(&mut reader).unwrap(), Some(record(&[("n", "2")])));
+
+        assert_eq!(first_record(&mut fixture("first_record_empty", "\n")).unwrap(), None);
+        assert_eq!(last_record(&mut fixture("last_record_empty", "")).unwrap(), None);
+        assert!(last_record(&mut fixture("last_record_error", "n:0\nbroken\n")).is_err());
+    }
+
+    #[test]
+    fn test_dedup_by() {
+        let content = "s:a\tn:0\ns:b\tn:1\nn:2\ns:a\tn:3\ns:c\tn:4\ns:b\tn:5\nn:6\n";
+        let s = "s".to_string();
+
+        let mut out = Vec::new();
+        let stats = dedup_by(&mut fixture("dedup_first", content), &s, Keep::First, &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "s:a\tn:0\ns:b\tn:1\nn:2\ns:c\tn:4\nn:6\n");
+        assert_eq!(stats, DedupStats { total: 7, emitted: 5, dropped: 2 });
+
+        let mut out = Vec::new();
+        let stats = dedup_by(&mut fixture("dedup_last", content), &s, Keep::Last, &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "n:2\ns:a\tn:3\ns:c\tn:4\ns:b\tn:5\nn:6\n");
+        assert_eq!(stats, DedupStats { total: 7, emitted: 5, dropped: 2 });
+
+        let mut out = Vec::new();
+        let mut reader = fixture("dedup_drop_missing", content);
+        let stats = dedup_by_with(&mut reader, &s, Keep::Last, false, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "s:a\tn:3\ns:c\tn:4\ns:b\tn:5\n");
+        assert_eq!(stats, DedupStats { total: 7, emitted: 3, dropped: 4 });
+    }
+
+    #[test]
+    fn test_distinct_count_approx() {
+        let content = (0..2000).map(|i| format!("addr:10.0.{}.{}\n", i % 500 / 100, i % 100))
+            .collect::<String>();
+        let addr = "addr".to_string();
+        let approx = distinct_count_approx(&mut fixture("distinct_approx", &content), &addr, 12)
+            .unwrap();
+        assert!((480..=520).contains(&approx), "{}", approx);
+
+        let mut a = distinct_sketch(&mut fixture("distinct_sketch_a", "addr:a\naddr:b\n"), &addr, 8)
+            .unwrap();
+        let b = distinct_sketch(&mut fixture("distinct_sketch_b", "addr:b\naddr:c\n"), &addr, 8)
+            .unwrap();
+        a.merge(&b).unwrap();
+        assert_eq!(a.estimate(), 3);
+
+        assert!(distinct_count_approx(&mut fixture("distinct_approx_invalid", ""), &addr, 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_parsed() {
+        let r = record(&[("status", "200"), ("reqtime", "0.25"), ("ok", "true"), ("size", "-")]);
+        assert_eq!(get_parsed::<i32>(&r, "status"), Some(200));
+        assert_eq!(get_parsed::<f64>(&r, "reqtime"), Some(0.25));
+        assert_eq!(get_parsed::<bool>(&r, "ok"), Some(true));
+        assert_eq!(get_parsed::<u64>(&r, "size"), None);
+        assert_eq!(get_parsed::<i32>(&r, "missing"), None);
+    }
+
+    #[test]
+    fn test_record_ext() {
+        let r = record(&[("host", "a"), ("status", "200"), ("reqtime", "0.25"), ("ok", "Yes"),
+                         ("cached", "0"), ("size", "-"), ("time", "2000-10-10T13:55:36Z")]);
+        assert_eq!(r.get_str("host"), Some("a"));
+        assert_eq!(r.get_str("missing"), None);
+        assert_eq!(r.get_parsed::<i32>("status").unwrap(), Some(200));
+        assert_eq!(r.get_parsed::<i32>("missing").unwrap(), None);
+        assert_eq!(r.get_f64("reqtime").unwrap(), Some(0.25));
+        assert_eq!(r.get_f64("missing").unwrap(), None);
+        assert_eq!(r.get_u64("status").unwrap(), Some(200));
+        assert_eq!(r.get_u64("missing").unwrap(), None);
+        assert_eq!(r.get_bool("ok").unwrap(), Some(true));
+        assert_eq!(r.get_bool("cached").unwrap(), Some(false));
+        assert_eq!(r.get_bool("missing").unwrap(), None);
+        let iso = TimeFormat::Strftime("%FT%TZ".to_string());
+        assert_eq!(r.get_time("time", &iso).unwrap(), Some(971186136));
+        assert_eq!(r.get_time("missing", &iso).unwrap(), None);
+
+        let err = r.get_u64("size").unwrap_err();
+        assert_eq!(err.msg, "invalid value for size: \"-\"");
+        assert_eq!(err.field(), Some("size"));
+        assert!(r.get_f64("host").is_err());
+        assert!(r.get_parsed::<u8>("status").is_ok() && r.get_parsed::<i8>("status").is_err());
+        assert!(r.get_bool("status").is_err());
+        assert!(r.get_time("host", &TimeFormat::Epoch).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = record(&[("host", "a"), ("status", "200"), ("size", "10")]);
+        let b = record(&[("host", "a"), ("status", "404"), ("ua", "curl")]);
+        let d = diff_records(&a, &b);
+        assert_eq!(d.added, ["ua"]);
+        assert_eq!(d.removed, ["size"]);
+        assert_eq!(d.changed, [("status".to_string(), "200".to_string(), "404".to_string())]);
+        assert!(!d.is_empty());
+        assert!(diff_records(&a, &a).is_empty());
+        assert_eq!(diff(&a, &b), d);
+    }
+
+    #[test]
+    fn test_heavy_hitters() {
+        let mut content = String::new();
+        let mut summary = SpaceSaving::new(20).unwrap();
+        let mut rng = Rng::new(Some(7));
+        for i in 0..5000 {
+            let ua = match i % 10 {
+                0..=3 => "curl".to_string(),
+                4 | 5 => "wget".to_string(),
+                6 => "bot".to_string(),
+                _ => format!("ua{}", rng.below(1000)),
+            };
+            content.push_str(&format!("ua:{}\n", ua));
+            summary.insert(&ua);
+            assert!(summary.len() <= 20);
+        }
+        let top = heavy_hitters(&mut fixture("heavy_hitters", &content), &"ua".to_string(), 3, 20)
+            .unwrap();
+        let values = top.iter().map(|(v, _)| v.as_str()).collect::<Vec<_>>();
+        assert_eq!(values, ["curl", "wget", "bot"]);
+        for (&(_, count), &exact) in top.iter().zip(&[2000, 1000, 500]) {
+            assert!(count >= exact && count <= exact + 5000 / 20);
+        }
+        assert_eq!(top, summary.top(3));
+        assert!(SpaceSaving::new(0).is_err());
+    }
+
+    struct FailingRead;
+
+    impl io::Read for FailingRead {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("read past the match"))
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let input = io::Read::chain(io::Cursor::new("status:200\nstatus:500\n"), FailingRead);
+        let mut reader = LineReader::from_reader(BufReader::new(input));
+        let found = find(&mut reader, |record| record["status"] == "500").unwrap();
+        assert_eq!(found, Some(record(&[("status", "500")])));
+        assert!(find(&mut reader, |_| true).is_err());
+
+        let mut reader = fixture("find_none", "status:200\n");
+        assert_eq!(find(&mut reader, |record| record["status"] == "500").unwrap(), None);
+    }
+
+    #[test]
+    fn test_split_by() {
+        let dir = env::temp_dir().join(format!("rlq-test-{}-split_by", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = "v:a.example.com\tn:0\nv:../etc\tn:1\nv:a/b\tn:2\nn:3\nv:c_d\tn:4\n\
+                       v:a.example.com\tn:5\nv:\tn:6\nv:../etc\tn:7\n";
+        let opts = SplitOptions { max_open: 2, ..SplitOptions::default() };
+        let stats = split_by(&mut fixture("split_by", content), &"v".to_string(), &dir, &opts)
+            .unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("a.example.com.ltsv"), "v:a.example.com\tn:0\nv:a.example.com\tn:5\n");
+        assert_eq!(read("%2E.%2Fetc.ltsv"), "v:../etc\tn:1\nv:../etc\tn:7\n");
+        assert_eq!(read("a%2Fb.ltsv"), "v:a/b\tn:2\n");
+        assert_eq!(read("c%5Fd.ltsv"), "v:c_d\tn:4\n");
+        assert_eq!(read("%.ltsv"), "v:\tn:6\n");
+        assert_eq!(read("_missing.ltsv"), "n:3\n");
+        assert_eq!(stats.records.len(), 6);
+        assert_eq!(stats.records[&dir.join("a.example.com.ltsv")], 2);
+        assert_eq!(stats.records.values().sum::<u64>(), 8);
+
+        let opts = SplitOptions { max_open: 0, ..SplitOptions::default() };
+        assert!(split_by(&mut fixture("split_by_invalid", ""), &"v".to_string(), &dir, &opts)
+            .is_err());
+    }
+
+    #[test]
+    fn test_split_by_paths() {
+        let dir = env::temp_dir().join(format!("rlq-test-{}-split_by_paths", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = "host:a\tn:0\nhost:b\tn:1\nhost:a\tn:2\n";
+        let stats = split_by(&mut fixture("split_by_paths", content),
+                             &"host".to_string(),
+                             &dir,
+                             &SplitOptions::default())
+            .unwrap();
+        assert_eq!(stats.paths(), [dir.join("a.ltsv"), dir.join("b.ltsv")]);
+        assert_eq!(fs::read_to_string(dir.join("a.ltsv")).unwrap(),
+                   "host:a\tn:0\nhost:a\tn:2\n");
+        assert_eq!(fs::read_to_string(dir.join("b.ltsv")).unwrap(), "host:b\tn:1\n");
+    }
+
+    #[test]
+    fn test_split_by_gzip() {
+        let dir = env::temp_dir().join(format!("rlq-test-{}-split_by_gzip", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = (0..2_000)
+            .map(|i| format!("v:{}\tn:{}\tua:Mozilla/5.0\n", ["a", "b", "c"][i % 3], i))
+            .collect::<String>();
+        let opts = SplitOptions { gzip: true, ..SplitOptions::default() };
+        let content = format!("{}n:x\n", content);
+        let stats = split_by(&mut fixture("split_by_gzip", &content), &"v".to_string(), &dir, &opts)
+            .unwrap();
+        assert_eq!(stats.records.len(), 4);
+
+        let read = |name: &str| {
+            let f = BufReader::new(File::open(dir.join(name)).unwrap());
+            collect_records(&mut LineReader::from_reader(sniff_gzip(f).unwrap())).unwrap()
+        };
+        let a = read("a.ltsv.gz");
+        assert_eq!(a.len(), 667);
+        assert_eq!(a[666]["n"], "1998");
+        assert_eq!(values(&read("_missing.ltsv.gz"), "n"), ["x"]);
+        let size = fs::metadata(dir.join("a.ltsv.gz")).unwrap().len();
+        assert!(size < 667 * 30 / 4, "{}", size);
+    }
+
+    #[test]
+    fn test_output_file() {
+        let path = env::temp_dir().join(format!("rlq-test-{}-output.ltsv.gz", process::id()));
+        let mut w = OutputFile::create(&path, false).unwrap();
+        w.write_all(b"host:a\n").unwrap();
+        w.finish().unwrap();
+        let mut w = OutputFile::append(&path, false).unwrap();
+        w.write_all(b"host:b\n").unwrap();
+        drop(w);
+        let mut data = Vec::new();
+        GzDecoder::new(File::open(&path).unwrap()).read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"host:a\nhost:b\n");
+
+        let plain = path.with_extension("");
+        OutputFile::create(&plain, false).unwrap().write_all(b"host:c\n").unwrap();
+        assert_eq!(fs::read(&plain).unwrap(), b"host:c\n");
+    }
+
+    #[test]
+    fn test_line_length_stats() {
+        let content = "n:1\n\nhost:é\r\nbroken line\n";
+        let stats = line_length_stats(&mut fixture("line_length_stats", content)).unwrap();
+        assert_eq!(stats, Stats { count: 3, min: 3.0, max: 11.0, mean: 7.0 });
+
+        let stats = line_length_stats(&mut fixture("line_length_stats_empty", "\n")).unwrap();
+        assert_eq!(stats.count, 0);
+        assert!(stats.min.is_nan() && stats.mean.is_nan());
+    }
+
+    #[test]
+    fn test_each_chunk() {
+        let chunks = |content: &str, n: usize| {
+            let mut chunks = Vec::new();
+            each_chunk(&mut fixture("each_chunk", content), n, |chunk| {
+                    chunks.push(values(chunk, "n"));
+                    Ok(())
+                })
+                .unwrap();
+            chunks
+        };
+        assert_eq!(chunks("n:0\n\nn:1\nn:2\n\nn:3\n", 2), [["0", "1"], ["2", "3"]]);
+        assert_eq!(chunks("n:0\nn:1\nn:2\n", 2), [vec!["0", "1"], vec!["2"]]);
+        assert!(chunks("\n", 2).is_empty());
+
+        let mut calls = 0;
+        let err = each_chunk(&mut numbered("each_chunk_error", 10), 3, |_| {
+            calls += 1;
+            if calls == 2 {
+                return Err(Error::InvalidArgument("sink failed".to_string()));
+            }
+            Ok(())
+        });
+        assert_eq!(format!("{}", err.unwrap_err()), "invalid argument: sink failed");
+        assert_eq!(calls, 2);
+        assert!(each_chunk(&mut fixture("each_chunk_zero", ""), 0, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_open_socket_tcp() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp:{}", listener.local_addr().unwrap());
+        let server = ::std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.write_all(b"n:0\nn:1\n\nn:2\n").unwrap();
+        });
+        let mut reader = open_socket(&addr).unwrap();
+        let seen = Cell::new(0);
+        each_record(&mut reader, |_| seen.set(seen.get() + 1)).unwrap();
+        assert_eq!(seen.get(), 3);
+        server.join().unwrap();
+
+        assert!(open_socket("udp:127.0.0.1:1").is_err());
+    }
+
+    #[test]
+    fn test_open_socket_timeout() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp:{}", listener.local_addr().unwrap());
+        let timeout = Some(::std::time::Duration::from_millis(50));
+        let mut reader = open_socket_with_timeout(&addr, timeout).unwrap();
+        match collect_records(&mut reader) {
+            Err(Error::Io(_)) => {}
+            other => panic!("expected a timeout, got {:?}", other.map(|r| r.len())),
+        }
+        drop(listener);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_socket_unix() {
+        let path = env::temp_dir().join(format!("rlq-test-{}-socket.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = ::std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = ::std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.write_all(b"host:a\n").unwrap();
+        });
+        let mut reader = open_socket(&format!("unix:{}", path.display())).unwrap();
+        assert_eq!(collect_records(&mut reader).unwrap(), [record(&[("host", "a")])]);
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Yields its data one byte per read.
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl io::Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn test_nul_delimiter() {
+        let opts = ParseOptions { delimiter: RecordDelimiter::Nul, ..ParseOptions::default() };
+        let content = "\u{feff}host:a\tpath:/x\n/y\0\0host:b\r\0host:a";
+        let mut reader = fixture("nul_delimiter", content);
+        let records = collect_with(&mut reader, &opts).unwrap();
+        assert_eq!(values(&records, "host"), ["a", "b\r", "a"]);
+        assert_eq!(records[0]["path"], "/x\n/y");
+        assert_eq!(reader.line_number, 4);
+
+        let mut reader = fixture("nul_delimiter_group", content);
+        let group = group_by_with(&mut reader, &"host".to_string(), &opts).unwrap();
+        assert_eq!((group["a"], group["b\r"]), (2, 1));
+
+        let mut reader = fixture("nul_delimiter_order", content);
+        let lines = order_by_with(&mut reader, &"host".to_string(), &opts).unwrap();
+        assert_eq!(lines, ["host:a\tpath:/x\n/y\0", "host:a", "host:b\r\0"]);
+
+        let mut out = Vec::new();
+        write_pairs(&mut out, &records[2], &opts).unwrap();
+        assert_eq!(out, b"host:a\0");
+
+        let mut reader = fixture("nul_delimiter_read", "n:1\0n:2\n");
+        let mut buf = String::new();
+        assert_eq!(reader.read_record(&mut buf, RecordDelimiter::Nul).unwrap(), 4);
+        assert_eq!(reader.read_record(&mut buf, RecordDelimiter::Nul).unwrap(), 4);
+        assert_eq!(reader.read_record(&mut buf, RecordDelimiter::Nul).unwrap(), 0);
+        assert_eq!(buf, "n:1\0n:2\n");
+    }
+
+    #[test]
+    fn test_sniff_gzip() {
+        // `printf 'host:a\nhost:b\n' | gzip -9n`
+        let gz = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0xc8,
+                      0x2f, 0x2e, 0xb1, 0x4a, 0xe4, 0xca, 0x00, 0x51, 0x49, 0x5c, 0x00, 0xbc,
+                      0x9c, 0xac, 0x7a, 0x0e, 0x00, 0x00, 0x00];
+        let hosts = |r: Box<dyn BufRead>| {
+            values(&collect_records(&mut LineReader::from_reader(r)).unwrap(), "host")
+        };
+        assert_eq!(hosts(sniff_gzip(io::Cursor::new(gz.clone())).unwrap()), ["a", "b"]);
+        let trickle = BufReader::with_capacity(1, Trickle(io::Cursor::new(gz)));
+        assert_eq!(hosts(sniff_gzip(trickle).unwrap()), ["a", "b"]);
+
+        let plain = BufReader::with_capacity(1, Trickle(io::Cursor::new(b"host:c\n".to_vec())));
+        assert_eq!(hosts(sniff_gzip(plain).unwrap()), ["c"]);
+        let mut short = Vec::new();
+        sniff_gzip(io::Cursor::new(vec![0x1f])).unwrap().read_to_end(&mut short).unwrap();
+        assert_eq!(short, [0x1f]);
+    }
+
+    #[test]
+    fn test_inner_join() {
+        let mut l = fixture("inner_join_left", "id:1	ip:a
+id:2	ip:b
+ip:c
+");
+        let mut r = fixture("inner_join_right", "id:1	ip:x	user:u
+id:1	user:v
+id:3
+");
+        let joined = inner_join(&mut l, &mut r, "id").unwrap();
+        assert_eq!(joined, [record(&[("id", "1"), ("ip", "a"), ("user", "v")])]);
+    }
+
+    #[test]
+    fn test_left_join() {
+        let mut l = fixture("left_join_left", "id:1\tip:a\nid:2\tip:b\nip:c\n");
+        let mut r = fixture("left_join_right", "id:1\tip:x\tuser:u\n");
+        let joined = left_join(&mut l, &mut r, "id").unwrap();
+        assert_eq!(joined,
+                   [record(&[("id", "1"), ("ip", "x"), ("user", "u")]),
+                    record(&[("id", "2"), ("ip", "b")]),
+                    record(&[("ip", "c")])]);
+    }
+
+    #[test]
+    fn test_bytes_read() {
+        let content = "\u{feff}host:a\r\nhost:b\n\nhost:c";
+        let mut reader = fixture("bytes_read", content);
+        assert_eq!(reader.total_len(), Some(content.len() as u64));
+        let mut line = String::new();
+        let mut last = 0;
+        while next_line(&mut reader, &mut line).unwrap() {
+            assert!(reader.bytes_read() > last);
+            last = reader.bytes_read();
+        }
+        assert_eq!(reader.bytes_read(), content.len() as u64);
+
+        let mut reader = LineReader::from_reader(io::Cursor::new("host:a\nhost:b\n"));
+        assert_eq!(reader.total_len(), None);
+        let mut head = [0; 3];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"hos");
+        let rest = (&mut reader).lines().collect::<io::Result<Vec<String>>>().unwrap();
+        assert_eq!(rest, ["t:a", "host:b"]);
+        assert_eq!(reader.bytes_read(), 14);
+    }
+
+    #[test]
+    fn test_cardinality() {
+        let mut reader = fixture("cardinality", "ip:a\nip:b\nuri:/\nip:a\nip:\nip:c\n");
+        assert_eq!(cardinality(&mut reader, &"ip".to_string()).unwrap(), 4);
+        let mut reader = fixture("cardinality_none", "uri:/\n");
+        assert_eq!(cardinality(&mut reader, &"ip".to_string()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_progress() {
+        let content = (0..10_000).map(|i| format!("n:{}\n", i % 7)).collect::<String>();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let opts = ParseOptions {
+            progress: Some(ProgressHook::new(1_000, move |p| sink.lock().unwrap().push(p))),
+            ..ParseOptions::default()
+        };
+
+        let mut reader = fixture("progress_group", &content);
+        group_by_with(&mut reader, &"n".to_string(), &opts).unwrap();
+        let records = reports.lock().unwrap().iter().map(|p| p.records).collect::<Vec<u64>>();
+        assert_eq!(records, [1_000, 2_000, 3_000, 4_000, 5_000, 6_000, 7_000, 8_000, 9_000,
+                             10_000, 10_000]);
+        assert_eq!(reports.lock().unwrap().last().unwrap().bytes_read, content.len() as u64);
+        assert!(reports.lock().unwrap().windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+
+        reports.lock().unwrap().clear();
+        let mut reader = fixture("progress_order", &content[..content.len() - 4]);
+        order_by_with(&mut reader, &"n".to_string(), &opts).unwrap();
+        assert_eq!(reports.lock().unwrap().len(), 10);
+        assert_eq!(reports.lock().unwrap()[9].records, 9_999);
+
+        reports.lock().unwrap().clear();
+        let mut reader = fixture("progress_each", "n:1\n\nn:2\n");
+        each_record_with(&mut reader, &opts, |_| {}).unwrap();
+        assert_eq!(reports.lock().unwrap().len(), 1);
+        assert_eq!(reports.lock().unwrap()[0].records, 2);
+    }
+
+    /// Yields `n:1` lines forever.
+    struct Endless;
+
+    impl io::Read for Endless {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len() / 4 * 4;
+            for chunk in buf[..n].chunks_mut(4) {
+                chunk.copy_from_slice(b"n:1\n");
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let opts = ParseOptions { cancel: Some(cancel.clone()), ..ParseOptions::default() };
+        let canceller = ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(50));
+            cancel.store(true, AtomicOrdering::Relaxed);
+        });
+        let start = Instant::now();
+        let mut reader = LineReader::from_reader(BufReader::new(Endless));
+        match group_by_with(&mut reader, &"n".to_string(), &opts) {
+            Err(Error::Cancelled(Some(counts))) => assert!(counts["1"] > 0),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+
+        let mut reader = LineReader::from_reader(BufReader::new(Endless));
+        match order_by_with(&mut reader, &"n".to_string(), &opts) {
+            Err(Error::Cancelled(None)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|lines| lines.len())),
+        }
+        let mut reader = LineReader::from_reader(BufReader::new(Endless));
+        assert!(each_record_with(&mut reader, &opts, |_| {}).is_err());
+
+        let n = "n".to_string();
+        let endless = || LineReader::from_reader(BufReader::new(Endless));
+        let cancelled = |result: Result<(), Error>| match result {
+            Err(Error::Cancelled(None)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        };
+        cancelled(order_by_kind_with(&mut endless(), &n, &SortKind::Numeric, &opts).map(|_| ()));
+        cancelled(order_by_time_with(&mut endless(), &n, "%s", &opts).map(|_| ()));
+        cancelled(order_records_by_with(&mut endless(), &n, &opts).map(|_| ()));
+        cancelled(cardinality_with(&mut endless(), &n, &opts).map(|_| ()));
+        cancelled(heavy_hitters_with(&mut endless(), &n, 3, 10, &opts).map(|_| ()));
+        cancelled(index_by_with(&mut endless(), &n, &opts).map(|_| ()));
+        cancelled(sum_with(&mut endless(), &n, &opts).map(|_| ()));
+        cancelled(mean_with(&mut endless(), &n, &opts).map(|_| ()));
+        cancelled(find_with(&mut endless(), &opts, |_| false).map(|_| ()));
+        let (mut left, mut right) = (endless(), endless());
+        let mut diffs = diff_streams_with(&mut left, &mut right, "n", opts.clone());
+        cancelled(diffs.next().unwrap().map(|_| ()));
+    }
+
+    #[test]
+    fn test_cardinality_approx() {
+        let content = (0..20_000)
+            .map(|i| i % 10_000)
+            .map(|i| format!("ip:10.0.{}.{}\n", i / 256, i % 256))
+            .collect::<String>();
+        let mut reader = fixture("cardinality_approx", &content);
+        let estimate = cardinality_approx(&mut reader, &"ip".to_string(), 12).unwrap();
+        assert!((estimate as f64 - 10_000.0).abs() < 500.0, "{}", estimate);
+    }
+
+    #[test]
+    fn test_rate_per_second() {
+        let content = "time:2000-10-10T20:55:37\ntime:2000-10-10T20:55:36\n\
+                       time:2000-10-10T20:55:38\ntime:-\ntime:2000-10-10T20:55:36\nuri:/\n";
+        let mut reader = fixture("rate_per_second", content);
+        assert_eq!(rate_per_second(&mut reader, &"time".to_string(), "%FT%T").unwrap(),
+                   [(971211336, 2), (971211337, 1), (971211338, 1)]);
+    }
+
+    #[test]
+    fn test_open_file_at() {
+        let content = "n:0\nn:1\nn:2\nn:3\nn:4";
+        let name = fixture_path("open_file_at", content);
+        let mut reader = open_file(&name).unwrap();
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            seen.push(next_record(&mut reader, &ParseOptions::default()).unwrap().unwrap());
+        }
+        assert_eq!(reader.offset(), 8);
+        let mut reader = open_file_at(&name, reader.offset()).unwrap();
+        assert_eq!(reader.skipped_bytes(), 0);
+        seen.extend(collect_records(&mut reader).unwrap());
+        assert_eq!(values(&seen, "n"), ["0", "1", "2", "3", "4"]);
+        assert_eq!(reader.offset(), content.len() as u64 - "n:4".len() as u64);
+
+        let mut reader = open_file_at(&name, 9).unwrap();
+        assert_eq!(reader.skipped_bytes(), 3);
+        assert_eq!(values(&collect_records(&mut reader).unwrap(), "n"), ["3", "4"]);
+        assert_eq!(open_file_at(&name, content.len() as u64).unwrap().skipped_bytes(), 0);
+        assert!(open_file_at(&name, 100).is_err());
+        assert!(open_file_at("-", 0).is_err());
+    }
+
+    #[test]
+    fn test_open_file_at_growing() {
+        let name = fixture_path("open_file_at_growing", "n:0\nn:1\nn:2\thost:ab");
+        let mut reader = open_file(&name).unwrap();
+        let first = collect_records(&mut reader).unwrap();
+        assert_eq!(values(&first, "n"), ["0", "1", "2"]);
+        assert_eq!(first[2]["host"], "ab");
+        assert_eq!(reader.offset(), 8);
+
+        OpenOptions::new().append(true).open(&name).unwrap().write_all(b"c\nn:3\n").unwrap();
+        let mut reader = open_file_at(&name, reader.offset()).unwrap();
+        assert_eq!(reader.skipped_bytes(), 0);
+        let second = collect_records(&mut reader).unwrap();
+        assert_eq!(values(&second, "n"), ["2", "3"]);
+        assert_eq!(second[0]["host"], "abc");
+        assert_eq!(reader.offset(), fs::metadata(&name).unwrap().len());
+    }
+
+    #[test]
+    fn test_group_by_min_count() {
+        let mut reader = fixture("group_by_min_count", "ip:a\nip:b\nip:a\nip:c\nip:a\nip:c\n");
+        let group = group_by_min_count(&mut reader, &"ip".to_string(), 2).unwrap();
+        assert_eq!(group, [("a".to_string(), 3), ("c".to_string(), 2)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn test_max_line_len() {
+        let content = "n:1\r\nn:123456\nn:123456\r\nn:1234567890\nn:2";
+        let strict = ParseOptions { max_line_len: 8, ..ParseOptions::default() };
+        let mut reader = fixture("max_line_len", content);
+        match collect_with(&mut reader, &strict) {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.msg, "line 4 is too long: 12 bytes, at most 8 allowed")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let lenient = ParseOptions { lenient: true, ..strict.clone() };
+        let mut reader = fixture("max_line_len_lenient", content);
+        let records = collect_with(&mut reader, &lenient).unwrap();
+        assert_eq!(values(&records, "n"), ["1", "123456", "123456", "2"]);
+        assert_eq!(reader.long_lines(), 1);
+        assert_eq!(reader.bytes_read(), content.len() as u64);
+
+        let mut reader = fixture("max_line_len_order", content);
+        assert!(order_by_with(&mut reader, &"n".to_string(), &strict).is_err());
+        let mut reader = fixture("max_line_len_order_lenient", content);
+        assert_eq!(order_by_with(&mut reader, &"n".to_string(), &lenient).unwrap().len(), 4);
+
+        let long = format!("n:{}", "x".repeat(10_000));
+        let input = BufReader::with_capacity(16, io::Cursor::new(long));
+        let mut reader = LineReader::from_reader(input);
+        assert!(collect_with(&mut reader, &strict).is_err());
+    }
+
+    fn collect_with(reader: &mut LineReader, opts: &ParseOptions) -> Result<Vec<Record>, Error> {
+        let mut records = Vec::new();
+        while let Some(record) = next_record(reader, opts)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn test_max_memory_bytes() {
+        let opts = ParseOptions {
+            max_memory_bytes: Some(3 * (4 + LINE_OVERHEAD)),
+            ..ParseOptions::default()
+        };
+        let mut reader = fixture("max_memory_bytes", "n:3\nn:1\nn:2\n");
+        assert_eq!(order_by_with(&mut reader, &"n".to_string(), &opts).unwrap(),
+                   ["n:1\n", "n:2\n", "n:3\n"]);
+
+        let mut reader = fixture("max_memory_bytes_over", "n:3\nn:1\nn:2\nn:10\n");
+        match order_by_with(&mut reader, &"n".to_string(), &opts) {
+            Err(Error::MemoryLimit { lines_buffered, bytes }) => {
+                assert_eq!(lines_buffered, 4);
+                assert_eq!(bytes, (17 + 4 * LINE_OVERHEAD) as u64);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_header_labels() {
+        let content = "time:1\thost:a\tstatus:200\thost:b\nstatus:404\ttime:2\n";
+        let mut reader = fixture("header_labels", content);
+        let columns = header_labels(&mut reader).unwrap();
+        assert_eq!(columns, ["time", "host", "status"]);
+
+        let mut reader = fixture("header_labels_csv", content);
+        assert_eq!(to_csv(&mut reader, &columns).unwrap(),
+                   ["time,host,status", "1,b,200", "2,,404"]);
+        assert!(header_labels(&mut fixture("header_labels_empty", "\n")).is_err());
+    }
+
+    #[test]
+    fn test_error_quotes_line() {
+        let mut reader = fixture("error_quotes_line", "host:a\nhost:b\tbroken\tstatus:200\n");
+        let err = collect_records(&mut reader).unwrap_err();
+        assert_eq!(format!("{}", err),
+                   "Parse error: line 2: invalid ltsv item: broken in \
+                    \"host:b\\tbroken\\tstatus:200\"");
+
+        let mut reader = fixture("error_quotes_line_group", "host:a\nhost:b\tbroken\n");
+        let err = group_by(&mut reader, &"host".to_string()).unwrap_err();
+        assert!(format!("{}", err).starts_with("Parse error: line 2: invalid ltsv item: broken"));
+
+        let long = format!("broken\t{}", "x:y\t".repeat(100));
+        let msg = format!("{}", parse_line(&long).unwrap_err());
+        assert!(msg.ends_with(&format!("{:?}...", &long[..256])), "{}", msg);
+    }
+
+    #[test]
+    fn test_error_line_numbers() {
+        let check = |err: Error| match err {
+            Error::Parse(e) => {
+                assert!(e.msg.starts_with("line 3: "), "{}", e.msg);
+                assert_eq!(e.line(), Some(3));
+                assert!(e.file().is_some_and(|f| f.ends_with(".ltsv")));
+            }
+            err => panic!("expected a parse error: {:?}", err),
+        };
+        let content = "host:a\n\nhost:b\tbroken\n\n";
+        let host = "host".to_string();
+        check(presence_count(&mut fixture("lines_presence", content), &host).unwrap_err());
+        check(last_record(&mut fixture("lines_last", content)).unwrap_err());
+        check(each_record_step(&mut fixture("lines_step", content), 1, |_| {}).unwrap_err());
+        let mut out = Vec::new();
+        check(drop_labels(&mut fixture("lines_drop", content), &[host], &mut out).unwrap_err());
+        check(each_record_json(&mut fixture("lines_json", "{}\n\n[\n"), |_| {}).unwrap_err());
+        let combined = "::1 - - [10/Oct/2000:13:55:36 +0000] \"GET /\" 200 0\n\nbroken\n";
+        check(each_record_combined(&mut fixture("lines_combined", combined), |_| {})
+            .unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_error_accessors() {
+        let path = fixture_path("parse_error_accessors", "host:a\nhost:b\tbroken\n");
+        match collect_records(&mut open_path(&path).unwrap()) {
+            Err(Error::Parse(e)) => {
+                assert_eq!((e.line(), e.file(), e.field()),
+                           (Some(2), Some(path.as_str()), Some("broken")));
+            }
+            r => panic!("expected a parse error: {:?}", r),
+        }
+        match parse_line("broken") {
+            Err(Error::Parse(e)) => assert_eq!((e.line(), e.file()), (None, None)),
+            r => panic!("expected a parse error: {:?}", r),
+        }
+        let mut reader = LineReader::from_reader(io::Cursor::new("\n\n"));
+        let opts = ParseOptions { blank_lines: BlankPolicy::Error, ..ParseOptions::default() };
+        match collect_with(&mut reader, &opts) {
+            Err(Error::Parse(e)) => assert_eq!((e.line(), e.field()), (Some(1), None)),
+            r => panic!("expected a parse error: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_group_by_hashers() {
+        fn same<S, T>(a: &GroupCountWith<S>, b: &GroupCountWith<T>) -> bool
+            where S: BuildHasher,
+                  T: BuildHasher
+        {
+            a.len() == b.len() && a.iter().all(|(k, v)| b.get(k) == Some(v))
+        }
+        let content = (0..5_000)
+            .map(|i| format!("ip:10.0.{}.{}\n", i % 7, i % 300))
+            .collect::<String>();
+        let mut reader = fixture("group_by_hashers", &content);
+        let group = group_by(&mut reader, &"ip".to_string()).unwrap();
+
+        let mut sip: GroupCountWith<RandomState> = HashMap::default();
+        let mut fx: GroupCountWith<::fxhash::FxBuildHasher> = HashMap::default();
+        for line in content.lines() {
+            *sip.entry(line[3..].to_string()).or_insert(0) += 1;
+            *fx.entry(line[3..].to_string()).or_insert(0) += 1;
+        }
+        assert!(same(&group, &sip));
+        assert!(same(&group, &fx));
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut reader = fixture("dedup", "n:1\nn:2\n\nn:1\nn:3\nn:2\r\nn:1\n");
+        assert_eq!(dedup(&mut reader).unwrap(), ["n:1", "n:2", "n:3"]);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let mut fields = Vec::new();
+        parse_fields("a:\t:b\tt:12:00:00\t:", ':', &mut |l, v| {
+                fields.push(format!("{}={}", l, v));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(fields, ["a=", "=b", "t=12:00:00", "="]);
+
+        let mut seen = 0;
+        let err = parse_fields("a:1\tbroken\tc:3", ':', &mut |_, _| {
+                seen += 1;
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(format!("{}", err),
+                   "Parse error: invalid ltsv item: broken in \"a:1\\tbroken\\tc:3\"");
+        assert_eq!(seen, 1);
+        assert!(parse_fields("", ':', &mut |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_dedup_lines_by() {
+        let content = "session_id:1\tn:0\nsession_id:2\tn:1\nn:2\nsession_id:1\tn:3\nn:2\n";
+        let mut reader = fixture("dedup_lines_by", content);
+        assert_eq!(dedup_lines_by(&mut reader, &"session_id".to_string()).unwrap(),
+                   ["session_id:1\tn:0", "session_id:2\tn:1", "n:2", "n:2"]);
+    }
+
+    #[test]
+    fn test_group_by_map() {
+        let content = "uri:/a?x=1\tstatus:200\nuri:/a#top\tstatus:404\nuri:/b\tstatus:204\n\
+                       status:-\nuri:/a\n";
+        let mut reader = fixture("group_by_map", content);
+        let status_class = |v: &str| KeyTransform::StatusClass.apply(v);
+        let group = group_by_map(&mut reader, &"status".to_string(), status_class).unwrap();
+        assert_eq!((group.len(), group["2xx"], group["4xx"]), (2, 2, 1));
+
+        let mut reader = fixture("group_by_map_uri", content);
+        let strip_query = |v: &str| KeyTransform::StripQuery.apply(v);
+        let group = group_by_map(&mut reader, &"uri".to_string(), strip_query).unwrap();
+        assert_eq!((group["/a"], group["/b"]), (3, 1));
+
+        let mut reader = fixture("group_by_map_none", content);
+        let group = group_by_map(&mut reader, &"uri".to_string(), |v| {
+                if v.starts_with("/a") { None } else { Some(v.to_string()) }
+            })
+            .unwrap();
+        assert_eq!(group, [("/b".to_string(), 1)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn test_group_by_ua_class() {
+        let content = "ua:Mozilla/5.0 (Windows NT 10.0) Gecko/20100101 Firefox/121.0\n\
+                       ua:Mozilla/5.0 (compatible; Googlebot/2.1)\n\
+                       ua:curl/8.4.0\nstatus:200\n";
+        let ua = "ua".to_string();
+        let mut reader = fixture("group_by_ua_class", content);
+        let group = group_by_ua_class(&mut reader, &ua, UaFacet::Browser).unwrap();
+        assert_eq!((group.len(), group["Firefox"], group["Other"]), (2, 1, 2));
+
+        let mut reader = fixture("group_by_ua_class_bot", content);
+        let group = group_by_ua_class(&mut reader, &ua, UaFacet::Bot).unwrap();
+        assert_eq!((group["true"], group["false"]), (2, 1));
+
+        let mut rules = UaRules::default();
+        rules.systems.insert(0, ("Googlebot".to_string(), "Google".to_string()));
+        let mut reader = fixture("group_by_ua_class_with", content);
+        let group = group_by_ua_class_with(&mut reader, &ua, UaFacet::Os, &rules).unwrap();
+        assert_eq!((group["Windows"], group["Google"], group["Other"]), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_key_transform() {
+        assert_eq!(KeyTransform::StatusClass.apply("503"), Some("5xx".to_string()));
+        assert_eq!(KeyTransform::StatusClass.apply("50"), None);
+        assert_eq!(KeyTransform::StatusClass.apply("abc"), None);
+        assert_eq!(KeyTransform::StripQuery.apply("/a/b?c=d#e"), Some("/a/b".to_string()));
+        assert_eq!(KeyTransform::StripQuery.apply("/"), Some("/".to_string()));
+        let fmt = TimeFormat::Strftime("%d/%b/%Y:%H:%M:%S %z".to_string());
+        let hour = KeyTransform::HourOfDay(fmt);
+        assert_eq!(hour.apply("10/Oct/2000:13:55:36 -0700"), Some("20".to_string()));
+        assert_eq!(hour.apply("10/Oct/2000:03:55:36 +0900"), Some("18".to_string()));
+        assert_eq!(hour.apply("-"), None);
+        assert_eq!(KeyTransform::Prefix(2).apply("ün.example"), Some("ün".to_string()));
+        assert_eq!(KeyTransform::Prefix(5).apply("ab"), Some("ab".to_string()));
+    }
+
+    #[test]
+    fn test_record_builder() {
+        let record = RecordBuilder::new().field("status", "200").field("host", "a").build();
+        assert_eq!(serialize_record(&record, &ParseOptions::default()).unwrap(),
+                   "host:a\tstatus:200");
+        assert_eq!(record, from_pairs(&[("host", "a"), ("status", "200")]));
+
+        let record = RecordBuilder::new().field("n", "1").field("n".to_string(), "2").build();
+        assert_eq!(record, from_pairs(&[("n", "1"), ("n", "2")]));
+        assert_eq!(record["n"], "2");
+        assert!(RecordBuilder::new().build().is_empty());
+    }
+
+    #[test]
+    fn test_reformat_time() {
+        let apache = "%d/%b/%Y:%H:%M:%S %z";
+        let time = "time".to_string();
+        let record = from_pairs(&[("time", "10/Oct/2000:13:55:36 -0700"), ("host", "a")]);
+        let iso = reformat_time(&record, &time, apache, "%FT%TZ").unwrap();
+        assert_eq!(iso, from_pairs(&[("time", "2000-10-10T20:55:36Z"), ("host", "a")]));
+        let record = from_pairs(&[("time", "10/Oct/2000:13:55:36")]);
+        assert_eq!(reformat_time(&record, &time, "%d/%b/%Y:%H:%M:%S", "%F %T").unwrap()["time"],
+                   "2000-10-10 13:55:36");
+
+        let host = from_pairs(&[("host", "a")]);
+        assert_eq!(reformat_time(&host, &time, apache, "%FT%TZ").unwrap(), host);
+        match reformat_time(&from_pairs(&[("time", "yesterday")]), &time, apache, "%F") {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.msg, "can't parse time as \"%d/%b/%Y:%H:%M:%S %z\": \"yesterday\"")
+            }
+            r => panic!("expected a parse error: {:?}", r),
+        }
+        assert!(reformat_time(&iso, &time, "%FT%TZ", "%q").is_err());
+    }
+
+    #[test]
+    fn test_composite_key() {
+        let labels = labels(&["method", "status"]);
+        let get = record(&[("method", "GET"), ("status", "200"), ("uri", "/")]);
+        assert_eq!(composite_key(&get, &labels, '\t'), "GET\t200");
+        assert_eq!(composite_key(&record(&[("status", "404")]), &labels, '|'), "|404");
+        assert_eq!(composite_key(&get, &[], '|'), "");
+    }
+
+    #[test]
+    fn test_group_by_capture() {
+        let content = "uri:/api/v2/users/1/orders/7\nuri:/api/v1/users/2\nuri:/api/v2/orders/3/\n\
+                       uri:/health\nhost:a\n";
+        let group = |pattern: &str, group: &str, keep_unmatched: bool| {
+            let mut reader = fixture("group_by_capture", content);
+            group_by_capture(&mut reader, &"uri".to_string(), pattern, group, keep_unmatched)
+        };
+        let routes = group(r"^/api/v\d+/(\w+)/", "1", false).unwrap();
+        assert_eq!((routes.len(), routes["users"], routes["orders"]), (2, 2, 1));
+        let named = group(r"^/api/(?P<version>v\d+)/(?P<route>\w+)/", "route", true).unwrap();
+        assert_eq!((named.len(), named["users"], named[NO_MATCH]), (3, 2, 1));
+        let whole = group(r"/\d+", "0", false).unwrap();
+        assert_eq!((whole.len(), whole["/1"], whole["/2"], whole["/3"]), (3, 1, 1, 1));
+        let optional = group(r"^/api/v2/(users)?", "1", true).unwrap();
+        assert_eq!((optional["users"], optional[NO_MATCH]), (1, 3));
+
+        assert!(group(r"(\w+", "1", false).is_err());
+        assert!(group(r"(\w+)", "2", false).is_err());
+        assert!(group(r"(\w+)", "route", false).is_err());
+    }
+
+    #[test]
+    fn test_group_by_multi() {
+        let content = "method:GET\tstatus:200\nmethod:POST\tstatus:200\nmethod:GET\tstatus:404\n\
+                       status:200\tmethod:GET\nmethod:GET\n";
+        let mut reader = fixture("group_by_multi", content);
+        let group = group_by_multi(&mut reader, &labels(&["method", "status"])).unwrap();
+        assert_eq!(group.len(), 4);
+        assert_eq!(group[&labels(&["GET", "200"])], 2);
+        assert_eq!(group[&labels(&["POST", "200"])], 1);
+        assert_eq!(group[&labels(&["GET", "404"])], 1);
+        assert_eq!(group[&labels(&["GET", ""])], 1);
+    }
+
+    #[test]
+    fn test_expand_query() {
+        let mut r = record(&[("uri", "/search?q=rust+lang%21&page=2&flag&tag=a&tag=b%2B&=x#top")]);
+        expand_query(&mut r, "uri", "q.");
+        assert_eq!(r.len(), 6);
+        assert_eq!(r["q.q"], "rust lang!");
+        assert_eq!(r["q.page"], "2");
+        assert_eq!(r["q.flag"], "");
+        assert_eq!(r["q.tag"], "a");
+        assert_eq!(r["q."], "x");
+
+        let mut joined = record(&[("uri", "/?tag=a&tag=b%2B&bad=%zz%e3%81%82")]);
+        expand_query_with(&mut joined, "uri", "", Some(","));
+        assert_eq!(joined["tag"], "a,b+");
+        assert_eq!(joined["bad"], "%zz\u{3042}");
+
+        let plain = record(&[("uri", "/index.html")]);
+        let mut expanded = plain.clone();
+        expand_query(&mut expanded, "uri", "q.");
+        expand_query(&mut expanded, "path", "q.");
+        assert_eq!(expanded, plain);
+    }
+
+    #[test]
+    fn test_group_by_query_param() {
+        let content = "uri:/s?q=a&page=2\nuri:/s?page=1&q=a+b\nuri:/s?q=a\nuri:/s\nhost:x\n";
+        let mut reader = fixture("group_by_query_param", content);
+        let group = group_by_query_param(&mut reader, &"uri".to_string(), "q").unwrap();
+        assert_eq!((group.len(), group["a"], group["a b"]), (2, 2, 1));
+    }
+
+    #[test]
+    fn test_first_separator_and_empty_label() {
+        for line in &["url:http://x:8080/a\t:orphan", ":orphan\turl:http://x:8080/a"] {
+            let expected = record(&[("url", "http://x:8080/a"), ("", "orphan")]);
+            assert_eq!(parse_line(line).unwrap(), expected);
+            assert_eq!(parse_head(&mut fixture("empty_label_head", line)).unwrap(), expected);
+            let mut reader = fixture("empty_label_each", line);
+            let seen = RefCell::new(Vec::new());
+            each_record(&mut reader, |r| seen.borrow_mut().push(r.clone())).unwrap();
+            assert_eq!(seen.into_inner(), [expected]);
+        }
+        assert_eq!(parse_line(":").unwrap(), record(&[("", "")]));
+    }
 }
@@ -4,14 +4,14 @@ extern crate getopts;
 extern crate log;
 
 use std::env;
-use std::process::exit;
 use std::io::{self, Write};
+use std::process::exit;
 use getopts::Options;
 
 extern crate rlq;
 use rlq::config::*;
 use rlq::ltsv;
-use rlq::error::CliError;
+use rlq::error::{CliError, Error};
 
 #[macro_use]
 pub mod error;
@@ -24,11 +24,11 @@ fn run(args: Vec<String>) -> i32 {
     let (args, config) = parse_config(args);
     let ret = if config.query_list {
         do_list(args)
-    } else if config.query_select.len() > 0 {
+    } else if !config.query_select.is_empty() {
         do_select(args, config.query_select)
-    } else if config.query_groupby != "" {
+    } else if !config.query_groupby.is_empty() {
         do_groupby(args, config.query_groupby)
-    } else if config.query_orderby != "" {
+    } else if !config.query_orderby.is_empty() {
         do_orderby(args, config.query_orderby)
     } else {
         None
@@ -43,8 +43,24 @@ fn run(args: Vec<String>) -> i32 {
     }
 }
 
+/// Reports the outcome of writing to `out`, flushing it first. A closed
+/// output, as when piped into `head`, isn't an error: there is no one left
+/// to tell, so the command stops quietly.
+fn finish_output(result: Result<(), Error>,
+                 out: &mut dyn Write,
+                 context: &str)
+                 -> Option<CliError> {
+    match result.and_then(|()| out.flush().map_err(Error::from)) {
+        Ok(()) | Err(Error::OutputClosed) => None,
+        Err(err) => {
+            stderr!("{}: {}", context, err);
+            Some(CliError::Other)
+        }
+    }
+}
+
 fn print_usage(opts: &Options) {
-    let message = format!("Usage: rlq [ options ... ] [URL]\n\twhere options include");
+    let message = "Usage: rlq [ options ... ] [URL]\n\twhere options include".to_string();
     println!("{}", opts.usage(&message));
 }
 
@@ -68,7 +84,7 @@ fn parse_config(args: Vec<String>) -> (Vec<String>, Config) {
     opts.optflag("h", "help", "show this message");
 
     let (_, args) = args.split_first().unwrap();
-    if args.len() == 0 || args[0] == "-h" || args[0] == "--help" {
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
         print_usage(&opts);
         exit(0);
     }
@@ -93,7 +109,7 @@ fn parse_config(args: Vec<String>) -> (Vec<String>, Config) {
 }
 
 fn do_list(args: Vec<String>) -> Option<CliError> {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Some(CliError::NotEnoughArgs);
     }
     if args.len() == 2 {
@@ -102,19 +118,19 @@ fn do_list(args: Vec<String>) -> Option<CliError> {
     match ltsv::open_file(args[0].as_ref()) {
         Err(err) => {
             stderr!("failed to open file: {}", err);
-            return Some(CliError::Other);
+            Some(CliError::Other)
         }
         Ok(mut f) => {
             match ltsv::parse_head(&mut f) {
                 Err(err) => {
                     stderr!("failed to parse head: {}", err);
-                    return Some(CliError::Other);
+                    Some(CliError::Other)
                 }
                 Ok(record) => {
-                    for (label, _) in &record {
-                        println!("{}", label)
-                    }
-                    return None;
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    let result = record.keys().try_for_each(|label| writeln!(out, "{}", label));
+                    finish_output(result.map_err(Error::from), &mut out, "failed to list labels")
                 }
             }
         }
@@ -122,7 +138,7 @@ fn do_list(args: Vec<String>) -> Option<CliError> {
 }
 
 fn do_select(args: Vec<String>, arg_labels: Vec<String>) -> Option<CliError> {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Some(CliError::NotEnoughArgs);
     }
     if args.len() == 2 {
@@ -151,6 +167,8 @@ fn do_select(args: Vec<String>, arg_labels: Vec<String>) -> Option<CliError> {
                 }
             }
 
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
             let printer = |record: &ltsv::Record| {
                 let line = arg_labels.iter()
                     .map(|label| {
@@ -161,21 +179,17 @@ fn do_select(args: Vec<String>, arg_labels: Vec<String>) -> Option<CliError> {
                     })
                     .collect::<Vec<String>>()
                     .join("\t");
-                println!("{}", line);
+                writeln!(out, "{}", line)?;
+                Ok(())
             };
-            match ltsv::each_record(&mut f, printer) {
-                Err(err) => {
-                    stderr!("failed to print each record: {}", err);
-                    Some(CliError::Other)
-                }
-                Ok(_) => None,
-            }
+            let result = ltsv::try_each_record(&mut f, printer);
+            finish_output(result, &mut out, "failed to print each record")
         }
     }
 }
 
 fn do_groupby(args: Vec<String>, arg_label: String) -> Option<CliError> {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Some(CliError::NotEnoughArgs);
     }
     if args.len() == 2 {
@@ -193,10 +207,12 @@ fn do_groupby(args: Vec<String>, arg_label: String) -> Option<CliError> {
                     Some(CliError::Other)
                 }
                 Ok(group) => {
-                    for (label_value, count) in group {
-                        println!("{}:{}\tcount:{}", arg_label, label_value, count);
-                    }
-                    None
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    let result = group.iter().try_for_each(|(label_value, count)| {
+                        writeln!(out, "{}:{}\tcount:{}", arg_label, label_value, count)
+                    });
+                    finish_output(result.map_err(Error::from), &mut out, "failed to print groups")
                 }
             }
         }
@@ -204,7 +220,7 @@ fn do_groupby(args: Vec<String>, arg_label: String) -> Option<CliError> {
 }
 
 fn do_orderby(args: Vec<String>, arg_label: String) -> Option<CliError> {
-    if args.len() == 0 {
+    if args.is_empty() {
         return Some(CliError::NotEnoughArgs);
     }
     if args.len() == 2 {
@@ -222,10 +238,10 @@ fn do_orderby(args: Vec<String>, arg_label: String) -> Option<CliError> {
                     Some(CliError::Other)
                 }
                 Ok(lines) => {
-                    for line in lines {
-                        print!("{}", line);
-                    }
-                    None
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    let result = lines.iter().try_for_each(|line| write!(out, "{}", line));
+                    finish_output(result.map_err(Error::from), &mut out, "failed to print lines")
                 }
             }
         }
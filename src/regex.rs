@@ -0,0 +1,533 @@
+use error::*;
+
+/// A regular expression, matched by backtracking with memoization so that
+/// matching takes time linear in the pattern and text sizes.
+///
+/// The syntax is a common subset of Perl's: literals, `.`, classes such as
+/// `[a-z_]` and `[^/]`, the escapes `\d \w \s \D \W \S`, the anchors `^` and
+/// `$`, groups `(...)`, `(?:...)` and `(?P<name>...)` (or `(?<name>...)`),
+/// alternation, and the quantifiers `* + ? {n} {n,} {n,m}`, each with a lazy
+/// `?` form. Searches find the leftmost match, preferring alternatives from
+/// left to right as Perl does.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    names: Vec<Option<String>>,
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    /// Try the first target, then the second.
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+#[derive(Clone, Debug)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+/// The largest count allowed in `{n,m}`.
+const MAX_REPEAT: u32 = 1000;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        let mut p = Parser {
+            pattern,
+            chars: pattern.chars().collect(),
+            pos: 0,
+            names: vec![None],
+        };
+        let node = p.alternation()?;
+        if p.pos < p.chars.len() {
+            return Err(p.error("unmatched ')'"));
+        }
+        // Unanchored search: a lazy `.*` in front of group 0.
+        let mut prog = vec![Inst::Split(3, 1), Inst::Any, Inst::Jmp(0), Inst::Save(0)];
+        compile(&node, &mut prog);
+        prog.push(Inst::Save(1));
+        prog.push(Inst::Match);
+        Ok(Regex { prog, names: p.names })
+    }
+
+    /// The number of capture groups, counting the whole match as group 0.
+    pub fn captures_len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The index of the group named `name`.
+    pub fn group_index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n.as_ref().is_some_and(|n| n == name))
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.captures(text).is_some()
+    }
+
+    /// Finds the leftmost match in `text` and returns the text of each
+    /// group, `None` for groups that took no part in the match.
+    pub fn captures<'t>(&self, text: &'t str) -> Option<Vec<Option<&'t str>>> {
+        let mut slots = vec![None; 2 * self.names.len()];
+        if !self.run(text, &mut slots) {
+            return None;
+        }
+        Some(slots.chunks(2)
+            .map(|s| match (s[0], s[1]) {
+                (Some(start), Some(end)) => Some(&text[start..end]),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn run(&self, text: &str, slots: &mut [Option<usize>]) -> bool {
+        let width = text.len() + 1;
+        let mut visited = vec![0u64; (self.prog.len() * width).div_ceil(64)];
+        let mut stack = vec![Job::Thread(0, 0)];
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut pos) = match job {
+                Job::Restore(slot, old) => {
+                    slots[slot] = old;
+                    continue;
+                }
+                Job::Thread(pc, pos) => (pc, pos),
+            };
+            loop {
+                let bit = pc * width + pos;
+                if visited[bit / 64] & (1 << (bit % 64)) != 0 {
+                    break;
+                }
+                visited[bit / 64] |= 1 << (bit % 64);
+                let next = text[pos..].chars().next();
+                match self.prog[pc] {
+                    Inst::Char(c) if next == Some(c) => {
+                        pc += 1;
+                        pos += c.len_utf8();
+                    }
+                    Inst::Any if next.is_some() => {
+                        pc += 1;
+                        pos += next.unwrap().len_utf8();
+                    }
+                    Inst::Class(ref class) if next.is_some_and(|c| class.matches(c)) => {
+                        pc += 1;
+                        pos += next.unwrap().len_utf8();
+                    }
+                    Inst::Start if pos == 0 => pc += 1,
+                    Inst::End if pos == text.len() => pc += 1,
+                    Inst::Split(x, y) => {
+                        stack.push(Job::Thread(y, pos));
+                        pc = x;
+                    }
+                    Inst::Jmp(x) => pc = x,
+                    Inst::Save(slot) => {
+                        stack.push(Job::Restore(slot, slots[slot]));
+                        slots[slot] = Some(pos);
+                        pc += 1;
+                    }
+                    Inst::Match => return true,
+                    _ => break,
+                }
+            }
+        }
+        false
+    }
+}
+
+enum Job {
+    Thread(usize, usize),
+    Restore(usize, Option<usize>),
+}
+
+fn compile(node: &Node, prog: &mut Vec<Inst>) {
+    match *node {
+        Node::Empty => {}
+        Node::Char(c) => prog.push(Inst::Char(c)),
+        Node::Any => prog.push(Inst::Any),
+        Node::Class(ref class) => prog.push(Inst::Class(class.clone())),
+        Node::Start => prog.push(Inst::Start),
+        Node::End => prog.push(Inst::End),
+        Node::Group(ref node, None) => compile(node, prog),
+        Node::Group(ref node, Some(index)) => {
+            prog.push(Inst::Save(2 * index));
+            compile(node, prog);
+            prog.push(Inst::Save(2 * index + 1));
+        }
+        Node::Concat(ref nodes) => {
+            for node in nodes {
+                compile(node, prog);
+            }
+        }
+        Node::Alt(ref nodes) => {
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                if i + 1 == nodes.len() {
+                    compile(node, prog);
+                    break;
+                }
+                let split = prog.len();
+                prog.push(Inst::Split(split + 1, 0));
+                compile(node, prog);
+                jumps.push(prog.len());
+                prog.push(Inst::Jmp(0));
+                let next = prog.len();
+                prog[split] = Inst::Split(split + 1, next);
+            }
+            let end = prog.len();
+            for jump in jumps {
+                prog[jump] = Inst::Jmp(end);
+            }
+        }
+        Node::Repeat { ref node, min, max, greedy } => {
+            for _ in 0..min {
+                compile(node, prog);
+            }
+            let split = |body: usize, out: usize| {
+                if greedy { Inst::Split(body, out) } else { Inst::Split(out, body) }
+            };
+            match max {
+                None => {
+                    let start = prog.len();
+                    prog.push(Inst::Jmp(0));
+                    compile(node, prog);
+                    prog.push(Inst::Jmp(start));
+                    let end = prog.len();
+                    prog[start] = split(start + 1, end);
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in min..max {
+                        splits.push(prog.len());
+                        prog.push(Inst::Jmp(0));
+                        compile(node, prog);
+                    }
+                    let end = prog.len();
+                    for s in splits {
+                        prog[s] = split(s + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    names: Vec<Option<String>>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> Error {
+        Error::InvalidArgument(format!("invalid regex {:?}: {}", self.pattern, what))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, Error> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alt(alternatives))
+        }
+    }
+
+    fn concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, Error> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let bounds = self.bounds()?;
+                self.pos -= 1;
+                bounds
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if let Node::Start | Node::End | Node::Empty = atom {
+            return Err(self.error("nothing to repeat"));
+        }
+        let greedy = !self.eat('?');
+        if let Some('*') | Some('+') | Some('?') | Some('{') = self.peek() {
+            return Err(self.error("repeated quantifier"));
+        }
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    /// Parses `n}`, `n,}` or `n,m}`, leaving `pos` just past the `}`.
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), Error> {
+        let min = self.number()?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') { None } else { Some(self.number()?) }
+        } else {
+            Some(min)
+        };
+        if !self.eat('}') {
+            return Err(self.error("expected '}'"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition bounds out of order"));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<u32, Error> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        match digits.parse::<u32>() {
+            Ok(n) if n <= MAX_REPEAT => Ok(n),
+            Ok(_) => Err(self.error("repetition count too large")),
+            Err(_) => Err(self.error("expected a repetition count")),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, Error> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '[' => self.class().map(Node::Class),
+            '(' => self.group(),
+            '\\' => self.escape(),
+            '*' | '+' | '?' | '{' => Err(self.error("nothing to repeat")),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn group(&mut self) -> Result<Node, Error> {
+        let index = if self.eat('?') {
+            if self.eat(':') {
+                None
+            } else {
+                self.eat('P');
+                if !self.eat('<') {
+                    return Err(self.error("unknown group flag"));
+                }
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                let name = self.chars[start..self.pos].iter().collect::<String>();
+                if name.is_empty() || !self.eat('>') {
+                    return Err(self.error("invalid group name"));
+                }
+                if self.names.contains(&Some(name.clone())) {
+                    return Err(self.error("duplicate group name"));
+                }
+                self.names.push(Some(name));
+                Some(self.names.len() - 1)
+            }
+        } else {
+            self.names.push(None);
+            Some(self.names.len() - 1)
+        };
+        let node = self.alternation()?;
+        if !self.eat(')') {
+            return Err(self.error("unmatched '('"));
+        }
+        Ok(Node::Group(Box::new(node), index))
+    }
+
+    fn escape(&mut self) -> Result<Node, Error> {
+        let c = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+        self.pos += 1;
+        if let Some(class) = shorthand(c) {
+            return Ok(Node::Class(class));
+        }
+        escaped_char(c).map(Node::Char).ok_or_else(|| self.error("unknown escape"))
+    }
+
+    /// Parses a class after its `[`.
+    fn class(&mut self) -> Result<Class, Error> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unmatched '['"))?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let e = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+                self.pos += 1;
+                match shorthand(e) {
+                    Some(Class { ranges: ref r, negated: false }) => {
+                        ranges.extend_from_slice(r);
+                        continue;
+                    }
+                    Some(_) => return Err(self.error("negated escape in class")),
+                    None => escaped_char(e).ok_or_else(|| self.error("unknown escape"))?,
+                }
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let hi = self.peek().unwrap();
+                self.pos += 1;
+                let hi = if hi == '\\' {
+                    let e = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+                    self.pos += 1;
+                    escaped_char(e).ok_or_else(|| self.error("invalid class range"))?
+                } else {
+                    hi
+                };
+                if hi < lo {
+                    return Err(self.error("class range out of order"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Class { ranges, negated })
+    }
+}
+
+fn shorthand(c: char) -> Option<Class> {
+    let (ranges, negated) = match c {
+        'd' => (vec![('0', '9')], false),
+        'D' => (vec![('0', '9')], true),
+        'w' => (vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], false),
+        'W' => (vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], true),
+        's' => (vec![('\t', '\r'), (' ', ' ')], false),
+        'S' => (vec![('\t', '\r'), (' ', ' ')], true),
+        _ => return None,
+    };
+    Some(Class { ranges, negated })
+}
+
+/// The character `\c` stands for, if it's a valid escape.
+fn escaped_char(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        c if c.is_ascii_punctuation() => Some(c),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn captures<'t>(pattern: &str, text: &'t str) -> Option<Vec<Option<&'t str>>> {
+        Regex::new(pattern).unwrap().captures(text)
+    }
+
+    #[test]
+    fn test_captures() {
+        assert_eq!(captures(r"^/api/v\d+/(\w+)/", "/api/v2/users/12345/orders/67"),
+                   Some(vec![Some("/api/v2/users/"), Some("users")]));
+        assert_eq!(captures(r"o(r?)", "foo orb"), Some(vec![Some("o"), Some("")]));
+        assert_eq!(captures(r"(a)|(b)", "xb"), Some(vec![Some("b"), None, Some("b")]));
+        assert_eq!(captures(r"(\d+)-(\d+)?", "a 12-"), Some(vec![Some("12-"), Some("12"), None]));
+        assert_eq!(captures(r"(\d{2,3}?)", "12345"), Some(vec![Some("12"), Some("12")]));
+        assert_eq!(captures(r"<(.+)>", "<a><b>"), Some(vec![Some("<a><b>"), Some("a><b")]));
+        assert_eq!(captures(r"<(.+?)>", "<a><b>"), Some(vec![Some("<a>"), Some("a")]));
+        assert_eq!(captures(r"[^/]+$", "/a/bé"), Some(vec![Some("bé")]));
+        assert_eq!(captures(r"[\d.-]+", "v=-1.5;"), Some(vec![Some("-1.5")]));
+        assert_eq!(captures(r"^(?:ab|a)c$", "ac"), Some(vec![Some("ac")]));
+        assert_eq!(captures(r"\.\*", "a.*b"), Some(vec![Some(".*")]));
+        assert_eq!(captures(r"x{3}", "xx"), None);
+        assert_eq!(captures(r"^b", "ab"), None);
+    }
+
+    #[test]
+    fn test_named_groups() {
+        let re = Regex::new(r"(?P<method>[A-Z]+) (?<path>\S+)").unwrap();
+        assert_eq!(re.captures_len(), 3);
+        assert_eq!(re.group_index("method"), Some(1));
+        assert_eq!(re.group_index("path"), Some(2));
+        assert_eq!(re.group_index("uri"), None);
+        assert_eq!(re.captures("GET /a HTTP/1.1").unwrap()[2], Some("/a"));
+    }
+
+    #[test]
+    fn test_pathological() {
+        let text = "a".repeat(5_000);
+        assert!(!Regex::new("(a*)*b").unwrap().is_match(&text));
+        assert!(!Regex::new("(a|aa)+$").unwrap().is_match(&format!("{}b", text)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for pattern in &["(", "a)", "[a", "*a", "a**", "x{2,1}", "x{1001}", r"\q", "(?x)", "[b-a]",
+                         r"a\", "(?P<n>a)(?P<n>b)"] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
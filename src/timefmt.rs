@@ -0,0 +1,338 @@
+/// How the timestamps of a label are written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeFormat {
+    /// Seconds since the Unix epoch, optionally with a fractional part.
+    Epoch,
+    /// A strftime-style pattern such as `%d/%b/%Y:%H:%M:%S %z`.
+    ///
+    /// Supported conversions are `%Y %y %m %d %e %H %M %S %b %B %a %A %z %s`,
+    /// the shorthands `%F` (`%Y-%m-%d`) and `%T` (`%H:%M:%S`), and `%%`.
+    /// `%z` accepts `+0900`, `+09:00` and `Z`; without it times are UTC.
+    Strftime(String),
+}
+
+impl TimeFormat {
+    /// Parses `value` into seconds since the Unix epoch.
+    pub fn parse(&self, value: &str) -> Option<i64> {
+        match *self {
+            TimeFormat::Epoch => {
+                value.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .map(|v| v.floor() as i64)
+            }
+            TimeFormat::Strftime(ref fmt) => strptime(value, fmt),
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = ["january", "february", "march", "april", "may", "june", "july",
+                            "august", "september", "october", "november", "december"];
+const WEEKDAYS: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday",
+                             "saturday", "sunday"];
+
+/// Parses `value` with the strftime-style `fmt` into seconds since the epoch.
+pub fn strptime(value: &str, fmt: &str) -> Option<i64> {
+    let mut p = Parser { s: value.as_bytes(), pos: 0 };
+    let mut t = Fields {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        min: 0,
+        sec: 0,
+        offset: 0,
+        epoch: None,
+    };
+    let mut spec = fmt.chars();
+    while let Some(c) = spec.next() {
+        if c != '%' {
+            let mut buf = [0; 4];
+            if !p.literal(c.encode_utf8(&mut buf).as_bytes()) {
+                return None;
+            }
+            continue;
+        }
+        match spec.next()? {
+            'Y' => t.year = p.number(4)?,
+            'y' => {
+                let y = p.number(2)?;
+                t.year = if y < 69 { 2000 + y } else { 1900 + y };
+            }
+            'm' => t.month = p.number(2)?,
+            'd' => t.day = p.number(2)?,
+            'e' => {
+                p.spaces();
+                t.day = p.number(2)?;
+            }
+            'H' => t.hour = p.number(2)?,
+            'M' => t.min = p.number(2)?,
+            'S' => t.sec = p.number(2)?,
+            'F' => {
+                t.year = p.number(4)?;
+                p.expect(b'-')?;
+                t.month = p.number(2)?;
+                p.expect(b'-')?;
+                t.day = p.number(2)?;
+            }
+            'T' => {
+                t.hour = p.number(2)?;
+                p.expect(b':')?;
+                t.min = p.number(2)?;
+                p.expect(b':')?;
+                t.sec = p.number(2)?;
+            }
+            'b' | 'B' => t.month = p.name(&MONTHS)? as i64 + 1,
+            'a' | 'A' => {
+                p.name(&WEEKDAYS)?;
+            }
+            'z' => t.offset = p.offset()?,
+            's' => {
+                let negative = p.literal(b"-");
+                let secs = p.number(19)?;
+                t.epoch = Some(if negative { -secs } else { secs });
+            }
+            '%' => p.expect(b'%')?,
+            _ => return None,
+        }
+    }
+    if p.pos != p.s.len() {
+        return None;
+    }
+    t.to_epoch()
+}
+
+/// Formats `epoch`, in seconds since the epoch, as UTC with the same
+/// conversions `strptime` accepts; `%z` gives `+0000`. Returns `None` for an
+/// unknown conversion.
+pub fn strftime(epoch: i64, fmt: &str) -> Option<String> {
+    let days = epoch.div_euclid(86400);
+    let secs = epoch.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, min, sec) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let weekday = (days.rem_euclid(7) + 3) % 7;
+    let mut out = String::new();
+    let mut spec = fmt.chars();
+    while let Some(c) = spec.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let formatted = match spec.next()? {
+            'Y' => format!("{:04}", year),
+            'y' => format!("{:02}", year.rem_euclid(100)),
+            'm' => format!("{:02}", month),
+            'd' => format!("{:02}", day),
+            'e' => format!("{:2}", day),
+            'H' => format!("{:02}", hour),
+            'M' => format!("{:02}", min),
+            'S' => format!("{:02}", sec),
+            'F' => format!("{:04}-{:02}-{:02}", year, month, day),
+            'T' => format!("{:02}:{:02}:{:02}", hour, min, sec),
+            'b' => capitalized(&MONTHS[month as usize - 1][..3]),
+            'B' => capitalized(MONTHS[month as usize - 1]),
+            'a' => capitalized(&WEEKDAYS[weekday as usize][..3]),
+            'A' => capitalized(WEEKDAYS[weekday as usize]),
+            'z' => "+0000".to_string(),
+            's' => epoch.to_string(),
+            '%' => "%".to_string(),
+            _ => return None,
+        };
+        out.push_str(&formatted);
+    }
+    Some(out)
+}
+
+fn capitalized(name: &str) -> String {
+    name[..1].to_ascii_uppercase() + &name[1..]
+}
+
+struct Fields {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    offset: i64,
+    epoch: Option<i64>,
+}
+
+impl Fields {
+    fn to_epoch(&self) -> Option<i64> {
+        if let Some(epoch) = self.epoch {
+            return Some(epoch);
+        }
+        if self.month < 1 || self.month > 12 || self.day < 1 ||
+           self.day > days_in_month(self.year, self.month) || self.hour > 23 ||
+           self.min > 59 || self.sec > 60 {
+            return None;
+        }
+        let days = days_from_civil(self.year, self.month, self.day);
+        Some(days * 86400 + self.hour * 3600 + self.min * 60 + self.sec - self.offset)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn literal(&mut self, lit: &[u8]) -> bool {
+        if self.s[self.pos..].starts_with(lit) {
+            self.pos += lit.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Option<()> {
+        if self.literal(&[b]) { Some(()) } else { None }
+    }
+
+    fn spaces(&mut self) {
+        while self.s.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+    }
+
+    /// Reads between one and `max` decimal digits.
+    fn number(&mut self, max: usize) -> Option<i64> {
+        let start = self.pos;
+        while self.pos - start < max && self.s.get(self.pos).is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        ::std::str::from_utf8(&self.s[start..self.pos]).ok()?.parse().ok()
+    }
+
+    /// Matches a full or three-letter abbreviated name, case-insensitively.
+    fn name(&mut self, names: &[&str]) -> Option<usize> {
+        let rest = &self.s[self.pos..];
+        for (i, name) in names.iter().enumerate() {
+            for len in &[name.len(), 3] {
+                let name = &name.as_bytes()[..*len];
+                if rest.len() >= name.len() && rest[..name.len()].eq_ignore_ascii_case(name) {
+                    self.pos += name.len();
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reads a UTC offset (`Z`, `+0900` or `+09:00`) in seconds.
+    fn offset(&mut self) -> Option<i64> {
+        if self.literal(b"Z") || self.literal(b"z") {
+            return Some(0);
+        }
+        let sign = if self.literal(b"+") {
+            1
+        } else if self.literal(b"-") {
+            -1
+        } else {
+            return None;
+        };
+        let start = self.pos;
+        let hours = self.number(2)?;
+        if self.pos - start != 2 {
+            return None;
+        }
+        self.literal(b":");
+        let start = self.pos;
+        let mins = self.number(2)?;
+        if self.pos - start != 2 || mins > 59 {
+            return None;
+        }
+        Some(sign * (hours * 3600 + mins * 60))
+    }
+}
+
+fn is_leap_year(y: i64) -> bool {
+    (y % 4 == 0 && y % 100 != 0) || y % 400 == 0
+}
+
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if is_leap_year(y) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of the given proleptic Gregorian date.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The proleptic Gregorian date of the given day since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strptime() {
+        assert_eq!(strptime("10/Oct/2000:13:55:36 -0700", "%d/%b/%Y:%H:%M:%S %z"),
+                   Some(971211336));
+        assert_eq!(strptime("2000-10-10T20:55:36Z", "%FT%T%z"), Some(971211336));
+        assert_eq!(strptime("2000-10-11T05:55:36+09:00", "%Y-%m-%dT%H:%M:%S%z"),
+                   Some(971211336));
+        assert_eq!(strptime("Tue, 29 Feb 2000 00:00:00", "%a, %d %b %Y %T"), Some(951782400));
+        assert_eq!(strptime("1969-12-31 23:59:59", "%F %T"), Some(-1));
+        assert_eq!(strptime("100%", "%s%%"), Some(100));
+    }
+
+    #[test]
+    fn test_strptime_invalid() {
+        assert_eq!(strptime("2001-02-29", "%F"), None);
+        assert_eq!(strptime("2000-10-10 12:00", "%F"), None);
+        assert_eq!(strptime("10/Foo/2000", "%d/%b/%Y"), None);
+        assert_eq!(strptime("2000-10-10T20:55:36+9", "%FT%T%z"), None);
+    }
+
+    #[test]
+    fn test_epoch() {
+        assert_eq!(TimeFormat::Epoch.parse("971211336.25"), Some(971211336));
+        assert_eq!(TimeFormat::Epoch.parse("-0.5"), Some(-1));
+        assert_eq!(TimeFormat::Epoch.parse("now"), None);
+    }
+
+    #[test]
+    fn test_strftime() {
+        assert_eq!(strftime(971211336, "%FT%T%z").unwrap(), "2000-10-10T20:55:36+0000");
+        assert_eq!(strftime(971211336, "%a, %e %B %y %H:%M:%S %%").unwrap(),
+                   "Tue, 10 October 00 20:55:36 %");
+        assert_eq!(strftime(951782400, "%A %d/%b/%Y %s").unwrap(),
+                   "Tuesday 29/Feb/2000 951782400");
+        assert_eq!(strftime(-1, "%F %T").unwrap(), "1969-12-31 23:59:59");
+        assert_eq!(strftime(0, "%Q"), None);
+        for &t in &[-86400 * 800, -1, 0, 951782400, 971211336, 4102444800] {
+            assert_eq!(strptime(&strftime(t, "%a %d %b %Y %T").unwrap(), "%a %d %b %Y %T"),
+                       Some(t));
+        }
+    }
+}
@@ -0,0 +1,226 @@
+/// The coarse class of a User-Agent string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UaClass {
+    /// The browser family, such as `Chrome`, or `Other`.
+    pub browser: String,
+    /// The operating system family, such as `Windows`, or `Other`.
+    pub os: String,
+    pub bot: bool,
+}
+
+/// Which part of a `UaClass` to group by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UaFacet {
+    Browser,
+    Os,
+    /// Groups into `true` for bots and `false` for the rest.
+    Bot,
+}
+
+impl UaClass {
+    pub fn facet(&self, facet: UaFacet) -> String {
+        match facet {
+            UaFacet::Browser => self.browser.clone(),
+            UaFacet::Os => self.os.clone(),
+            UaFacet::Bot => self.bot.to_string(),
+        }
+    }
+}
+
+/// The family of unrecognized User-Agents.
+pub const OTHER: &str = "Other";
+
+// Rules are tried in order and the first substring found wins, so more
+// specific tokens come first: Edge and Chrome also send `Safari/`, Chrome on
+// Android sends `Linux`, and iOS sends `Mac OS X`.
+const BROWSERS: &[(&str, &str)] = &[("Edg/", "Edge"),
+                                    ("EdgA/", "Edge"),
+                                    ("EdgiOS/", "Edge"),
+                                    ("Edge/", "Edge"),
+                                    ("Firefox/", "Firefox"),
+                                    ("FxiOS/", "Firefox"),
+                                    ("Chrome/", "Chrome"),
+                                    ("CriOS/", "Chrome"),
+                                    ("Chromium/", "Chrome"),
+                                    ("Safari/", "Safari")];
+const SYSTEMS: &[(&str, &str)] = &[("Windows", "Windows"),
+                                   ("iPhone", "iOS"),
+                                   ("iPad", "iOS"),
+                                   ("iPod", "iOS"),
+                                   ("Android", "Android"),
+                                   ("CrOS", "ChromeOS"),
+                                   ("Mac OS X", "macOS"),
+                                   ("Macintosh", "macOS"),
+                                   ("Linux", "Linux")];
+// Matched case-insensitively.
+const BOTS: &[&str] = &["bot", "crawler", "spider", "slurp", "curl/", "wget/", "python-requests",
+                        "python-urllib", "go-http-client", "java/", "okhttp", "libwww-perl",
+                        "headlesschrome"];
+
+/// Classifies `ua` with the built-in rules.
+pub fn classify_user_agent(ua: &str) -> UaClass {
+    classify(ua, BROWSERS, SYSTEMS, BOTS)
+}
+
+/// Classification rules, each a substring and the family it stands for.
+/// Rules are tried in order and the first one whose substring occurs in the
+/// User-Agent wins; bot substrings are matched case-insensitively.
+///
+/// `UaRules::default()` holds the built-in rules, to which more specific
+/// rules can be added in front.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UaRules {
+    pub browsers: Vec<(String, String)>,
+    pub systems: Vec<(String, String)>,
+    pub bots: Vec<String>,
+}
+
+impl Default for UaRules {
+    fn default() -> UaRules {
+        let owned = |rules: &[(&str, &str)]| {
+            rules.iter().map(|&(s, family)| (s.to_string(), family.to_string())).collect()
+        };
+        UaRules {
+            browsers: owned(BROWSERS),
+            systems: owned(SYSTEMS),
+            bots: BOTS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl UaRules {
+    pub fn classify(&self, ua: &str) -> UaClass {
+        let bots = self.bots.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+        classify(ua, &borrowed(&self.browsers), &borrowed(&self.systems), &bots)
+    }
+}
+
+fn borrowed(rules: &[(String, String)]) -> Vec<(&str, &str)> {
+    rules.iter().map(|(s, family)| (s.as_str(), family.as_str())).collect()
+}
+
+fn classify(ua: &str,
+            browsers: &[(&str, &str)],
+            systems: &[(&str, &str)],
+            bots: &[&str])
+            -> UaClass {
+    let family = |rules: &[(&str, &str)]| {
+        rules.iter()
+            .find(|&&(s, _)| ua.contains(s))
+            .map_or(OTHER, |&(_, family)| family)
+            .to_string()
+    };
+    let lower = ua.to_lowercase();
+    UaClass {
+        browser: family(browsers),
+        os: family(systems),
+        bot: bots.iter().any(|s| lower.contains(&s.to_lowercase())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AGENTS: &[(&str, &str, &str, bool)] = &[
+        ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Safari/537.36",
+         "Chrome", "Windows", false),
+        ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Safari/537.36 Edg/120.0.2210.91",
+         "Edge", "Windows", false),
+        ("Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0",
+         "Firefox", "Windows", false),
+        ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/70.0.3538.102 Safari/537.36 Edge/18.19045",
+         "Edge", "Windows", false),
+        ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like \
+          Gecko) Version/17.2 Safari/605.1.15",
+         "Safari", "macOS", false),
+        ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like \
+          Gecko) Chrome/120.0.0.0 Safari/537.36",
+         "Chrome", "macOS", false),
+        ("Mozilla/5.0 (Macintosh; Intel Mac OS X 14.2; rv:121.0) Gecko/20100101 Firefox/121.0",
+         "Firefox", "macOS", false),
+        ("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Safari/537.36",
+         "Chrome", "Linux", false),
+        ("Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+         "Firefox", "Linux", false),
+        ("Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Safari/537.36",
+         "Chrome", "ChromeOS", false),
+        ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, \
+          like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+         "Safari", "iOS", false),
+        ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, \
+          like Gecko) CriOS/120.0.6099.119 Mobile/15E148 Safari/604.1",
+         "Chrome", "iOS", false),
+        ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, \
+          like Gecko) FxiOS/121.0 Mobile/15E148 Safari/605.1.15",
+         "Firefox", "iOS", false),
+        ("Mozilla/5.0 (iPad; CPU OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like \
+          Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+         "Safari", "iOS", false),
+        ("Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Mobile Safari/537.36",
+         "Chrome", "Android", false),
+        ("Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) \
+          Chrome/120.0.0.0 Mobile Safari/537.36 EdgA/120.0.0.0",
+         "Edge", "Android", false),
+        ("Mozilla/5.0 (Android 14; Mobile; rv:121.0) Gecko/121.0 Firefox/121.0",
+         "Firefox", "Android", false),
+        ("Mozilla/5.0 (Linux; Android 13; SM-S901B) AppleWebKit/537.36 (KHTML, like Gecko) \
+          SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36",
+         "Chrome", "Android", false),
+        ("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+         OTHER, OTHER, true),
+        ("Mozilla/5.0 (Linux; Android 6.0.1; Nexus 5X Build/MMB29P) AppleWebKit/537.36 (KHTML, \
+          like Gecko) Chrome/120.0.6099.129 Mobile Safari/537.36 (compatible; Googlebot/2.1; \
+          +http://www.google.com/bot.html)",
+         "Chrome", "Android", true),
+        ("Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+         OTHER, OTHER, true),
+        ("Mozilla/5.0 (compatible; Yahoo! Slurp; http://help.yahoo.com/help/us/ysearch/slurp)",
+         OTHER, OTHER, true),
+        ("Mozilla/5.0 (compatible; Baiduspider/2.0; +http://www.baidu.com/search/spider.html)",
+         OTHER, OTHER, true),
+        ("Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)", OTHER, OTHER, true),
+        ("facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)",
+         OTHER, OTHER, false),
+        ("curl/8.4.0", OTHER, OTHER, true),
+        ("Wget/1.21.4", OTHER, OTHER, true),
+        ("python-requests/2.31.0", OTHER, OTHER, true),
+        ("Go-http-client/1.1", OTHER, OTHER, true),
+        ("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+          HeadlessChrome/120.0.0.0 Safari/537.36",
+         "Chrome", "Linux", true),
+        ("", OTHER, OTHER, false),
+        ("-", OTHER, OTHER, false),
+    ];
+
+    #[test]
+    fn test_classify_user_agent() {
+        for &(ua, browser, os, bot) in AGENTS {
+            let class = classify_user_agent(ua);
+            assert_eq!(class,
+                       UaClass { browser: browser.to_string(), os: os.to_string(), bot },
+                       "{}",
+                       ua);
+            assert_eq!(UaRules::default().classify(ua), class);
+        }
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let ua = "Mozilla/5.0 (Linux; Android 13; SM-S901B) AppleWebKit/537.36 (KHTML, like \
+                  Gecko) SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36";
+        let mut rules = UaRules::default();
+        rules.browsers.insert(0, ("SamsungBrowser/".to_string(), "Samsung".to_string()));
+        rules.bots.push("facebookexternalhit".to_string());
+        let class = rules.classify(ua);
+        assert_eq!((class.browser.as_str(), class.os.as_str()), ("Samsung", "Android"));
+        assert_eq!(class.facet(UaFacet::Bot), "false");
+        assert!(rules.classify("facebookexternalhit/1.1").bot);
+    }
+}